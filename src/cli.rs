@@ -0,0 +1,71 @@
+use anyhow::Result;
+
+pub const USAGE: &str = "Usage: zkom_client [COMMAND]
+
+Commands:
+  run [--once]                  Register if needed and start the node (default).
+                                With --once, process a single task and exit with its status
+  status                        Show node registration and configuration status
+  refresh-token                 Refresh the access token now and save it
+  reconfigure [--yes]           Clear the registration and register the node again,
+                                keeping base_url and other settings
+  config dump [--show-secrets]  Print the effective configuration and where each value came from";
+
+/// 命令行子命令
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Start the node; with `once`, process a single task without heartbeats and exit
+    Run { once: bool },
+    Status,
+    /// Force an access token refresh and persist the result
+    RefreshToken,
+    /// Clear tokens and node ID, then register again; skips the confirmation prompt with `yes`
+    Reconfigure { yes: bool },
+    /// Print the resolved configuration; tokens are redacted unless `show_secrets`
+    ConfigDump { show_secrets: bool },
+}
+
+/// 解析命令行参数
+pub fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Command> {
+    let args: Vec<String> = args.into_iter().collect();
+    match args.first().map(String::as_str) {
+        None => Ok(Command::Run { once: false }),
+        Some("--once") if args.len() == 1 => Ok(Command::Run { once: true }),
+        Some("run") => {
+            let mut once = false;
+            for flag in &args[1..] {
+                match flag.as_str() {
+                    "--once" => once = true,
+                    other => return Err(anyhow::anyhow!("Unknown option: {}\n\n{}", other, USAGE)),
+                }
+            }
+            Ok(Command::Run { once })
+        }
+        Some("status") => Ok(Command::Status),
+        Some("refresh-token") => Ok(Command::RefreshToken),
+        Some("reconfigure") => {
+            let mut yes = false;
+            for flag in &args[1..] {
+                match flag.as_str() {
+                    "--yes" | "-y" => yes = true,
+                    other => return Err(anyhow::anyhow!("Unknown option: {}\n\n{}", other, USAGE)),
+                }
+            }
+            Ok(Command::Reconfigure { yes })
+        }
+        Some("config") => match args.get(1).map(String::as_str) {
+            Some("dump") => {
+                let mut show_secrets = false;
+                for flag in &args[2..] {
+                    match flag.as_str() {
+                        "--show-secrets" => show_secrets = true,
+                        other => return Err(anyhow::anyhow!("Unknown option: {}\n\n{}", other, USAGE)),
+                    }
+                }
+                Ok(Command::ConfigDump { show_secrets })
+            }
+            _ => Err(anyhow::anyhow!("Usage: zkom_client config dump [--show-secrets]")),
+        },
+        Some(other) => Err(anyhow::anyhow!("Unknown command: {}\n\n{}", other, USAGE)),
+    }
+}
@@ -1,8 +1,13 @@
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
 use dirs::config_dir;
 use crate::consts::*;
+use crate::stable_diffusion::SDPaths;
+
+pub mod settings;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct NodeConfig {
@@ -12,6 +17,113 @@ pub struct NodeConfig {
     pub refresh_token: Option<String>,
     pub node_id: Option<String>,
     pub base_url: String,
+    /// NATS server URL; `NATS_SERVER` overrides it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nats_server: Option<String>,
+    /// Default Stable Diffusion API URL for nodes without their own `sd_url`; `SD_URL` overrides it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sd_url: Option<String>,
+    /// Random per-install id, generated and saved the first time the client starts.
+    /// Configs written before this field existed get one on their next start
+    #[serde(default)]
+    pub installation_id: Option<String>,
+    /// Device fingerprint the node registered with; a different fingerprint at startup
+    /// means the config was copied to another machine (cloned VM or image)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_fingerprint: Option<String>,
+    /// Additional logical nodes served by this process (e.g. one per GPU).
+    /// When empty, the process runs a single node using `node_id`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub nodes: Vec<NodeEntry>,
+    /// Named prompt styles tasks can select with the `style` param
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub styles: HashMap<String, PromptStyle>,
+    /// SD server options (`/sdapi/v1/options`) applied before processing tasks,
+    /// e.g. `{"sd_vae": "...", "CLIP_stop_at_last_layers": 2}`
+    #[serde(default, skip_serializing_if = "serde_json::Map::is_empty")]
+    pub sd_options: serde_json::Map<String, serde_json::Value>,
+    /// Task param keys remote tasks may set; tasks with any other key are rejected.
+    /// Unset or empty allows every key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_params: Option<Vec<String>>,
+    /// Appended to every task's prompt, after any style and LoRA tags
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub global_prompt_suffix: Option<String>,
+    /// Appended to every task's negative prompt, e.g. a standard safety negative
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub global_negative_prompt: Option<String>,
+    /// Extra SD error substrings treated as transient and retried, on top of the built-in ones
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub retryable_sd_errors: Vec<String>,
+    /// SD API endpoint paths for non-Automatic1111 backends; omitted paths keep the A1111
+    /// defaults and each can be overridden with `SD_PATH_<NAME>`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sd_paths: Option<SDPaths>,
+    /// Exit after this many seconds without tasks (e.g. on autoscaled or spot machines);
+    /// unset or 0 keeps the node running. `IDLE_EXIT_SECS` overrides it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idle_exit_secs: Option<u64>,
+    /// Time limit for each SD query made at startup (capabilities, scripts, loaded model);
+    /// `SD_PROBE_TIMEOUT_SECS` overrides it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sd_probe_timeout_secs: Option<u64>,
+}
+
+/// 可复用的提示词风格
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PromptStyle {
+    /// Prepended to the task prompt
+    #[serde(default)]
+    pub prompt_prefix: Option<String>,
+    /// Appended to the task prompt
+    #[serde(default)]
+    pub prompt_suffix: Option<String>,
+    /// Appended to the task negative prompt
+    #[serde(default)]
+    pub negative_prompt: Option<String>,
+}
+
+/// 单个逻辑节点配置
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeEntry {
+    pub node_id: String,
+    /// Stable Diffusion backend for this node (defaults to `SD_URL`)
+    #[serde(default)]
+    pub sd_url: Option<String>,
+    /// JetStream consumer name (defaults to `zkom-processor-{node_id}`)
+    #[serde(default)]
+    pub consumer_name: Option<String>,
+    /// GPU (nvidia-smi index) this node's SD backend runs on, for per-GPU throttling.
+    /// Unset means the node is affected by throttling on any GPU
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gpu_index: Option<u32>,
+}
+
+impl NodeEntry {
+    /// 节点的 JetStream 消费者名称，未配置时为 `zkom-processor-{node_id}`
+    pub fn consumer_name(&self) -> String {
+        self.consumer_name
+            .clone()
+            .unwrap_or_else(|| format!("{}-{}", DEFAULT_CONSUMER_NAME, self.node_id))
+    }
+}
+
+impl NodeConfig {
+    /// 本进程需要运行的所有逻辑节点
+    pub fn node_entries(&self) -> Vec<NodeEntry> {
+        if !self.nodes.is_empty() {
+            return self.nodes.clone();
+        }
+        self.node_id
+            .iter()
+            .map(|id| NodeEntry {
+                node_id: id.clone(),
+                sd_url: None,
+                consumer_name: Some(DEFAULT_CONSUMER_NAME.to_string()),
+                gpu_index: None,
+            })
+            .collect()
+    }
 }
 
 impl Default for NodeConfig {
@@ -23,6 +135,41 @@ impl Default for NodeConfig {
             refresh_token: None,
             node_id: None,
             base_url: API_BASE_URL.to_string(),
+            nats_server: None,
+            sd_url: None,
+            installation_id: None,
+            device_fingerprint: None,
+            nodes: Vec::new(),
+            styles: HashMap::new(),
+            sd_options: serde_json::Map::new(),
+            allowed_params: None,
+            global_prompt_suffix: None,
+            global_negative_prompt: None,
+            retryable_sd_errors: Vec::new(),
+            sd_paths: None,
+            idle_exit_secs: None,
+            sd_probe_timeout_secs: None,
+        }
+    }
+}
+
+/// 配置持久化模式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageMode {
+    /// Saved to the default config directory
+    Persistent,
+    /// Default directory is read-only, saved under `ZKOM_CONFIG_FALLBACK_DIR`
+    Fallback,
+    /// No writable location, tokens live only in memory
+    Ephemeral,
+}
+
+impl fmt::Display for StorageMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Persistent => f.write_str("persistent"),
+            Self::Fallback => f.write_str("persistent (fallback directory)"),
+            Self::Ephemeral => f.write_str("ephemeral (changes are not persisted)"),
         }
     }
 }
@@ -30,17 +177,20 @@ impl Default for NodeConfig {
 pub struct ConfigManager {
     config_path: PathBuf,
     config: NodeConfig,
+    storage_mode: StorageMode,
 }
 
 impl ConfigManager {
     pub fn new() -> Result<Self> {
         let config_dir = config_dir()
             .ok_or_else(|| anyhow::anyhow!("无法获取配置目录"))?;
-        let config_path = config_dir.join(CONFIG_DIR).join(CONFIG_FILE);
+        let default_path = config_dir.join(CONFIG_DIR).join(CONFIG_FILE);
+        let (config_path, storage_mode) = Self::resolve_storage(&default_path);
 
-        let config = if config_path.exists() {
-            let content = std::fs::read_to_string(&config_path)?;
-            serde_json::from_str(&content)?
+        // 回退目录中尚无配置时，从默认位置读取已有配置
+        let source_path = if config_path.exists() { &config_path } else { &default_path };
+        let config = if source_path.exists() {
+            Self::load(source_path)?
         } else {
             NodeConfig::default()
         };
@@ -48,10 +198,60 @@ impl ConfigManager {
         Ok(Self {
             config_path,
             config,
+            storage_mode,
         })
     }
 
+    /// 读取配置文件；内容损坏时备份为 `config.json.corrupt` 并使用默认配置，以便重新注册
+    fn load(path: &Path) -> Result<NodeConfig> {
+        let content = std::fs::read(path)?;
+        let error = match serde_json::from_slice(&content) {
+            Ok(config) => return Ok(config),
+            Err(e) => e,
+        };
+
+        let mut backup = path.as_os_str().to_owned();
+        backup.push(CONFIG_CORRUPT_SUFFIX);
+        let backup = PathBuf::from(backup);
+        log::error!("Config file {:?} is not valid JSON ({}), starting with a fresh config", path, error);
+        match std::fs::rename(path, &backup) {
+            Ok(()) => log::warn!("Corrupt config backed up to {:?}; the node will register again", backup),
+            Err(e) => log::warn!("Failed to back up corrupt config to {:?}: {}", backup, e),
+        }
+        Ok(NodeConfig::default())
+    }
+
+    /// 选择可写的配置位置：默认目录、回退目录（环境变量）或仅内存
+    fn resolve_storage(default_path: &Path) -> (PathBuf, StorageMode) {
+        if is_writable(default_path) {
+            return (default_path.to_path_buf(), StorageMode::Persistent);
+        }
+
+        if let Ok(dir) = std::env::var(CONFIG_FALLBACK_DIR_ENV) {
+            let fallback_path = PathBuf::from(dir).join(CONFIG_FILE);
+            if is_writable(&fallback_path) {
+                log::warn!(
+                    "Config location {:?} is not writable, using fallback {:?}",
+                    default_path, fallback_path
+                );
+                return (fallback_path, StorageMode::Fallback);
+            }
+            log::warn!("Fallback config location {:?} is not writable either", fallback_path);
+        }
+
+        log::warn!(
+            "Config location {:?} is not writable, running in ephemeral mode: refreshed tokens will not be persisted (set {} to a writable directory to persist)",
+            default_path, CONFIG_FALLBACK_DIR_ENV
+        );
+        (default_path.to_path_buf(), StorageMode::Ephemeral)
+    }
+
     pub fn save(&self) -> Result<()> {
+        if self.storage_mode == StorageMode::Ephemeral {
+            log::debug!("Ephemeral config mode, skipping save");
+            return Ok(());
+        }
+
         if let Some(parent) = self.config_path.parent() {
             std::fs::create_dir_all(parent)?;
         }
@@ -61,6 +261,14 @@ impl ConfigManager {
         Ok(())
     }
 
+    pub fn config_path(&self) -> &Path {
+        &self.config_path
+    }
+
+    pub fn storage_mode(&self) -> StorageMode {
+        self.storage_mode
+    }
+
     pub fn get_config(&self) -> &NodeConfig {
         &self.config
     }
@@ -72,6 +280,19 @@ impl ConfigManager {
         Ok(())
     }
 
+    /// 获取本安装的唯一标识，首次调用时生成并保存
+    pub fn installation_id(&mut self) -> Result<String> {
+        if let Some(id) = &self.config.installation_id {
+            return Ok(id.clone());
+        }
+
+        let id = uuid::Uuid::new_v4().to_string();
+        log::info!("Generated installation id {}", id);
+        self.config.installation_id = Some(id.clone());
+        self.save()?;
+        Ok(id)
+    }
+
     pub fn set_device_code(&mut self, code: String) -> Result<()> {
         self.config.device_code = Some(code);
         self.save()?;
@@ -97,9 +318,184 @@ impl ConfigManager {
         Ok(())
     }
 
-    pub fn update_access_token(&mut self, access_token: String) -> Result<()> {
-        self.config.access_token = Some(access_token);
+    /// 清除注册信息（设备码、令牌与节点ID），保留 base_url、安装ID及其他配置以便重新注册
+    pub fn clear_registration(&mut self) -> Result<()> {
+        self.config.device_code = None;
+        self.config.user_code = None;
+        self.config.access_token = None;
+        self.config.refresh_token = None;
+        self.config.node_id = None;
+        self.config.device_fingerprint = None;
+        self.save()?;
+        Ok(())
+    }
+
+    pub fn set_device_fingerprint(&mut self, fingerprint: String) -> Result<()> {
+        self.config.device_fingerprint = Some(fingerprint);
         self.save()?;
         Ok(())
     }
-} 
\ No newline at end of file
+
+    /// 保存刷新后的令牌，后端轮换了刷新令牌时一并更新
+    ///
+    /// 保存后读回配置文件确认令牌已写入，失败时重试；仍然失败则切换到仅内存模式，
+    /// 新令牌在本次运行中继续使用，但重启后会丢失。重试间隔异步等待，不占用运行时的工作线程。
+    pub async fn update_tokens(&mut self, access_token: String, refresh_token: Option<String>) -> Result<()> {
+        self.config.access_token = Some(access_token);
+        if let Some(refresh_token) = refresh_token {
+            self.config.refresh_token = Some(refresh_token);
+        }
+        if self.storage_mode == StorageMode::Ephemeral {
+            return Ok(());
+        }
+
+        let mut last_error = None;
+        for attempt in 1..=TOKEN_SAVE_ATTEMPTS {
+            if attempt > 1 {
+                tokio::time::sleep(std::time::Duration::from_millis(TOKEN_SAVE_RETRY_DELAY_MS)).await;
+            }
+            match self.save().and_then(|()| self.verify_tokens()) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    log::warn!("Saving refreshed tokens to {:?} failed (attempt {}/{}): {}",
+                        self.config_path, attempt, TOKEN_SAVE_ATTEMPTS, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        log::error!(
+            "Refreshed tokens could not be persisted to {:?}; switching to ephemeral mode. \
+             The node keeps running, but must be re-registered if it restarts before the config \
+             is writable again (set {} to a writable directory)",
+            self.config_path, CONFIG_FALLBACK_DIR_ENV
+        );
+        self.storage_mode = StorageMode::Ephemeral;
+        Err(last_error
+            .unwrap_or_else(|| anyhow::anyhow!("Failed to persist tokens"))
+            .context("Refreshed tokens were not persisted"))
+    }
+
+    /// 读回配置文件，确认其中的令牌与内存中一致
+    fn verify_tokens(&self) -> Result<()> {
+        let stored: NodeConfig = serde_json::from_slice(&std::fs::read(&self.config_path)?)?;
+        if stored.access_token != self.config.access_token || stored.refresh_token != self.config.refresh_token {
+            anyhow::bail!("Tokens read back from {:?} don't match the saved tokens", self.config_path);
+        }
+        Ok(())
+    }
+} 
+
+/// 检查配置文件位置是否可写（目录可创建且文件可写入）
+fn is_writable(path: &Path) -> bool {
+    let Some(parent) = path.parent() else {
+        return false;
+    };
+    if std::fs::create_dir_all(parent).is_err() {
+        return false;
+    }
+    if path.exists() {
+        return std::fs::OpenOptions::new().append(true).open(path).is_ok();
+    }
+
+    let probe = parent.join(".write_test");
+    let writable = std::fs::write(&probe, b"").is_ok();
+    let _ = std::fs::remove_file(&probe);
+    writable
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 每个测试独占的临时目录
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("zkom-config-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn manager(config_path: PathBuf) -> ConfigManager {
+        ConfigManager {
+            config_path,
+            config: NodeConfig::default(),
+            storage_mode: StorageMode::Persistent,
+        }
+    }
+
+    #[tokio::test]
+    async fn update_tokens_persists_and_verifies() {
+        let dir = temp_dir();
+        let mut manager = manager(dir.join(CONFIG_FILE));
+
+        manager.update_tokens("access".to_string(), Some("refresh".to_string())).await.unwrap();
+        assert_eq!(manager.storage_mode(), StorageMode::Persistent);
+        let stored = ConfigManager::load(manager.config_path()).unwrap();
+        assert_eq!(stored.access_token.as_deref(), Some("access"));
+        assert_eq!(stored.refresh_token.as_deref(), Some("refresh"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn update_tokens_falls_back_to_ephemeral_after_failed_retries() {
+        let dir = temp_dir();
+        // 父路径是普通文件，每次保存都会失败
+        let blocker = dir.join("not-a-dir");
+        std::fs::write(&blocker, b"").unwrap();
+        let mut manager = manager(blocker.join(CONFIG_FILE));
+        manager.config.refresh_token = Some("old-refresh".to_string());
+
+        let started = std::time::Instant::now();
+        let result = manager.update_tokens("access".to_string(), None).await;
+        assert!(result.is_err());
+        assert!(started.elapsed() >= std::time::Duration::from_millis(TOKEN_SAVE_RETRY_DELAY_MS * (TOKEN_SAVE_ATTEMPTS as u64 - 1)));
+        assert_eq!(manager.storage_mode(), StorageMode::Ephemeral);
+        // 新令牌在内存中继续使用
+        assert_eq!(manager.get_config().access_token.as_deref(), Some("access"));
+        assert_eq!(manager.get_config().refresh_token.as_deref(), Some("old-refresh"));
+
+        // 仅内存模式下不再尝试写入
+        manager.update_tokens("access-2".to_string(), None).await.unwrap();
+        assert_eq!(manager.get_config().access_token.as_deref(), Some("access-2"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn installation_id_is_stable_across_reloads() {
+        let dir = temp_dir();
+        let path = dir.join(CONFIG_FILE);
+        let mut first = manager(path.clone());
+        let id = first.installation_id().unwrap();
+        assert_eq!(first.installation_id().unwrap(), id);
+
+        let mut reloaded = ConfigManager {
+            config: ConfigManager::load(&path).unwrap(),
+            ..manager(path.clone())
+        };
+        assert_eq!(reloaded.installation_id().unwrap(), id);
+
+        // 重新注册保留安装ID
+        reloaded.clear_registration().unwrap();
+        assert_eq!(ConfigManager::load(&path).unwrap().installation_id.as_deref(), Some(id.as_str()));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_config_is_backed_up_and_reset() {
+        let dir = temp_dir();
+        let path = dir.join(CONFIG_FILE);
+        std::fs::write(&path, b"{\"base_url\": \"https://backend.example\",\x00").unwrap();
+
+        let config = ConfigManager::load(&path).unwrap();
+        assert_eq!(config.base_url, API_BASE_URL);
+        assert!(config.nodes.is_empty());
+        assert!(!path.exists());
+        let backup = dir.join(format!("{}{}", CONFIG_FILE, CONFIG_CORRUPT_SUFFIX));
+        assert_eq!(std::fs::read(&backup).unwrap(), b"{\"base_url\": \"https://backend.example\",\x00");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,745 @@
+use super::NodeConfig;
+use crate::consts::*;
+use crate::device::registration::RegistrationConfig;
+use crate::device::ProbeConfig;
+use crate::heartbeat::HeartbeatConfig;
+use crate::heartbeat::sampler::MetricAggregation;
+use crate::metrics::history::GpuHistoryConfig;
+use crate::stable_diffusion::{RetryableErrors, SDAuth, SDPaths, DEFAULT_IMAGE_SIZE};
+use crate::task::webhook::{ResultDelivery, WebhookConfig};
+use crate::task::{self, ResultSubjects, TaskProcessorConfig};
+use crate::upload::{ImageCacheConfig, UploadConfig};
+use std::fmt::{self, Display, Write as _};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// 配置项的取值来源
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// Built-in default
+    Default,
+    /// Derived from the detected hardware
+    Detected,
+    /// Set in the config file
+    ConfigFile,
+    /// Set by the named environment variable
+    Env(&'static str),
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Default => f.write_str("default"),
+            Self::Detected => f.write_str("detected"),
+            Self::ConfigFile => f.write_str("config file"),
+            Self::Env(key) => write!(f, "env {}", key),
+        }
+    }
+}
+
+/// 带来源的配置值
+#[derive(Debug, Clone)]
+pub struct Setting<T> {
+    pub value: T,
+    pub source: Source,
+}
+
+impl<T> Setting<T> {
+    fn new(value: T, source: Source) -> Self {
+        Self { value, source }
+    }
+
+    fn default(value: T) -> Self {
+        Self::new(value, Source::Default)
+    }
+}
+
+impl<T: FromStr> Setting<T> {
+    /// 环境变量覆盖低优先级的取值，无法解析时保留原值
+    fn env(key: &'static str, fallback: Setting<T>) -> Self {
+        Self::env_value(key, std::env::var(key).ok(), fallback)
+    }
+
+    /// 用环境变量 `key` 的原始值（未设置时为 `None`）覆盖低优先级的取值
+    fn env_value(key: &'static str, raw: Option<String>, fallback: Setting<T>) -> Self {
+        match raw {
+            Some(raw) => match raw.parse() {
+                Ok(value) => Self::new(value, Source::Env(key)),
+                Err(_) => {
+                    log::warn!("Ignoring invalid value for {}: {:?}", key, raw);
+                    fallback
+                }
+            },
+            None => fallback,
+        }
+    }
+
+    fn env_or(key: &'static str, default: T) -> Self {
+        Self::env(key, Self::default(default))
+    }
+}
+
+impl<T: FromStr> Setting<Option<T>> {
+    /// 可选配置项，未设置或无法解析时为空
+    fn env_opt(key: &'static str) -> Self {
+        Self::env_opt_or(key, Self::default(None))
+    }
+
+    /// 可选配置项，环境变量覆盖低优先级的取值，无法解析时保留原值
+    fn env_opt_or(key: &'static str, fallback: Self) -> Self {
+        match std::env::var(key) {
+            Ok(raw) => match raw.parse() {
+                Ok(value) => Self::new(Some(value), Source::Env(key)),
+                Err(_) => {
+                    log::warn!("Ignoring invalid value for {}: {:?}", key, raw);
+                    fallback
+                }
+            },
+            Err(_) => fallback,
+        }
+    }
+}
+
+/// 节点的完整有效配置
+///
+/// 按 默认值 < 配置文件 < 环境变量 的优先级统一解析，并记录每项的来源。
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub base_url: Setting<String>,
+    pub nats_server: Setting<String>,
+    pub sd_url: Setting<String>,
+    pub sd_auth: Setting<Option<SDAuth>>,
+    pub sd_options: Setting<serde_json::Map<String, serde_json::Value>>,
+    pub access_token: Setting<Option<String>>,
+    pub refresh_token: Setting<Option<String>>,
+
+    pub task_timeout_secs: Setting<u64>,
+    pub max_task_timeout_ms: Setting<u64>,
+    pub sd_max_backoff_ms: Setting<Option<u64>>,
+    pub sd_max_total_retry_ms: Setting<Option<u64>>,
+    pub sd_max_concurrent_requests: Setting<usize>,
+    pub sd_probe_timeout_secs: Setting<u64>,
+    pub sd_probe_budget_secs: Setting<u64>,
+    pub sd_probe_concurrency: Setting<usize>,
+    pub sd_retryable_errors: Setting<Vec<String>>,
+    pub sd_retryable_errors_ignore_case: Setting<bool>,
+    pub sd_path_txt2img: Setting<String>,
+    pub sd_path_options: Setting<String>,
+    pub sd_path_png_info: Setting<String>,
+    pub sd_path_sd_models: Setting<String>,
+    pub sd_path_loras: Setting<String>,
+    pub sd_path_scripts: Setting<String>,
+    pub sd_path_samplers: Setting<String>,
+    pub sd_path_extensions: Setting<String>,
+    pub sd_path_progress: Setting<String>,
+    pub ack_wait_secs: Setting<u64>,
+    pub max_deliver: Setting<i64>,
+    pub max_concurrent_tasks: Setting<usize>,
+    pub throttled_max_concurrent_tasks: Setting<Option<usize>>,
+    pub fetch_batch_size: Setting<usize>,
+    pub task_queue_capacity: Setting<usize>,
+    pub max_pixels: Setting<u64>,
+    pub default_image_size: Setting<u32>,
+    pub vram_sample_interval_ms: Setting<u64>,
+    pub min_gpu_memory_mb: Setting<u64>,
+    pub skip_gpu_memory_check: Setting<bool>,
+    pub registration_max_cycles: Setting<u32>,
+    pub registration_timeout_secs: Setting<u64>,
+    pub reregister_on_fingerprint_mismatch: Setting<bool>,
+    pub max_frames: Setting<u32>,
+    pub max_prompt_length: Setting<usize>,
+    pub allowed_params: Setting<Option<Vec<String>>>,
+    pub global_prompt_suffix: Setting<Option<String>>,
+    pub global_negative_prompt: Setting<Option<String>>,
+    pub verify_loras: Setting<bool>,
+    pub strict_params: Setting<bool>,
+    pub thumbnail_max_size: Setting<Option<u32>>,
+    pub task_log_capture_bytes: Setting<usize>,
+    pub max_error_stack_bytes: Setting<usize>,
+    pub sd_busy_check: Setting<bool>,
+    pub sd_busy_retry_delay_secs: Setting<u64>,
+    pub sd_keep_warm: Setting<bool>,
+    pub shutdown_grace_secs: Setting<u64>,
+    pub idle_exit_secs: Setting<Option<u64>>,
+    pub sd_keep_warm_interval_secs: Setting<u64>,
+    pub publish_attempts: Setting<u32>,
+    pub result_webhook_url: Setting<Option<String>>,
+    pub result_webhook_auth: Setting<Option<String>>,
+    pub result_webhook_attempts: Setting<u32>,
+    pub result_delivery: Setting<ResultDelivery>,
+    pub result_subject_completed: Setting<String>,
+    pub result_subject_failed: Setting<String>,
+    pub lifecycle_subject: Setting<Option<String>>,
+
+    pub upload_url: Setting<Option<String>>,
+    pub upload_public_url: Setting<Option<String>>,
+    pub upload_auth_token: Setting<Option<String>>,
+    pub upload_concurrency: Setting<usize>,
+    pub upload_allow_partial: Setting<bool>,
+    pub upload_multipart_threshold: Setting<Option<usize>>,
+    pub upload_chunk_size: Setting<usize>,
+    pub image_cache_dir: Setting<Option<String>>,
+    pub image_cache_max_bytes: Setting<u64>,
+    pub image_cache_public_url: Setting<Option<String>>,
+
+    pub heartbeat_interval_secs: Setting<u64>,
+    pub heartbeat_jitter: Setting<f64>,
+    pub heartbeat_client_max_age_secs: Setting<u64>,
+    pub heartbeat_failure_threshold: Setting<u32>,
+    pub heartbeat_samples: Setting<u32>,
+    pub heartbeat_aggregation: Setting<MetricAggregation>,
+    pub opaque_token_lifetime_secs: Setting<Option<u64>>,
+
+    pub metrics_addr: Setting<Option<String>>,
+    pub gpu_history_size: Setting<usize>,
+    pub gpu_history_file: Setting<Option<String>>,
+    pub gpu_history_max_bytes: Setting<u64>,
+}
+
+impl Settings {
+    /// 解析有效配置，`gpu_memory_mb` 用于推导默认的像素上限
+    pub fn resolve(config: &NodeConfig, gpu_memory_mb: Option<u64>) -> Self {
+        let base_url = if config.base_url == API_BASE_URL {
+            Setting::default(config.base_url.clone())
+        } else {
+            Setting::new(config.base_url.clone(), Source::ConfigFile)
+        };
+
+        let sd_auth = match SDAuth::from_env() {
+            Some(auth @ SDAuth::Bearer(_)) => Setting::new(Some(auth), Source::Env("SD_API_KEY")),
+            Some(auth) => Setting::new(Some(auth), Source::Env("SD_USERNAME")),
+            None => Setting::default(None),
+        };
+
+        let detected_max_pixels = match gpu_memory_mb {
+            Some(_) => Setting::new(task::default_max_pixels(gpu_memory_mb), Source::Detected),
+            None => Setting::default(DEFAULT_MAX_PIXELS),
+        };
+        let detected_image_size = match gpu_memory_mb {
+            Some(_) => Setting::new(task::default_image_size(gpu_memory_mb), Source::Detected),
+            None => Setting::default(DEFAULT_IMAGE_SIZE),
+        };
+
+        // 逗号分隔的参数白名单，环境变量优先于配置文件；空白名单等同于未配置，不限制参数
+        let allowed_params = match std::env::var("ALLOWED_TASK_PARAMS") {
+            Ok(raw) => Setting::new(
+                Some(raw.split(',').map(str::trim).filter(|key| !key.is_empty()).map(String::from).collect::<Vec<_>>())
+                    .filter(|keys| !keys.is_empty()),
+                Source::Env("ALLOWED_TASK_PARAMS"),
+            ),
+            Err(_) if config.allowed_params.is_some() => Setting::new(
+                config.allowed_params.clone().filter(|keys| !keys.is_empty()),
+                Source::ConfigFile,
+            ),
+            Err(_) => Setting::default(None),
+        };
+
+        // 逗号分隔的可重试SD错误子串，环境变量优先于配置文件
+        let sd_retryable_errors = match std::env::var("SD_RETRYABLE_ERRORS") {
+            Ok(raw) => Setting::new(
+                raw.split(',').map(str::trim).filter(|s| !s.is_empty()).map(String::from).collect(),
+                Source::Env("SD_RETRYABLE_ERRORS"),
+            ),
+            Err(_) if !config.retryable_sd_errors.is_empty() => {
+                Setting::new(config.retryable_sd_errors.clone(), Source::ConfigFile)
+            }
+            Err(_) => Setting::default(Vec::new()),
+        };
+
+        // SD端点路径：配置文件中与默认值不同的路径计为配置文件来源
+        let default_paths = SDPaths::default();
+        let file_paths = config.sd_paths.clone().unwrap_or_default();
+        let sd_path = |key: &'static str, file: &str, default: &str| {
+            let fallback = if file != default {
+                Setting::new(file.to_string(), Source::ConfigFile)
+            } else {
+                Setting::default(default.to_string())
+            };
+            Setting::env(key, fallback)
+        };
+
+        // 配置了 webhook 时默认同时投递到 NATS 与 webhook
+        let result_webhook_url = Setting::env_opt("RESULT_WEBHOOK_URL");
+        let default_delivery = if result_webhook_url.value.is_some() {
+            ResultDelivery::Both
+        } else {
+            ResultDelivery::Nats
+        };
+
+        Self {
+            base_url,
+            nats_server: Setting::env("NATS_SERVER", from_config_or(config.nats_server.clone(), NATS_SERVER_URL)),
+            sd_url: Setting::env("SD_URL", from_config_or(config.sd_url.clone(), SD_API_URL)),
+            sd_auth,
+            sd_options: if config.sd_options.is_empty() {
+                Setting::default(config.sd_options.clone())
+            } else {
+                Setting::new(config.sd_options.clone(), Source::ConfigFile)
+            },
+            access_token: from_config(config.access_token.clone()),
+            refresh_token: from_config(config.refresh_token.clone()),
+
+            task_timeout_secs: Setting::env_or("TASK_TIMEOUT_SECS", TASK_TIMEOUT_SECONDS),
+            max_task_timeout_ms: Setting::env_or("MAX_TASK_TIMEOUT_MS", MAX_TASK_TIMEOUT_MS),
+            sd_max_backoff_ms: Setting::env_opt("SD_MAX_BACKOFF_MS"),
+            sd_max_total_retry_ms: Setting::env_opt("SD_MAX_TOTAL_RETRY_MS"),
+            sd_max_concurrent_requests: Setting::env_or("SD_MAX_CONCURRENT_REQUESTS", SD_MAX_CONCURRENT_REQUESTS),
+            sd_probe_timeout_secs: Setting::env(
+                "SD_PROBE_TIMEOUT_SECS",
+                match config.sd_probe_timeout_secs {
+                    Some(secs) => Setting::new(secs, Source::ConfigFile),
+                    None => Setting::default(SD_PROBE_TIMEOUT_SECONDS),
+                },
+            ),
+            sd_probe_budget_secs: Setting::env_or("SD_PROBE_BUDGET_SECS", SD_PROBE_BUDGET_SECONDS),
+            sd_probe_concurrency: Setting::env_or("SD_PROBE_CONCURRENCY", SD_PROBE_CONCURRENCY),
+            sd_retryable_errors,
+            sd_retryable_errors_ignore_case: Setting::env_or("SD_RETRYABLE_ERRORS_IGNORE_CASE", false),
+            sd_path_txt2img: sd_path("SD_PATH_TXT2IMG", &file_paths.txt2img, &default_paths.txt2img),
+            sd_path_options: sd_path("SD_PATH_OPTIONS", &file_paths.options, &default_paths.options),
+            sd_path_png_info: sd_path("SD_PATH_PNG_INFO", &file_paths.png_info, &default_paths.png_info),
+            sd_path_sd_models: sd_path("SD_PATH_SD_MODELS", &file_paths.sd_models, &default_paths.sd_models),
+            sd_path_loras: sd_path("SD_PATH_LORAS", &file_paths.loras, &default_paths.loras),
+            sd_path_scripts: sd_path("SD_PATH_SCRIPTS", &file_paths.scripts, &default_paths.scripts),
+            sd_path_samplers: sd_path("SD_PATH_SAMPLERS", &file_paths.samplers, &default_paths.samplers),
+            sd_path_extensions: sd_path("SD_PATH_EXTENSIONS", &file_paths.extensions, &default_paths.extensions),
+            sd_path_progress: sd_path("SD_PATH_PROGRESS", &file_paths.progress, &default_paths.progress),
+            ack_wait_secs: Setting::env_or("JETSTREAM_ACK_WAIT_SECS", JETSTREAM_ACK_WAIT_SECONDS),
+            max_deliver: Setting::env_or("JETSTREAM_MAX_DELIVER", JETSTREAM_MAX_DELIVER),
+            max_concurrent_tasks: Setting::env_or("MAX_CONCURRENT_TASKS", MAX_CONCURRENT_TASKS),
+            throttled_max_concurrent_tasks: Setting::env_opt("THROTTLED_MAX_CONCURRENT_TASKS"),
+            fetch_batch_size: Setting::env_or("FETCH_BATCH_SIZE", FETCH_BATCH_SIZE),
+            task_queue_capacity: Setting::env_or("TASK_QUEUE_CAPACITY", TASK_QUEUE_CAPACITY),
+            max_pixels: Setting::env("MAX_PIXELS", detected_max_pixels),
+            default_image_size: Setting::env("DEFAULT_IMAGE_SIZE", detected_image_size),
+            vram_sample_interval_ms: Setting::env_or("VRAM_SAMPLE_INTERVAL_MS", VRAM_SAMPLE_INTERVAL_MS),
+            min_gpu_memory_mb: Setting::env_or("MIN_GPU_MEMORY_MB", MIN_GPU_MEMORY_MB),
+            skip_gpu_memory_check: Setting::env_or("SKIP_GPU_MEMORY_CHECK", false),
+            registration_max_cycles: Setting::env_or("REGISTRATION_MAX_CYCLES", REGISTRATION_MAX_CYCLES),
+            registration_timeout_secs: Setting::env_or("REGISTRATION_TIMEOUT_SECS", REGISTRATION_TIMEOUT_SECONDS),
+            reregister_on_fingerprint_mismatch: Setting::env_or("REREGISTER_ON_FINGERPRINT_MISMATCH", false),
+            max_frames: Setting::env_or("MAX_ANIMATION_FRAMES", MAX_ANIMATION_FRAMES),
+            max_prompt_length: Setting::env_or("MAX_PROMPT_LENGTH", MAX_PROMPT_LENGTH),
+            allowed_params,
+            global_prompt_suffix: Setting::env_opt_or("GLOBAL_PROMPT_SUFFIX", from_config(config.global_prompt_suffix.clone())),
+            global_negative_prompt: Setting::env_opt_or("GLOBAL_NEGATIVE_PROMPT", from_config(config.global_negative_prompt.clone())),
+            verify_loras: Setting::env_or("VERIFY_LORAS", false),
+            strict_params: Setting::env_or("STRICT_TASK_PARAMS", false),
+            thumbnail_max_size: Setting::env_opt("THUMBNAIL_MAX_SIZE"),
+            task_log_capture_bytes: Setting::env_or("TASK_LOG_CAPTURE_BYTES", TASK_LOG_CAPTURE_BYTES),
+            max_error_stack_bytes: Setting::env_or("MAX_ERROR_STACK_BYTES", MAX_ERROR_STACK_BYTES),
+            sd_busy_check: Setting::env_or("SD_BUSY_CHECK", false),
+            sd_busy_retry_delay_secs: Setting::env_or("SD_BUSY_RETRY_DELAY_SECS", SD_BUSY_RETRY_DELAY_SECONDS),
+            sd_keep_warm: Setting::env_or("SD_KEEP_WARM", false),
+            shutdown_grace_secs: Setting::env_or("SHUTDOWN_GRACE_SECS", SHUTDOWN_GRACE_SECONDS),
+            idle_exit_secs: Setting::env_opt_or(
+                "IDLE_EXIT_SECS",
+                match config.idle_exit_secs {
+                    Some(secs) => Setting::new(Some(secs), Source::ConfigFile),
+                    None => Setting::default(None),
+                },
+            ),
+            sd_keep_warm_interval_secs: Setting::env_or("SD_KEEP_WARM_INTERVAL_SECS", SD_KEEP_WARM_INTERVAL_SECONDS),
+            publish_attempts: Setting::env_or("PUBLISH_ATTEMPTS", PUBLISH_ATTEMPTS),
+            result_webhook_url,
+            result_webhook_auth: Setting::env_opt("RESULT_WEBHOOK_AUTH"),
+            result_webhook_attempts: Setting::env_or("RESULT_WEBHOOK_ATTEMPTS", RESULT_WEBHOOK_ATTEMPTS),
+            result_delivery: Setting::env_or("RESULT_DELIVERY", default_delivery),
+            result_subject_completed: Setting::env_or("RESULT_SUBJECT_COMPLETED", RESULT_SUBJECT_TEMPLATE.to_string()),
+            result_subject_failed: Setting::env_or("RESULT_SUBJECT_FAILED", RESULT_SUBJECT_TEMPLATE.to_string()),
+            lifecycle_subject: Setting::env_opt("LIFECYCLE_SUBJECT"),
+
+            upload_url: Setting::env_opt("UPLOAD_URL"),
+            upload_public_url: Setting::env_opt("UPLOAD_PUBLIC_URL"),
+            upload_auth_token: Setting::env_opt("UPLOAD_AUTH_TOKEN"),
+            upload_concurrency: Setting::env_or("UPLOAD_CONCURRENCY", UPLOAD_CONCURRENCY),
+            upload_allow_partial: Setting::env_or("UPLOAD_ALLOW_PARTIAL", false),
+            upload_multipart_threshold: Setting::env_opt("UPLOAD_MULTIPART_THRESHOLD"),
+            upload_chunk_size: Setting::env_or("UPLOAD_CHUNK_SIZE", UPLOAD_CHUNK_SIZE),
+            image_cache_dir: Setting::env_opt("IMAGE_CACHE_DIR"),
+            image_cache_max_bytes: Setting::env_or("IMAGE_CACHE_MAX_BYTES", IMAGE_CACHE_MAX_BYTES),
+            image_cache_public_url: Setting::env_opt("IMAGE_CACHE_PUBLIC_URL"),
+
+            heartbeat_interval_secs: Setting::env_or("HEARTBEAT_INTERVAL_SECS", HEARTBEAT_INTERVAL_SECONDS),
+            heartbeat_jitter: Setting::env_or("HEARTBEAT_JITTER", HEARTBEAT_JITTER_FRACTION),
+            heartbeat_client_max_age_secs: Setting::env_or(
+                "HEARTBEAT_CLIENT_MAX_AGE_SECS",
+                HEARTBEAT_CLIENT_MAX_AGE_SECONDS,
+            ),
+            heartbeat_failure_threshold: Setting::env_or("HEARTBEAT_FAILURE_THRESHOLD", HEARTBEAT_FAILURE_THRESHOLD),
+            heartbeat_samples: Setting::env_or("HEARTBEAT_SAMPLES", HEARTBEAT_SAMPLES_PER_INTERVAL),
+            heartbeat_aggregation: Setting::env_or("HEARTBEAT_AGGREGATION", MetricAggregation::Mean),
+            opaque_token_lifetime_secs: Setting::env_opt("OPAQUE_TOKEN_LIFETIME_SECS"),
+
+            metrics_addr: Setting::env_opt("METRICS_ADDR"),
+            gpu_history_size: Setting::env_or("GPU_HISTORY_SIZE", GPU_HISTORY_SIZE),
+            gpu_history_file: Setting::env_opt("GPU_HISTORY_FILE"),
+            gpu_history_max_bytes: Setting::env_or("GPU_HISTORY_MAX_BYTES", GPU_HISTORY_MAX_BYTES),
+        }
+    }
+
+    /// SD API端点路径
+    pub fn sd_paths(&self) -> SDPaths {
+        SDPaths {
+            txt2img: self.sd_path_txt2img.value.clone(),
+            options: self.sd_path_options.value.clone(),
+            png_info: self.sd_path_png_info.value.clone(),
+            sd_models: self.sd_path_sd_models.value.clone(),
+            loras: self.sd_path_loras.value.clone(),
+            scripts: self.sd_path_scripts.value.clone(),
+            samplers: self.sd_path_samplers.value.clone(),
+            extensions: self.sd_path_extensions.value.clone(),
+            progress: self.sd_path_progress.value.clone(),
+        }
+    }
+
+    /// 启动阶段SD能力探测的限制
+    pub fn probe_config(&self) -> ProbeConfig {
+        ProbeConfig {
+            timeout: Duration::from_secs(self.sd_probe_timeout_secs.value.max(1)),
+            budget: Duration::from_secs(self.sd_probe_budget_secs.value.max(1)),
+            concurrency: self.sd_probe_concurrency.value.max(1),
+        }
+    }
+
+    /// 对象存储上传配置，未设置 `UPLOAD_URL` 时为空
+    pub fn upload_config(&self) -> Option<UploadConfig> {
+        self.upload_url.value.clone().map(|url| UploadConfig {
+            url,
+            public_url: self.upload_public_url.value.clone(),
+            auth_token: self.upload_auth_token.value.clone(),
+            concurrency: self.upload_concurrency.value,
+            allow_partial: self.upload_allow_partial.value,
+            multipart_threshold: self.upload_multipart_threshold.value.filter(|&threshold| threshold > 0),
+            chunk_size: self.upload_chunk_size.value,
+        })
+    }
+
+    /// 本地图片缓存配置，未设置 `IMAGE_CACHE_DIR` 或无法确定拉取地址时为空
+    ///
+    /// 拉取地址默认为指标服务地址（`METRICS_ADDR`），后端需要通过其他地址访问节点时设置 `IMAGE_CACHE_PUBLIC_URL`。
+    pub fn image_cache_config(&self) -> Option<ImageCacheConfig> {
+        let dir = self.image_cache_dir.value.as_ref()?;
+        let public_url = self
+            .image_cache_public_url
+            .value
+            .clone()
+            .or_else(|| self.metrics_addr.value.as_ref().map(|addr| format!("http://{}", addr)))?;
+        Some(ImageCacheConfig {
+            dir: PathBuf::from(dir),
+            max_bytes: self.image_cache_max_bytes.value,
+            public_url,
+        })
+    }
+
+    /// 结果回调配置，未设置 `RESULT_WEBHOOK_URL` 时为空
+    pub fn webhook_config(&self) -> Option<WebhookConfig> {
+        self.result_webhook_url.value.clone().map(|url| WebhookConfig {
+            url,
+            auth_header: self.result_webhook_auth.value.clone(),
+            attempts: self.result_webhook_attempts.value,
+        })
+    }
+
+    /// 所有节点共享的任务处理器配置，节点相关字段由调用方填充
+    pub fn task_config(&self, config: &NodeConfig) -> TaskProcessorConfig {
+        TaskProcessorConfig {
+            nats_server: self.nats_server.value.clone(),
+            sd_url: self.sd_url.value.clone(),
+            sd_auth: self.sd_auth.value.clone(),
+            node_id: String::new(),
+            consumer_name: DEFAULT_CONSUMER_NAME.to_string(),
+            task_timeout_secs: self.task_timeout_secs.value,
+            max_task_timeout_ms: self.max_task_timeout_ms.value,
+            sd_max_backoff_ms: self.sd_max_backoff_ms.value,
+            sd_max_total_retry_ms: self.sd_max_total_retry_ms.value,
+            sd_max_concurrent_requests: self.sd_max_concurrent_requests.value,
+            sd_probe_timeout_secs: self.sd_probe_timeout_secs.value.max(1),
+            sd_paths: self.sd_paths(),
+            sd_retryable_errors: RetryableErrors::new(
+                &self.sd_retryable_errors.value,
+                self.sd_retryable_errors_ignore_case.value,
+            ),
+            ack_wait_secs: self.ack_wait_secs.value,
+            max_deliver: self.max_deliver.value,
+            max_concurrent_tasks: self.max_concurrent_tasks.value,
+            throttled_max_concurrent_tasks: self.throttled_max_concurrent_tasks.value,
+            // 按节点设置，见 main 中的 node_task_config
+            shared_consumer: false,
+            fetch_batch_size: self.fetch_batch_size.value,
+            upload: self.upload_config(),
+            // 缓存需要先打开目录，由调用方创建后填入
+            image_cache: None,
+            result_webhook: self.webhook_config(),
+            result_delivery: self.result_delivery.value,
+            result_subjects: ResultSubjects {
+                completed: self.result_subject_completed.value.clone(),
+                failed: self.result_subject_failed.value.clone(),
+            },
+            // 设为空字符串时不发布生命周期事件
+            lifecycle_subject: non_empty(&self.lifecycle_subject.value),
+            max_pixels: self.max_pixels.value,
+            default_image_size: self.default_image_size.value,
+            vram_sample_interval_ms: self.vram_sample_interval_ms.value,
+            sd_busy_check: self.sd_busy_check.value,
+            sd_busy_retry_delay_secs: self.sd_busy_retry_delay_secs.value,
+            sd_keep_warm_interval_secs: self.sd_keep_warm.value.then_some(self.sd_keep_warm_interval_secs.value),
+            shutdown_grace_secs: self.shutdown_grace_secs.value,
+            publish_attempts: self.publish_attempts.value,
+            styles: config.styles.clone(),
+            sd_options: self.sd_options.value.clone(),
+            task_queue_capacity: self.task_queue_capacity.value,
+            max_frames: self.max_frames.value,
+            max_prompt_length: self.max_prompt_length.value,
+            allowed_params: self.allowed_params.value.clone(),
+            global_prompt_suffix: non_empty(&self.global_prompt_suffix.value),
+            global_negative_prompt: non_empty(&self.global_negative_prompt.value),
+            verify_loras: self.verify_loras.value,
+            strict_params: self.strict_params.value,
+            thumbnail_max_size: self.thumbnail_max_size.value.filter(|&size| size > 0),
+            task_log_capture_bytes: self.task_log_capture_bytes.value,
+            max_error_stack_bytes: self.max_error_stack_bytes.value,
+        }
+    }
+
+    pub fn registration_config(&self) -> RegistrationConfig {
+        RegistrationConfig {
+            max_cycles: self.registration_max_cycles.value.max(1),
+            timeout: Duration::from_secs(self.registration_timeout_secs.value),
+        }
+    }
+
+    pub fn heartbeat_config(&self) -> HeartbeatConfig {
+        HeartbeatConfig {
+            interval_secs: self.heartbeat_interval_secs.value,
+            jitter_fraction: self.heartbeat_jitter.value,
+            client_max_age_secs: self.heartbeat_client_max_age_secs.value,
+            failure_threshold: self.heartbeat_failure_threshold.value,
+            opaque_token_lifetime_secs: self.opaque_token_lifetime_secs.value,
+            samples_per_interval: self.heartbeat_samples.value,
+            aggregation: self.heartbeat_aggregation.value,
+        }
+    }
+
+    pub fn gpu_history_config(&self) -> GpuHistoryConfig {
+        GpuHistoryConfig {
+            capacity: self.gpu_history_size.value,
+            file: self.gpu_history_file.value.as_ref().map(PathBuf::from),
+            max_file_bytes: self.gpu_history_max_bytes.value,
+        }
+    }
+
+    /// 以 `名称 = 值 (来源)` 的格式输出所有配置项，默认隐藏令牌等敏感信息
+    pub fn dump(&self, show_secrets: bool) -> String {
+        let secret = |value: &Option<String>| match value {
+            Some(value) if show_secrets => value.clone(),
+            Some(_) => "<redacted>".to_string(),
+            None => "(unset)".to_string(),
+        };
+        let sd_auth = match &self.sd_auth.value {
+            Some(SDAuth::Bearer(token)) => format!("bearer {}", secret(&Some(token.clone()))),
+            Some(SDAuth::Basic { username, password }) => {
+                format!("basic {}:{}", username, secret(&Some(password.clone())))
+            }
+            None => "(unset)".to_string(),
+        };
+
+        let rows: Vec<(&str, String, Source)> = vec![
+            ("base_url", self.base_url.value.clone(), self.base_url.source),
+            ("nats_server", self.nats_server.value.clone(), self.nats_server.source),
+            ("sd_url", self.sd_url.value.clone(), self.sd_url.source),
+            ("sd_auth", sd_auth, self.sd_auth.source),
+            (
+                "sd_options",
+                serde_json::Value::Object(self.sd_options.value.clone()).to_string(),
+                self.sd_options.source,
+            ),
+            ("access_token", secret(&self.access_token.value), self.access_token.source),
+            ("refresh_token", secret(&self.refresh_token.value), self.refresh_token.source),
+            row("task_timeout_secs", &self.task_timeout_secs),
+            row("max_task_timeout_ms", &self.max_task_timeout_ms),
+            row_opt("sd_max_backoff_ms", &self.sd_max_backoff_ms),
+            row_opt("sd_max_total_retry_ms", &self.sd_max_total_retry_ms),
+            row("sd_max_concurrent_requests", &self.sd_max_concurrent_requests),
+            row("sd_probe_timeout_secs", &self.sd_probe_timeout_secs),
+            row("sd_probe_budget_secs", &self.sd_probe_budget_secs),
+            row("sd_probe_concurrency", &self.sd_probe_concurrency),
+            (
+                "sd_retryable_errors",
+                match self.sd_retryable_errors.value.as_slice() {
+                    [] => "(built-in only)".to_string(),
+                    extra => extra.join(","),
+                },
+                self.sd_retryable_errors.source,
+            ),
+            row("sd_retryable_errors_ignore_case", &self.sd_retryable_errors_ignore_case),
+            row("sd_path_txt2img", &self.sd_path_txt2img),
+            row("sd_path_options", &self.sd_path_options),
+            row("sd_path_png_info", &self.sd_path_png_info),
+            row("sd_path_sd_models", &self.sd_path_sd_models),
+            row("sd_path_loras", &self.sd_path_loras),
+            row("sd_path_scripts", &self.sd_path_scripts),
+            row("sd_path_samplers", &self.sd_path_samplers),
+            row("sd_path_extensions", &self.sd_path_extensions),
+            row("sd_path_progress", &self.sd_path_progress),
+            row("ack_wait_secs", &self.ack_wait_secs),
+            row("max_deliver", &self.max_deliver),
+            row("max_concurrent_tasks", &self.max_concurrent_tasks),
+            row_opt("throttled_max_concurrent_tasks", &self.throttled_max_concurrent_tasks),
+            row("fetch_batch_size", &self.fetch_batch_size),
+            row("task_queue_capacity", &self.task_queue_capacity),
+            row("max_pixels", &self.max_pixels),
+            row("default_image_size", &self.default_image_size),
+            row("vram_sample_interval_ms", &self.vram_sample_interval_ms),
+            row("min_gpu_memory_mb", &self.min_gpu_memory_mb),
+            row("skip_gpu_memory_check", &self.skip_gpu_memory_check),
+            row("registration_max_cycles", &self.registration_max_cycles),
+            row("registration_timeout_secs", &self.registration_timeout_secs),
+            row("reregister_on_fingerprint_mismatch", &self.reregister_on_fingerprint_mismatch),
+            row("max_frames", &self.max_frames),
+            row("max_prompt_length", &self.max_prompt_length),
+            (
+                "allowed_params",
+                match &self.allowed_params.value {
+                    Some(keys) => keys.join(","),
+                    None => "(any)".to_string(),
+                },
+                self.allowed_params.source,
+            ),
+            row_opt("global_prompt_suffix", &self.global_prompt_suffix),
+            row_opt("global_negative_prompt", &self.global_negative_prompt),
+            row("verify_loras", &self.verify_loras),
+            row("strict_params", &self.strict_params),
+            row_opt("thumbnail_max_size", &self.thumbnail_max_size),
+            row("task_log_capture_bytes", &self.task_log_capture_bytes),
+            row("max_error_stack_bytes", &self.max_error_stack_bytes),
+            row("sd_busy_check", &self.sd_busy_check),
+            row("sd_busy_retry_delay_secs", &self.sd_busy_retry_delay_secs),
+            row("sd_keep_warm", &self.sd_keep_warm),
+            row("sd_keep_warm_interval_secs", &self.sd_keep_warm_interval_secs),
+            row("shutdown_grace_secs", &self.shutdown_grace_secs),
+            row_opt("idle_exit_secs", &self.idle_exit_secs),
+            row("publish_attempts", &self.publish_attempts),
+            row_opt("result_webhook_url", &self.result_webhook_url),
+            ("result_webhook_auth", secret(&self.result_webhook_auth.value), self.result_webhook_auth.source),
+            row("result_webhook_attempts", &self.result_webhook_attempts),
+            row("result_delivery", &self.result_delivery),
+            row("result_subject_completed", &self.result_subject_completed),
+            row("result_subject_failed", &self.result_subject_failed),
+            row_opt("lifecycle_subject", &self.lifecycle_subject),
+            row_opt("upload_url", &self.upload_url),
+            row_opt("upload_public_url", &self.upload_public_url),
+            ("upload_auth_token", secret(&self.upload_auth_token.value), self.upload_auth_token.source),
+            row("upload_concurrency", &self.upload_concurrency),
+            row("upload_allow_partial", &self.upload_allow_partial),
+            row_opt("upload_multipart_threshold", &self.upload_multipart_threshold),
+            row("upload_chunk_size", &self.upload_chunk_size),
+            row_opt("image_cache_dir", &self.image_cache_dir),
+            row("image_cache_max_bytes", &self.image_cache_max_bytes),
+            row_opt("image_cache_public_url", &self.image_cache_public_url),
+            row("heartbeat_interval_secs", &self.heartbeat_interval_secs),
+            row("heartbeat_jitter", &self.heartbeat_jitter),
+            row("heartbeat_client_max_age_secs", &self.heartbeat_client_max_age_secs),
+            row("heartbeat_failure_threshold", &self.heartbeat_failure_threshold),
+            row("heartbeat_samples", &self.heartbeat_samples),
+            row("heartbeat_aggregation", &self.heartbeat_aggregation),
+            row_opt("opaque_token_lifetime_secs", &self.opaque_token_lifetime_secs),
+            row_opt("metrics_addr", &self.metrics_addr),
+            row("gpu_history_size", &self.gpu_history_size),
+            row_opt("gpu_history_file", &self.gpu_history_file),
+            row("gpu_history_max_bytes", &self.gpu_history_max_bytes),
+        ];
+
+        let width = rows.iter().map(|(name, _, _)| name.len()).max().unwrap_or(0);
+        let mut out = String::new();
+        for (name, value, source) in rows {
+            let _ = writeln!(out, "{:width$} = {} ({})", name, value, source, width = width);
+        }
+        out
+    }
+}
+
+fn from_config(value: Option<String>) -> Setting<Option<String>> {
+    let source = if value.is_some() { Source::ConfigFile } else { Source::Default };
+    Setting::new(value, source)
+}
+
+/// 配置文件中的取值，未设置时使用内置默认值
+fn from_config_or(value: Option<String>, default: &str) -> Setting<String> {
+    match value {
+        Some(value) => Setting::new(value, Source::ConfigFile),
+        None => Setting::default(default.to_string()),
+    }
+}
+
+/// 去除首尾空白后为空的文本视为未设置
+fn non_empty(value: &Option<String>) -> Option<String> {
+    value.as_deref().map(str::trim).filter(|text| !text.is_empty()).map(String::from)
+}
+
+fn row<'a, T: Display>(name: &'a str, setting: &Setting<T>) -> (&'a str, String, Source) {
+    (name, setting.value.to_string(), setting.source)
+}
+
+fn row_opt<'a, T: Display>(name: &'a str, setting: &Setting<Option<T>>) -> (&'a str, String, Source) {
+    let value = match &setting.value {
+        Some(value) => value.to_string(),
+        None => "(unset)".to_string(),
+    };
+    (name, value, setting.source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn endpoints_prefer_env_then_config_file_then_default() {
+        let endpoints = [
+            ("NATS_SERVER", NATS_SERVER_URL, "nats://file:4222", "nats://env:4222"),
+            ("SD_URL", SD_API_URL, "http://file:7860", "http://env:7860"),
+        ];
+        for (key, default, file, env) in endpoints {
+            let resolved = Setting::env_value(key, Some(env.to_string()), from_config_or(Some(file.to_string()), default));
+            assert_eq!((resolved.value.as_str(), resolved.source), (env, Source::Env(key)));
+
+            let resolved = Setting::env_value(key, None, from_config_or(Some(file.to_string()), default));
+            assert_eq!((resolved.value.as_str(), resolved.source), (file, Source::ConfigFile));
+
+            let resolved = Setting::env_value(key, None, from_config_or(None, default));
+            assert_eq!((resolved.value.as_str(), resolved.source), (default, Source::Default));
+        }
+    }
+
+    #[test]
+    fn invalid_env_value_keeps_lower_layer() {
+        let resolved = Setting::env_value("MAX_CONCURRENT_TASKS", Some("many".to_string()), Setting::new(4usize, Source::ConfigFile));
+        assert_eq!((resolved.value, resolved.source), (4, Source::ConfigFile));
+    }
+
+    #[test]
+    fn base_url_comes_from_config_file_or_default() {
+        let config = NodeConfig::default();
+        let resolved = Settings::resolve(&config, None).base_url;
+        assert_eq!((resolved.value.as_str(), resolved.source), (API_BASE_URL, Source::Default));
+
+        let config = NodeConfig { base_url: "https://backend.example".to_string(), ..NodeConfig::default() };
+        let resolved = Settings::resolve(&config, None).base_url;
+        assert_eq!((resolved.value.as_str(), resolved.source), ("https://backend.example", Source::ConfigFile));
+    }
+
+    #[test]
+    fn lifecycle_events_are_opt_in() {
+        let config = NodeConfig::default();
+        let mut settings = Settings::resolve(&config, None);
+        assert_eq!(settings.lifecycle_subject.value, None);
+        assert_eq!(settings.task_config(&config).lifecycle_subject, None);
+
+        settings.lifecycle_subject = Setting::new(Some("  ".to_string()), Source::Env("LIFECYCLE_SUBJECT"));
+        assert_eq!(settings.task_config(&config).lifecycle_subject, None);
+
+        settings.lifecycle_subject = Setting::new(Some("nodes.{node_id}.lifecycle".to_string()), Source::Env("LIFECYCLE_SUBJECT"));
+        assert_eq!(settings.task_config(&config).lifecycle_subject.as_deref(), Some("nodes.{node_id}.lifecycle"));
+    }
+}
@@ -2,6 +2,7 @@
 pub const API_BASE_URL: &str = "https://zkom-backend.abo.network";
 #[allow(dead_code)]
 pub const API_VERSION: &str = "v1";
+pub const CLIENT_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 // NATS和Stable Diffusion配置
 pub const NATS_SERVER_URL: &str = "nats://zkom-nats.abo.network:4222";
@@ -13,9 +14,82 @@ pub const DEVICE_CODE_LENGTH: usize = 8;
 #[allow(dead_code)]
 pub const DEVICE_CODE_EXPIRY_SECONDS: u64 = 300; // 5 minutes
 pub const DEVICE_VERIFY_POLL_INTERVAL: u64 = 5; // 5 seconds
+pub const DEVICE_VERIFY_MAX_BACKOFF_SECONDS: u64 = 60; // Cap for verify polling backoff while the backend is unreachable
+pub const REGISTRATION_MAX_CYCLES: u32 = 3; // Device codes issued before registration gives up
+pub const REGISTRATION_TIMEOUT_SECONDS: u64 = 3600; // Overall registration budget across all device codes
+pub const EGRESS_IP_ECHO_URL: &str = "https://api.ipify.org"; // Returns the caller's public IP as plain text
+pub const EGRESS_IP_TIMEOUT_SECONDS: u64 = 5;
 
 // 心跳相关配置
 pub const HEARTBEAT_INTERVAL_SECONDS: u64 = 60; // Default 60 seconds heartbeat interval
+// Bounds applied to a server-supplied heartbeat interval
+pub const HEARTBEAT_MIN_INTERVAL_SECONDS: u64 = 10;
+pub const HEARTBEAT_MAX_INTERVAL_SECONDS: u64 = 600;
+pub const HEARTBEAT_JITTER_FRACTION: f64 = 0.1; // ±10% randomization of each heartbeat sleep
+pub const HEARTBEAT_CLIENT_MAX_AGE_SECONDS: u64 = 6 * 3600; // Rebuild the backend HTTP client after this long
+pub const HEARTBEAT_FAILURE_THRESHOLD: u32 = 3; // Consecutive failed rounds before heartbeats back off
+pub const HEARTBEAT_MAX_BACKOFF_SECONDS: u64 = 1800; // Cap for the backoff while the breaker is open
+// GPU load readings per heartbeat interval; each extra reading runs nvidia-smi once
+// (roughly 20-50 ms of CPU), so the default is a single read at heartbeat time
+pub const HEARTBEAT_SAMPLES_PER_INTERVAL: u32 = 1;
+pub const HEARTBEAT_MIN_SAMPLE_PERIOD_MS: u64 = 1000; // Floor for the spacing between background readings
+pub const GPU_TEMPERATURE_MIN_C: u8 = 5; // Readings outside this range are treated as bad reads
+pub const GPU_TEMPERATURE_MAX_C: u8 = 110;
+pub const GPU_HISTORY_SIZE: usize = 120; // GPU samples kept in memory (2 hours at the default interval)
+pub const GPU_HISTORY_MAX_BYTES: u64 = 10 * 1024 * 1024; // History file size before rotation
+pub const STATUS_GPU_HISTORY_SAMPLES: usize = 10; // Samples shown by the status command
+
+// 任务处理相关配置
+pub const DEFAULT_CONSUMER_NAME: &str = "zkom-processor";
+pub const TASK_TIMEOUT_SECONDS: u64 = 120; // Default Stable Diffusion request timeout per task
+pub const MAX_TASK_TIMEOUT_MS: u64 = 600_000; // Upper bound for the per-task `timeout_ms` param
+// JetStream ack wait must comfortably exceed the task timeout (including SD retries),
+// otherwise messages are redelivered while still being processed
+pub const JETSTREAM_ACK_WAIT_SECONDS: u64 = 900;
+pub const JETSTREAM_MAX_DELIVER: i64 = 3; // Max delivery attempts per task message
+pub const MAX_CONCURRENT_TASKS: usize = 1; // Tasks processed concurrently per node
+pub const SD_MAX_CONCURRENT_REQUESTS: usize = 1; // Generation requests in flight per SD server
+pub const FETCH_BATCH_SIZE: usize = 1; // Messages per JetStream fetch, 1 streams messages one at a time
+pub const TASK_QUEUE_CAPACITY: usize = 64; // Fetched messages held for priority ordering
+pub const FETCH_IDLE_DELAY_MS: u64 = 1000; // Delay before fetching again when a batch was empty
+// Generation size limit (width × height × batch). Derived from GPU memory unless overridden
+pub const MAX_PIXELS_PER_GPU_MB: u64 = 128; // 8 GB -> 1024×1024×1
+pub const DEFAULT_MAX_PIXELS: u64 = 1024 * 1024; // Used when GPU memory can't be detected
+pub const VRAM_SAMPLE_INTERVAL_MS: u64 = 500; // nvidia-smi memory.used sampling while a task runs
+pub const MIN_GPU_MEMORY_MB: u64 = 4 * 1024; // Nodes with less GPU memory refuse to start (0 disables)
+// Default width/height for tasks that omit them, by GPU memory tier
+pub const DEFAULT_IMAGE_SIZE_TIERS: &[(u64, u32)] = &[(8 * 1024, 512), (16 * 1024, 768)]; // (below MB, size)
+pub const LARGE_GPU_IMAGE_SIZE: u32 = 1024; // GPUs with at least 16 GB
+pub const SD_BUSY_RETRY_DELAY_SECONDS: u64 = 10; // Redelivery delay when the SD server is busy
+pub const FOREIGN_TASK_REDELIVERY_DELAY_SECONDS: u64 = 5; // Redelivery delay for other nodes' tasks skipped by --once
+pub const IDLE_CHECK_INTERVAL_SECONDS: u64 = 10; // How often idle exit checks for recent tasks
+pub const IDLE_EXIT_CODE: i32 = 3; // Process exit code after an idle exit
+pub const SHUTDOWN_GRACE_SECONDS: u64 = 20; // In-flight task drain on SIGTERM, below the usual 30s orchestrator kill timeout
+pub const SHUTDOWN_ABORT_TIMEOUT_SECONDS: u64 = 5; // Publishing interrupted results after the grace period
+// Startup SD probes (capabilities report, script listing, loaded model)
+pub const SD_PROBE_TIMEOUT_SECONDS: u64 = 10; // Per probe request
+pub const SD_PROBE_BUDGET_SECONDS: u64 = 20; // All capability probes together
+pub const SD_PROBE_CONCURRENCY: usize = 4; // Capability probes in flight at once
+pub const SD_KEEP_WARM_INTERVAL_SECONDS: u64 = 300; // Idle SD ping interval when keep-warm is enabled
+pub const RESULT_SUBJECT_TEMPLATE: &str = "results.{task_id}"; // Default result subject for every status
+pub const LIFECYCLE_PUBLISH_TIMEOUT_SECONDS: u64 = 5; // Give up publishing a lifecycle event after this long
+pub const TASK_LOG_CAPTURE_BYTES: usize = 16 * 1024; // Log lines attached to a failed result
+pub const MAX_ERROR_STACK_BYTES: usize = 8 * 1024; // error_stack longer than this is truncated
+pub const THROTTLE_RECHECK_SECONDS: u64 = 5; // Re-check GPU throttling while holding back a task
+pub const PUBLISH_ATTEMPTS: u32 = 3; // Result publish attempts before persisting to disk
+pub const RESULT_WEBHOOK_ATTEMPTS: u32 = 3; // Result webhook POST attempts before giving up
+pub const RESULT_WEBHOOK_TIMEOUT_SECONDS: u64 = 10;
+pub const PUBLISH_RETRY_DELAY_MS: u64 = 500; // Initial backoff between publish attempts
+pub const UPLOAD_CONCURRENCY: usize = 4; // Images uploaded concurrently per task
+pub const UPLOAD_CHUNK_SIZE: usize = 8 * 1024 * 1024; // Part size for multipart uploads
+pub const UPLOAD_MIN_CHUNK_SIZE: usize = 5 * 1024 * 1024; // S3 minimum size of every part but the last
+pub const IMAGE_CACHE_MAX_BYTES: u64 = 2 * 1024 * 1024 * 1024; // Size cap of the local image cache
+pub const IMAGE_CACHE_WRITE_CONCURRENCY: usize = 4; // Images written to the local cache concurrently per task
+pub const MAX_PROMPT_LENGTH: usize = 8000; // Max characters accepted for prompt / negative_prompt
+pub const DEFAULT_NATS_MAX_PAYLOAD: usize = 1024 * 1024; // Used when the server doesn't report max_payload
+pub const MAX_ANIMATION_FRAMES: u32 = 24; // Max `frames` accepted per animated task
+pub const DEFAULT_FRAME_DELAY_MS: u32 = 100; // Delay between animation frames
+pub const MAX_LORA_WEIGHT: f64 = 2.0; // LoRA weights accepted in -MAX..=MAX
 
 // 令牌相关配置
 pub const TOKEN_REFRESH_THRESHOLD_SECONDS: u64 = 300; // Refresh token when less than 5 minutes remaining
@@ -29,6 +103,14 @@ pub const API_NODES_REFRESH: &str = "/api/nodes/device/refresh";
 // 配置相关
 pub const CONFIG_DIR: &str = "zkom";
 pub const CONFIG_FILE: &str = "config.json";
+pub const CONFIG_CORRUPT_SUFFIX: &str = ".corrupt"; // Appended to a config file that failed to parse
+pub const CONFIG_FALLBACK_DIR_ENV: &str = "ZKOM_CONFIG_FALLBACK_DIR"; // Used when the default config dir is read-only
+pub const TOKEN_SAVE_ATTEMPTS: u32 = 3; // Attempts to save and verify refreshed tokens before going ephemeral
+pub const TOKEN_SAVE_RETRY_DELAY_MS: u64 = 200;
+pub const PENDING_RESULTS_DIR: &str = "pending_results";
+pub const TASK_ATTEMPTS_FILE: &str = "task_attempts.json"; // Per-task attempt counts kept across restarts
+pub const TASK_ATTEMPTS_TTL_SECONDS: i64 = 24 * 3600; // Attempt records older than this are dropped
+pub const TASK_ATTEMPTS_MAX_ENTRIES: usize = 1000; // Oldest records are dropped beyond this
 
 // 设备指纹相关
 pub const FINGERPRINT_SEPARATOR: &str = ";";
@@ -53,6 +135,8 @@ pub const MSG_DEVICE_VERIFY_SUCCESS: &str = "Device verification successful!";
 pub const MSG_DEVICE_VERIFY_TIMEOUT: &str = "Device verification timeout, please restart the program";
 pub const MSG_NODE_STARTING: &str = "Node starting...";
 pub const MSG_NODE_ID: &str = "Node ID: {}";
+pub const MSG_CLIENT_OUTDATED: &str = "A newer client version is available, please upgrade this node";
+pub const MSG_CLIENT_UNSUPPORTED: &str = "This client version is no longer supported by the backend, please upgrade this node";
 
 // 验证提示
 pub const MSG_VERIFY_INSTRUCTIONS: &str = "Please visit the following URL to complete device verification:";
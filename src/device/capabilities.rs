@@ -0,0 +1,145 @@
+use super::hardware;
+use crate::consts::*;
+use crate::stable_diffusion::StableDiffusion;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::time::Instant;
+
+/// 启动阶段SD探测的限制
+#[derive(Debug, Clone)]
+pub struct ProbeConfig {
+    /// Time limit for a single probe request
+    pub timeout: Duration,
+    /// Time limit for all probes together; probes still running when it ends are reported missing
+    pub budget: Duration,
+    /// Probes in flight at once
+    pub concurrency: usize,
+}
+
+/// 并发执行探测，每项受单项超时约束，全部受总时限约束
+struct Prober {
+    config: ProbeConfig,
+    permits: Semaphore,
+    deadline: Instant,
+}
+
+impl Prober {
+    fn new(config: ProbeConfig) -> Self {
+        Self {
+            permits: Semaphore::new(config.concurrency.max(1)),
+            deadline: Instant::now() + config.budget,
+            config,
+        }
+    }
+
+    async fn run<T>(&self, probe: impl Future<Output = Result<T>>) -> Result<T> {
+        let limited = async {
+            let _permit = self.permits.acquire().await?;
+            tokio::time::timeout(self.config.timeout, probe)
+                .await
+                .map_err(|_| anyhow::anyhow!("timed out after {}s", self.config.timeout.as_secs_f64()))?
+        };
+        tokio::time::timeout_at(self.deadline, limited)
+            .await
+            .map_err(|_| anyhow::anyhow!("probe budget of {}s exhausted", self.config.budget.as_secs_f64()))?
+    }
+}
+
+/// 节点能力，随设备初始化上报，供后端按能力分派任务
+///
+/// 查询失败的字段为 `null`，并列在 `missing` 中，后端据此区分“不支持”与“未知”。
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Capabilities {
+    pub client_version: String,
+    /// Largest `width × height` accepted per image
+    pub max_pixels: u64,
+    pub max_concurrent_tasks: usize,
+    pub gpu_count: Option<usize>,
+    /// Sampler names accepted as `sampler_name`
+    pub samplers: Option<Vec<String>>,
+    /// Checkpoint titles available on the SD server
+    pub models: Option<Vec<String>>,
+    /// Enabled SD extensions
+    pub extensions: Option<Vec<String>>,
+    /// txt2img scripts, including always-on extension scripts
+    pub scripts: Option<Vec<String>>,
+    /// Checkpoint loaded on the SD server at startup
+    pub sd_model: Option<String>,
+    /// Fields that could not be queried
+    #[serde(default)]
+    pub missing: Vec<String>,
+}
+
+impl Capabilities {
+    /// 汇总SD服务器与硬件的能力信息
+    ///
+    /// 各项查询按 `probes` 的并发数同时执行、互不影响，超时或超出总时限的查询计为缺失；
+    /// SD不可达（或客户端未能创建）时仍返回已知部分。
+    pub async fn collect(
+        sd: Option<&StableDiffusion>,
+        probes: ProbeConfig,
+        max_pixels: u64,
+        max_concurrent_tasks: usize,
+    ) -> Self {
+        let mut capabilities = Self {
+            client_version: CLIENT_VERSION.to_string(),
+            max_pixels,
+            max_concurrent_tasks,
+            ..Self::default()
+        };
+
+        // nvidia-smi 是阻塞调用，放到阻塞线程池执行，不占用异步工作线程
+        let gpus = tokio::task::spawn_blocking(hardware::collect_gpu_metrics)
+            .await
+            .map_err(|e| anyhow::anyhow!(e))
+            .and_then(|metrics| metrics)
+            .map(|metrics| metrics.gpus.len())
+            .and_then(|count| match count {
+                0 => Err(anyhow::anyhow!("no per-GPU status reported")),
+                count => Ok(count),
+            });
+        capabilities.gpu_count = capabilities.known("gpu_count", gpus);
+
+        let Some(sd) = sd else {
+            capabilities.missing.extend(["samplers", "models", "extensions", "scripts", "sd_model"].map(String::from));
+            return capabilities;
+        };
+        let started = std::time::Instant::now();
+        let prober = Prober::new(probes);
+        let (samplers, models, extensions, scripts, sd_model) = tokio::join!(
+            prober.run(sd.list_samplers()),
+            prober.run(sd.list_models()),
+            prober.run(sd.list_extensions()),
+            prober.run(sd.list_scripts()),
+            prober.run(sd.current_model()),
+        );
+        log::debug!("SD capability probes finished in {:.2}s", started.elapsed().as_secs_f64());
+
+        capabilities.samplers =
+            capabilities.known("samplers", samplers.map(|samplers| samplers.into_iter().map(|s| s.name).collect()));
+        capabilities.models =
+            capabilities.known("models", models.map(|models| models.into_iter().map(|m| m.title).collect()));
+        capabilities.extensions = capabilities.known(
+            "extensions",
+            extensions.map(|extensions| extensions.into_iter().filter(|e| e.enabled).map(|e| e.name).collect()),
+        );
+        capabilities.scripts = capabilities.known("scripts", scripts.map(|scripts| scripts.txt2img));
+        capabilities.sd_model = capabilities.known("sd_model", sd_model).flatten();
+        capabilities
+    }
+
+    /// 查询成功时返回结果，失败时记入 `missing`
+    fn known<T>(&mut self, field: &str, result: Result<T>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(e) => {
+                log::warn!("Capability '{}' unavailable, reporting it as missing: {:#}", field, e);
+                self.missing.push(field.to_string());
+                None
+            }
+        }
+    }
+}
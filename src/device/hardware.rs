@@ -5,7 +5,7 @@ use serde::{Deserialize, Serialize};
 use std::process::Command;
 use sysinfo::System;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HardwareInfo {
     pub cpu_serial: String,
     pub gpu_uuid: Option<String>,
@@ -16,14 +16,62 @@ pub struct HardwareInfo {
     pub driver_version: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GpuMetrics {
+    /// GPU utilization (%); `None` when nvidia-smi output couldn't be parsed
+    pub utilization: Option<u8>,
+    /// GPU memory used (MB); `None` when nvidia-smi output couldn't be parsed
+    pub memory_used: Option<u64>,
+    /// GPU temperature (°C); `None` when nvidia-smi output couldn't be parsed
+    pub temperature: Option<u8>,
+    /// Uncorrected ECC errors across all GPUs, `None` when ECC is unsupported
+    pub ecc_errors: Option<u64>,
+    /// Per-GPU temperature and throttle state; empty when it couldn't be queried
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub gpus: Vec<GpuStatus>,
+    pub timestamp: String,
+}
+
+/// 一次GPU负载读数（多GPU时取第一块），用于心跳间隔内的后台采样
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadSample {
     pub utilization: u8,
     pub memory_used: u64,
     pub temperature: u8,
-    pub timestamp: String,
 }
 
+/// 单块GPU的温度与降频状态
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GpuStatus {
+    pub index: u32,
+    pub temperature: Option<u8>,
+    /// Active `clocks_throttle_reasons` bitmask, `None` when the card doesn't report it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub throttle_reasons: Option<u64>,
+    /// Clocks reduced for thermal or hardware reasons
+    pub throttling: bool,
+}
+
+impl GpuStatus {
+    /// 降频原因的可读名称
+    pub fn throttle_reason_names(&self) -> Vec<&'static str> {
+        let reasons = self.throttle_reasons.unwrap_or(0);
+        THROTTLE_REASON_NAMES
+            .iter()
+            .filter(|(bit, _)| reasons & bit != 0)
+            .map(|(_, name)| *name)
+            .collect()
+    }
+}
+
+/// `clocks_throttle_reasons.active` 中视为降频的位：硬件降速、软件/硬件温控降速、电源制动
+const THROTTLE_REASON_NAMES: &[(u64, &str)] = &[
+    (0x08, "hw_slowdown"),
+    (0x20, "sw_thermal_slowdown"),
+    (0x40, "hw_thermal_slowdown"),
+    (0x80, "hw_power_brake_slowdown"),
+];
+
 pub struct HardwareCollector {
     sys: System,
 }
@@ -35,36 +83,27 @@ impl HardwareCollector {
         }
     }
 
+    /// 收集硬件信息，各项外部命令（nvidia-smi、nvcc）并行执行
     pub fn collect_info(&self) -> Result<HardwareInfo> {
-        Ok(HardwareInfo {
-            cpu_serial: self.get_cpu_serial()?,
-            gpu_uuid: self.get_gpu_uuid(),
-            system_fingerprint: self.generate_system_fingerprint()?,
-            gpu_model: self.get_gpu_model(),
-            gpu_memory: self.get_gpu_memory(),
-            cuda_version: self.get_cuda_version(),
-            driver_version: self.get_driver_version(),
-        })
-    }
+        std::thread::scope(|s| {
+            let gpu_uuid = s.spawn(|| self.get_gpu_uuid());
+            let gpu_model = s.spawn(|| self.get_gpu_model());
+            let gpu_memory = s.spawn(|| self.get_gpu_memory());
+            let cuda_version = s.spawn(|| self.get_cuda_version());
+            let driver_version = s.spawn(|| self.get_driver_version());
+            let cpu_serial = self.get_cpu_serial()?;
+            let system_fingerprint = self.generate_system_fingerprint()?;
 
-    pub fn collect_gpu_metrics(&self) -> Result<GpuMetrics> {
-        // 获取GPU利用率
-        let utilization = self.get_gpu_utilization()?;
-        
-        // 获取显存使用量
-        let memory_used = self.get_gpu_memory_used()?;
-        
-        // 获取GPU温度
-        let temperature = self.get_gpu_temperature()?;
-        
-        // 获取当前时间戳（ISO 8601格式）
-        let timestamp = Utc::now().to_rfc3339();
-        
-        Ok(GpuMetrics {
-            utilization,
-            memory_used,
-            temperature,
-            timestamp,
+            let join = |name: &str| anyhow::anyhow!("Hardware query for {} panicked", name);
+            Ok(HardwareInfo {
+                cpu_serial,
+                gpu_uuid: gpu_uuid.join().map_err(|_| join("GPU UUID"))?,
+                system_fingerprint,
+                gpu_model: gpu_model.join().map_err(|_| join("GPU model"))?,
+                gpu_memory: gpu_memory.join().map_err(|_| join("GPU memory"))?,
+                cuda_version: cuda_version.join().map_err(|_| join("CUDA version"))?,
+                driver_version: driver_version.join().map_err(|_| join("driver version"))?,
+            })
         })
     }
 
@@ -72,7 +111,7 @@ impl HardwareCollector {
         // 在 Linux 系统上获取 CPU 序列号
         let output = Command::new("cat").arg("/proc/cpuinfo").output()?;
 
-        let cpu_info = String::from_utf8(output.stdout)?;
+        let cpu_info = String::from_utf8_lossy(&output.stdout);
         let serial = cpu_info
             .lines()
             .find(|line| line.starts_with("Serial"))
@@ -85,107 +124,27 @@ impl HardwareCollector {
 
     fn get_gpu_uuid(&self) -> Option<String> {
         // 尝试获取 NVIDIA GPU UUID
-        if let Ok(output) = Command::new("nvidia-smi")
-            .arg("--query-gpu=gpu_uuid")
-            .arg("--format=csv,noheader")
-            .output()
-        {
-            if let Ok(uuid) = String::from_utf8(output.stdout) {
-                return Some(uuid.trim().to_string());
-            }
-        }
-        None
-    }
-
-    fn get_gpu_model(&self) -> Option<String> {
-        if let Ok(output) = Command::new("nvidia-smi")
-            .arg("--query-gpu=gpu_name")
-            .arg("--format=csv,noheader")
-            .output()
-        {
-            if let Ok(model) = String::from_utf8(output.stdout) {
-                return Some(model.trim().to_string());
-            }
-        }
-        None
-    }
-
-    fn get_gpu_memory(&self) -> Option<u64> {
-        if let Ok(output) = Command::new("nvidia-smi")
-            .arg("--query-gpu=memory.total")
-            .arg("--format=csv,noheader")
-            .output()
-        {
-            if let Ok(memory) = String::from_utf8(output.stdout) {
-                if let Ok(memory_mb) = memory.trim().parse::<u64>() {
-                    return Some(memory_mb);
-                }
-            }
-        }
-        None
+        query_gpu("gpu_uuid", false).ok().and_then(|output| parse_text(&output))
+    }
+
+    pub fn get_gpu_model(&self) -> Option<String> {
+        query_gpu("gpu_name", false).ok().and_then(|output| parse_text(&output))
+    }
+
+    pub fn get_gpu_memory(&self) -> Option<u64> {
+        query_gpu("memory.total", true).ok().and_then(|output| parse_number(&output))
     }
 
     fn get_cuda_version(&self) -> Option<String> {
-        if let Ok(output) = Command::new("nvcc").arg("--version").output() {
-            if let Ok(version) = String::from_utf8(output.stdout) {
-                // Extract CUDA version from nvcc output
-                if let Some(line) = version.lines().find(|line| line.contains("release")) {
-                    if let Some(version) = line.split_whitespace().nth(5) {
-                        return Some(version.to_string());
-                    }
-                }
-            }
-        }
-        None
+        let output = Command::new("nvcc").arg("--version").output().ok()?;
+        let version = String::from_utf8_lossy(&output.stdout);
+        // Extract CUDA version from nvcc output
+        let line = version.lines().find(|line| line.contains("release"))?;
+        line.split_whitespace().nth(5).map(str::to_string)
     }
 
     fn get_driver_version(&self) -> Option<String> {
-        if let Ok(output) = Command::new("nvidia-smi")
-            .arg("--query-gpu=driver_version")
-            .arg("--format=csv,noheader")
-            .output()
-        {
-            if let Ok(version) = String::from_utf8(output.stdout) {
-                return Some(version.trim().to_string());
-            }
-        }
-        None
-    }
-
-    fn get_gpu_utilization(&self) -> Result<u8> {
-        let output = Command::new("nvidia-smi")
-            .args(&["--query-gpu=utilization.gpu", "--format=csv,noheader,nounits"])
-            .output()?;
-        
-        let utilization_str = String::from_utf8(output.stdout)?;
-        let utilization = utilization_str.trim().parse::<u8>()
-            .map_err(|_| anyhow::anyhow!("无法解析GPU利用率"))?;
-        
-        Ok(utilization)
-    }
-    
-    fn get_gpu_memory_used(&self) -> Result<u64> {
-        let output = Command::new("nvidia-smi")
-            .args(&["--query-gpu=memory.used", "--format=csv,noheader,nounits"])
-            .output()?;
-        
-        let memory_str = String::from_utf8(output.stdout)?;
-        let memory = memory_str.trim().parse::<u64>()
-            .map_err(|_| anyhow::anyhow!("无法解析显存使用量"))?;
-        
-        Ok(memory)
-    }
-    
-    fn get_gpu_temperature(&self) -> Result<u8> {
-        let output = Command::new("nvidia-smi")
-            .args(&["--query-gpu=temperature.gpu", "--format=csv,noheader,nounits"])
-            .output()?;
-        
-        let temperature_str = String::from_utf8(output.stdout)?;
-        let temperature = temperature_str.trim().parse::<u8>()
-            .map_err(|_| anyhow::anyhow!("无法解析GPU温度"))?;
-        
-        Ok(temperature)
+        query_gpu("driver_version", false).ok().and_then(|output| parse_text(&output))
     }
 
     fn generate_system_fingerprint(&self) -> Result<String> {
@@ -220,3 +179,232 @@ impl HardwareCollector {
         Ok(fingerprint)
     }
 }
+
+/// 查询当前显存使用量（MB），多GPU时取第一块；查询或解析失败时为空
+pub fn gpu_memory_used() -> Option<u64> {
+    query_gpu("memory.used", true).ok().and_then(|output| parse_number(&output))
+}
+
+/// 以一次 nvidia-smi 调用收集利用率、显存、温度、ECC错误与每块GPU的降频状态
+///
+/// 阻塞调用，异步上下文中应放到阻塞线程池执行。
+pub fn collect_gpu_metrics() -> Result<GpuMetrics> {
+    let output = query_gpu(GPU_METRICS_QUERY, true)?;
+    Ok(parse_gpu_metrics(&output, Utc::now().to_rfc3339()))
+}
+
+/// 以一次 nvidia-smi 调用读取利用率、显存使用量与温度；查询或解析失败时返回错误
+pub fn sample_gpu_load() -> Result<LoadSample> {
+    let output = query_gpu("utilization.gpu,memory.used,temperature.gpu", true)?;
+    parse_load_sample(&output).ok_or_else(|| anyhow::anyhow!("无法解析GPU负载: {:?}", output.trim()))
+}
+
+/// 解析第一行 `utilization, memory_used, temperature` 输出
+fn parse_load_sample(output: &str) -> Option<LoadSample> {
+    let mut fields = value_lines(output).next()?.split(',').map(str::trim);
+    Some(LoadSample {
+        utilization: fields.next()?.parse().ok()?,
+        memory_used: fields.next()?.parse().ok()?,
+        temperature: fields.next()?.parse().ok()?,
+    })
+}
+
+/// 心跳指标的查询字段，每块GPU输出一行，字段顺序与 `parse_gpu_metrics` 一致
+const GPU_METRICS_QUERY: &str =
+    "index,utilization.gpu,memory.used,temperature.gpu,ecc.errors.uncorrected.aggregate.total,clocks_throttle_reasons.active";
+
+/// 解析 `GPU_METRICS_QUERY` 的输出
+///
+/// 利用率、显存与温度取第一块GPU，无法解析时记录警告并为空；ECC错误为各卡之和，均不支持时为空；
+/// 不支持降频原因的卡视为未降频；无法解析索引的行（警告等）跳过。
+fn parse_gpu_metrics(output: &str, timestamp: String) -> GpuMetrics {
+    let rows: Vec<Vec<&str>> = output
+        .lines()
+        .map(|line| line.trim_start_matches('\u{feff}').split(',').map(str::trim).collect::<Vec<_>>())
+        .filter(|fields| fields[0].parse::<u32>().is_ok())
+        .collect();
+    // 没有有效行时以原始输出记录警告
+    let first = |column: usize| rows.first().and_then(|fields| fields.get(column)).copied().unwrap_or(output);
+    let ecc_counts: Vec<u64> = rows
+        .iter()
+        .filter_map(|fields| fields.get(4).and_then(|field| parse_number(field)))
+        .collect();
+    GpuMetrics {
+        utilization: parse_reading(first(1), "GPU利用率"),
+        memory_used: parse_reading(first(2), "显存使用量"),
+        temperature: parse_reading(first(3), "GPU温度"),
+        ecc_errors: (!ecc_counts.is_empty()).then(|| ecc_counts.iter().sum()),
+        gpus: rows.iter().filter_map(|fields| parse_gpu_status(fields)).collect(),
+        timestamp,
+    }
+}
+
+/// 由一行中的 `index`、`temperature`、`throttle_reasons` 字段得到单块GPU状态
+fn parse_gpu_status(fields: &[&str]) -> Option<GpuStatus> {
+    let index = fields.first()?.parse().ok()?;
+    let temperature = fields.get(3).and_then(|field| field.parse().ok());
+    let throttle_reasons = fields
+        .get(5)
+        .and_then(|field| u64::from_str_radix(field.trim_start_matches("0x"), 16).ok());
+    let throttling = throttle_reasons
+        .is_some_and(|reasons| THROTTLE_REASON_NAMES.iter().any(|(bit, _)| reasons & bit != 0));
+    Some(GpuStatus { index, temperature, throttle_reasons, throttling })
+}
+
+/// 执行 nvidia-smi 查询，输出按有损UTF-8解码（部分驱动会输出非法字节）
+fn query_gpu(field: &str, no_units: bool) -> Result<String> {
+    let format = if no_units { "--format=csv,noheader,nounits" } else { "--format=csv,noheader" };
+    let output = Command::new("nvidia-smi")
+        .arg(format!("--query-gpu={}", field))
+        .arg(format)
+        .output()?;
+    Ok(decode_output(&output.stdout))
+}
+
+/// 按有损UTF-8解码命令输出，非法字节替换为 U+FFFD
+fn decode_output(stdout: &[u8]) -> String {
+    String::from_utf8_lossy(stdout).into_owned()
+}
+
+/// 去除BOM与空白，跳过空行、警告行和 `[N/A]`/`[Not Supported]` 之类的占位值
+fn value_lines(output: &str) -> impl Iterator<Item = &str> {
+    output
+        .lines()
+        .map(|line| line.trim_start_matches('\u{feff}').trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('[') && !line.to_ascii_lowercase().starts_with("warning"))
+}
+
+/// 取第一个有效行的文本值
+fn parse_text(output: &str) -> Option<String> {
+    value_lines(output).next().map(str::to_string)
+}
+
+/// 解析单项读数；无法解析时记录警告并返回空，而不是以 0 冒充真实读数
+fn parse_reading<T: std::str::FromStr>(output: &str, name: &str) -> Option<T> {
+    let value = parse_number(output);
+    if value.is_none() {
+        log::warn!("无法解析{}: {:?}", name, output.trim());
+    }
+    value
+}
+
+/// 取第一个以数字开头的行，解析其数字部分（忽略单位等后缀）
+fn parse_number<T: std::str::FromStr>(output: &str) -> Option<T> {
+    value_lines(output).find_map(|line| {
+        let end = line
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(line.len());
+        line[..end].parse().ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_number_skips_noise_and_units() {
+        assert_eq!(parse_number::<u8>("45\n"), Some(45));
+        assert_eq!(parse_number::<u8>("\u{feff} 45 %\r\n"), Some(45));
+        assert_eq!(parse_number::<u64>("WARNING: infoROM is corrupted at gpu 0000:01:00.0\n8123 MiB\n"), Some(8123));
+        assert_eq!(parse_number::<u64>("\n[N/A]\n\n1024\n"), Some(1024));
+        assert_eq!(parse_number::<u8>("12.5\n"), None);
+    }
+
+    #[test]
+    fn parse_number_rejects_unparseable_output() {
+        assert_eq!(parse_number::<u8>(""), None);
+        assert_eq!(parse_number::<u8>("[Not Supported]\n"), None);
+        assert_eq!(parse_number::<u8>("NVIDIA-SMI has failed because it couldn't communicate with the driver\n"), None);
+        // 超出类型范围的数值不截断
+        assert_eq!(parse_number::<u8>("300\n"), None);
+    }
+
+    #[test]
+    fn parse_number_handles_invalid_utf8() {
+        assert_eq!(parse_number::<u8>(&decode_output(b"\xff\xfe\n67\n")), Some(67));
+        assert_eq!(parse_number::<u8>(&decode_output(b"\xef\xbb\xbf67\n")), Some(67));
+        assert_eq!(parse_number::<u8>(&decode_output(b"\xff67\n")), None);
+    }
+
+    #[test]
+    fn parse_reading_distinguishes_failed_read_from_zero() {
+        assert_eq!(parse_reading::<u8>("0\n", "GPU利用率"), Some(0));
+        assert_eq!(parse_reading::<u8>("[N/A]\n", "GPU利用率"), None);
+        assert_eq!(parse_reading::<u64>(&decode_output(b"\x80\x81"), "显存使用量"), None);
+    }
+
+    #[test]
+    fn parse_text_takes_first_value_line() {
+        assert_eq!(parse_text("\u{feff}NVIDIA GeForce RTX 4090\n").as_deref(), Some("NVIDIA GeForce RTX 4090"));
+        assert_eq!(parse_text("Warning: persistence mode is disabled\nTesla T4\n").as_deref(), Some("Tesla T4"));
+        assert_eq!(parse_text("[N/A]\n  \n"), None);
+    }
+
+    #[test]
+    fn parse_load_sample_requires_all_fields() {
+        assert_eq!(
+            parse_load_sample("87, 10240, 71\n3, 512, 40\n"),
+            Some(LoadSample { utilization: 87, memory_used: 10240, temperature: 71 })
+        );
+        assert_eq!(
+            parse_load_sample("\u{feff}WARNING: something\n 5 ,100, 35 \n"),
+            Some(LoadSample { utilization: 5, memory_used: 100, temperature: 35 })
+        );
+        assert_eq!(parse_load_sample("87, 10240\n"), None);
+        assert_eq!(parse_load_sample("87, [N/A], 71\n"), None);
+        assert_eq!(parse_load_sample(&decode_output(b"87, \xff, 71\n")), None);
+        assert_eq!(parse_load_sample(""), None);
+    }
+
+    #[test]
+    fn parse_gpu_metrics_reads_every_gpu() {
+        let output = "\u{feff}0, 87, 10240, 45, 0, 0x0000000000000001\n\
+                      1, 12, 512, 83, 2, 0x0000000000000040\n\
+                      2, 3, 256, 60, [N/A], [N/A]\n\
+                      3, 0, 128, [N/A], 1, 0x0000000000000004\n";
+        let metrics = parse_gpu_metrics(output, "now".to_string());
+        assert_eq!(metrics.utilization, Some(87));
+        assert_eq!(metrics.memory_used, Some(10240));
+        assert_eq!(metrics.temperature, Some(45));
+        // 不支持ECC的卡不计入
+        assert_eq!(metrics.ecc_errors, Some(3));
+        let status = |index, temperature, throttle_reasons, throttling| GpuStatus { index, temperature, throttle_reasons, throttling };
+        assert_eq!(
+            metrics.gpus,
+            vec![
+                // 空闲（0x1）与功耗上限（0x4）不算降频
+                status(0, Some(45), Some(0x01), false),
+                status(1, Some(83), Some(0x40), true),
+                status(2, Some(60), None, false),
+                status(3, None, Some(0x04), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_gpu_metrics_keeps_unreadable_fields_empty() {
+        let metrics = parse_gpu_metrics("WARNING: infoROM is corrupted at gpu 0000:01:00.0\n\n0, [N/A], 100, 35, [N/A], [N/A]\n", String::new());
+        assert_eq!(metrics.utilization, None);
+        assert_eq!(metrics.memory_used, Some(100));
+        assert_eq!(metrics.temperature, Some(35));
+        assert_eq!(metrics.ecc_errors, None);
+        assert_eq!(metrics.gpus.len(), 1);
+
+        let metrics = parse_gpu_metrics("NVIDIA-SMI has failed because it couldn't communicate with the driver\n", String::new());
+        assert_eq!((metrics.utilization, metrics.memory_used, metrics.temperature), (None, None, None));
+        assert!(metrics.gpus.is_empty());
+    }
+
+    #[test]
+    fn parse_gpu_metrics_flags_each_throttle_reason() {
+        for (bit, name) in THROTTLE_REASON_NAMES {
+            let metrics = parse_gpu_metrics(&format!("0, 50, 100, 70, 0, {:#018x}\n", bit | 0x01), String::new());
+            assert!(metrics.gpus[0].throttling, "{}", name);
+            assert_eq!(metrics.gpus[0].throttle_reason_names(), vec![*name]);
+        }
+
+        let metrics = parse_gpu_metrics("0, 50, 100, 90, 0, 0x00000000000000A8\n", String::new());
+        assert_eq!(metrics.gpus[0].throttle_reason_names(), vec!["hw_slowdown", "sw_thermal_slowdown", "hw_power_brake_slowdown"]);
+    }
+}
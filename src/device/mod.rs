@@ -2,22 +2,27 @@ use crate::consts::*;
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
 use sha2::{Sha256, Digest};
 use base64::{Engine as _, engine::general_purpose};
 
-pub use hardware::{HardwareCollector, HardwareInfo};
+pub use capabilities::{Capabilities, ProbeConfig};
+pub use hardware::{GpuMetrics, GpuStatus, HardwareCollector, HardwareInfo, LoadSample};
+pub use network::NetworkInfo;
+pub mod capabilities;
 pub mod hardware;
+pub mod network;
+pub mod registration;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GpuInfo {
     pub model: String,
     pub memory: u64,
     pub cuda_version: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DeviceInfo {
     pub cpu_serial: String,
     pub gpu_uuid: Option<String>,
@@ -31,6 +36,11 @@ pub struct DeviceInitRequest {
     pub gpu_info: GpuInfo,
     pub hardware_info: HardwareInfo,
     pub installation_hash: String,
+    pub client_version: String,
+    #[serde(flatten)]
+    pub network: NetworkInfo,
+    /// Samplers, models, extensions, scripts and limits, for capability-aware task routing
+    pub capabilities: Capabilities,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -48,11 +58,21 @@ pub struct DeviceVerifyResponse {
     pub refresh_token: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DeviceMetrics {
-    pub gpu_utilization: u8,      // GPU利用率（%）
-    pub gpu_memory_used: u64,     // 显存使用量（MB）
-    pub gpu_temperature: u8,      // GPU温度
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gpu_utilization: Option<u8>,  // GPU利用率（%），读取失败且无可沿用的值时省略
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gpu_memory_used: Option<u64>, // 显存使用量（MB），同上
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gpu_temperature: Option<u8>,  // GPU温度，同上
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ecc_errors: Option<u64>,  // 未纠正的ECC错误数（不支持ECC时为空）
+    pub hardware_healthy: bool,   // 无未纠正ECC错误
+    pub max_concurrent_tasks: u32, // 节点允许的最大并发任务数
+    pub tasks_in_flight: u32,     // 当前正在处理的任务数
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub gpus: Vec<GpuStatus>,     // 每块GPU的温度与降频状态
     pub timestamp: String,        // ISO 8601格式的时间戳
 }
 
@@ -60,12 +80,24 @@ pub struct DeviceMetrics {
 pub struct DeviceHeartbeatRequest {
     pub node_id: String,
     pub metrics: DeviceMetrics,
+    pub client_version: String,
+    #[serde(flatten)]
+    pub network: NetworkInfo,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DeviceHeartbeatResponse {
     pub status: String,
     pub message: String,
+    /// Newest client version published by the backend
+    #[serde(default)]
+    pub latest_version: Option<String>,
+    /// Oldest client version the backend still accepts
+    #[serde(default)]
+    pub min_supported_version: Option<String>,
+    /// Interval the backend wants before the next heartbeat
+    #[serde(default)]
+    pub next_interval_secs: Option<u64>,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -84,6 +116,8 @@ pub enum DeviceError {
     HeartbeatError(String),
     #[error("令牌刷新失败: {0}")]
     RefreshError(String),
+    #[error("刷新令牌已被拒绝，请重新注册节点")]
+    RefreshTokenRejected,
     #[error("令牌解析失败: {0}")]
     TokenParseError(String),
 }
@@ -91,11 +125,15 @@ pub enum DeviceError {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DeviceRefreshResponse {
     pub access_token: String,
+    /// New refresh token, when the backend rotates it
+    #[serde(default)]
+    pub refresh_token: Option<String>,
 }
 
 pub struct DeviceManager {
     client: reqwest::Client,
     base_url: String,
+    client_created_at: Instant,
 }
 
 impl DeviceManager {
@@ -103,14 +141,28 @@ impl DeviceManager {
         Self {
             client: reqwest::Client::new(),
             base_url,
+            client_created_at: Instant::now(),
         }
     }
 
+    /// 重建HTTP客户端，丢弃连接池中可能已失效的长连接并重新解析DNS
+    pub fn rebuild_client(&mut self) {
+        self.client = reqwest::Client::new();
+        self.client_created_at = Instant::now();
+    }
+
+    /// 当前HTTP客户端的存活时间
+    pub fn client_age(&self) -> Duration {
+        self.client_created_at.elapsed()
+    }
+
     pub async fn init_device(
         &self,
         device_info: DeviceInfo,
         gpu_info: GpuInfo,
         hardware_info: HardwareInfo,
+        network: NetworkInfo,
+        capabilities: Capabilities,
     ) -> Result<DeviceInitResponse, DeviceError> {
         let fingerprint = self.generate_device_fingerprint(&device_info);
         let request = DeviceInitRequest {
@@ -118,6 +170,9 @@ impl DeviceManager {
             gpu_info,
             hardware_info,
             installation_hash: device_info.installation_hash,
+            client_version: CLIENT_VERSION.to_string(),
+            network,
+            capabilities,
         };
 
         log::debug!(
@@ -178,7 +233,8 @@ impl DeviceManager {
         }
     }
 
-    fn generate_device_fingerprint(&self, info: &DeviceInfo) -> String {
+    /// 由硬件标识与安装哈希生成设备指纹
+    pub fn generate_device_fingerprint(&self, info: &DeviceInfo) -> String {
         // Create a combined string from hardware info and gpu info
         let combined = format!(
             "{}:{}:{}:{}",
@@ -197,15 +253,16 @@ impl DeviceManager {
         format!("{:x}", result)
     }
 
-    pub fn generate_installation_hash(&self) -> String {
-        // Dummy hash for development
-        "dummy_installation_hash_2024".to_string()
+    /// 由持久化的安装ID派生安装哈希：同一安装保持不变，不同机器各不相同
+    pub fn generate_installation_hash(&self, installation_id: &str) -> String {
+        format!("{:x}", Sha256::digest(installation_id.as_bytes()))
     }
 
     pub async fn send_heartbeat(
         &self,
         node_id: &str,
         metrics: DeviceMetrics,
+        network: NetworkInfo,
         access_token: &str,
     ) -> Result<DeviceHeartbeatResponse, DeviceError> {
         log::debug!(
@@ -217,6 +274,8 @@ impl DeviceManager {
         let request = DeviceHeartbeatRequest {
             node_id: node_id.to_string(),
             metrics,
+            client_version: CLIENT_VERSION.to_string(),
+            network,
         };
 
         let response = self
@@ -238,7 +297,7 @@ impl DeviceManager {
         response
             .json()
             .await
-            .map_err(|e| DeviceError::HeartbeatError(format!("解析响应失败: {}", e.to_string())))
+            .map_err(|e| DeviceError::HeartbeatError(format!("解析响应失败: {}", e)))
     }
 
     pub async fn refresh_token(
@@ -255,10 +314,14 @@ impl DeviceManager {
             .await
             .map_err(|e| DeviceError::NetworkError(e.to_string()))?;
 
-        if !response.status().is_success() {
+        let status = response.status();
+        if status == reqwest::StatusCode::UNAUTHORIZED || status == reqwest::StatusCode::FORBIDDEN {
+            return Err(DeviceError::RefreshTokenRejected);
+        }
+        if !status.is_success() {
             return Err(DeviceError::RefreshError(format!(
                 "令牌刷新失败: {}",
-                response.status()
+                status
             )));
         }
 
@@ -282,7 +345,7 @@ impl DeviceManager {
         // 如果过期时间小于当前时间加上阈值，则应该刷新
         if expiry <= now + threshold_seconds {
             log::debug!("Token will expire soon (in {} seconds), should refresh", 
-                        if expiry > now { expiry - now } else { 0 });
+                        expiry.saturating_sub(now));
             Ok(true)
         } else {
             log::debug!("Token still valid for {} seconds", expiry - now);
@@ -291,7 +354,7 @@ impl DeviceManager {
     }
     
     // 从JWT令牌中提取过期时间
-    fn get_token_expiry(&self, token: &str) -> Result<u64, DeviceError> {
+    pub fn get_token_expiry(&self, token: &str) -> Result<u64, DeviceError> {
         // JWT格式: header.payload.signature
         let parts: Vec<&str> = token.split('.').collect();
         if parts.len() != 3 {
@@ -313,3 +376,76 @@ impl DeviceManager {
         Ok(exp)
     }
 }
+
+/// 配置中记录的设备指纹与本机指纹的核对结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FingerprintCheck {
+    /// Registered on this machine
+    Match,
+    /// Registered by a version that didn't record the fingerprint
+    Unrecorded,
+    /// Registered on another machine (copied config, cloned VM or image)
+    Mismatch,
+}
+
+/// 核对注册时记录的设备指纹 `registered` 与本机当前指纹 `current`
+pub fn compare_fingerprint(registered: Option<&str>, current: &str) -> FingerprintCheck {
+    match registered {
+        Some(registered) if registered == current => FingerprintCheck::Match,
+        Some(_) => FingerprintCheck::Mismatch,
+        None => FingerprintCheck::Unrecorded,
+    }
+}
+
+/// 比较点分版本号（如 "0.2.10"），`version` 早于 `other` 时返回 true
+pub fn is_version_older(version: &str, other: &str) -> bool {
+    let parse = |v: &str| -> Vec<u64> {
+        v.trim_start_matches('v')
+            .split(['.', '-', '+'])
+            .map_while(|part| part.parse().ok())
+            .collect()
+    };
+    parse(version) < parse(other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device_info(cpu_serial: &str, gpu_uuid: Option<&str>, installation_id: &str) -> DeviceInfo {
+        let device_manager = DeviceManager::new(String::new());
+        DeviceInfo {
+            cpu_serial: cpu_serial.to_string(),
+            gpu_uuid: gpu_uuid.map(String::from),
+            system_fingerprint: "linux-x86_64".to_string(),
+            installation_hash: device_manager.generate_installation_hash(installation_id),
+        }
+    }
+
+    #[test]
+    fn fingerprint_matches_on_the_same_machine() {
+        let device_manager = DeviceManager::new(String::new());
+        let info = device_info("cpu-1", Some("GPU-aaaa"), "install-1");
+        let registered = device_manager.generate_device_fingerprint(&info);
+        let current = device_manager.generate_device_fingerprint(&info.clone());
+        assert_eq!(compare_fingerprint(Some(&registered), &current), FingerprintCheck::Match);
+        assert_eq!(compare_fingerprint(None, &current), FingerprintCheck::Unrecorded);
+    }
+
+    #[test]
+    fn fingerprint_mismatches_when_inputs_change() {
+        let device_manager = DeviceManager::new(String::new());
+        let registered = device_manager.generate_device_fingerprint(&device_info("cpu-1", Some("GPU-aaaa"), "install-1"));
+        // 克隆的虚拟机：安装ID相同，硬件不同
+        let changes = [
+            device_info("cpu-2", Some("GPU-aaaa"), "install-1"),
+            device_info("cpu-1", Some("GPU-bbbb"), "install-1"),
+            device_info("cpu-1", None, "install-1"),
+            device_info("cpu-1", Some("GPU-aaaa"), "install-2"),
+        ];
+        for info in &changes {
+            let current = device_manager.generate_device_fingerprint(info);
+            assert_eq!(compare_fingerprint(Some(&registered), &current), FingerprintCheck::Mismatch, "{:?}", info);
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use crate::consts::*;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::sync::OnceCell;
+
+/// 节点网络位置信息，供后端按地域调度
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct NetworkInfo {
+    /// Public IP the node reaches the internet from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub egress_ip: Option<String>,
+    /// Operator-supplied region hint (`NODE_REGION`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+}
+
+static NETWORK_INFO: OnceCell<NetworkInfo> = OnceCell::const_new();
+
+/// 获取节点网络信息，仅在首次调用时查询
+///
+/// 查询地址由 `EGRESS_IP_URL` 指定，设为 `off` 或空字符串可在隔离网络中关闭查询。
+/// 查询失败时 `egress_ip` 为空，不影响启动。
+pub async fn network_info() -> NetworkInfo {
+    NETWORK_INFO
+        .get_or_init(|| async {
+            NetworkInfo {
+                egress_ip: lookup_egress_ip().await,
+                region: std::env::var("NODE_REGION").ok().filter(|r| !r.is_empty()),
+            }
+        })
+        .await
+        .clone()
+}
+
+async fn lookup_egress_ip() -> Option<String> {
+    let url = std::env::var("EGRESS_IP_URL").unwrap_or_else(|_| EGRESS_IP_ECHO_URL.to_string());
+    if url.is_empty() || url.eq_ignore_ascii_case("off") {
+        log::info!("Egress IP lookup disabled");
+        return None;
+    }
+
+    let result = async {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(EGRESS_IP_TIMEOUT_SECONDS))
+            .build()?;
+        let body = client.get(&url).send().await?.error_for_status()?.text().await?;
+        let ip: IpAddr = body.trim().parse()?;
+        anyhow::Ok(ip.to_string())
+    }
+    .await;
+
+    match result {
+        Ok(ip) => {
+            log::info!("Egress IP: {}", ip);
+            Some(ip)
+        }
+        Err(e) => {
+            log::warn!("Failed to look up egress IP from {}: {}", url, e);
+            None
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use super::{Capabilities, DeviceError, DeviceInfo, DeviceInitResponse, DeviceManager, DeviceVerifyResponse, GpuInfo, HardwareInfo, NetworkInfo};
+use crate::consts::*;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// 注册流程的限制
+#[derive(Debug, Clone)]
+pub struct RegistrationConfig {
+    /// Device codes issued before giving up; an expired code is replaced until this is reached
+    pub max_cycles: u32,
+    /// Overall time budget for registration, across all codes
+    pub timeout: Duration,
+}
+
+/// 申请设备码所需的设备信息，每次重新申请时复用
+#[derive(Debug, Clone)]
+pub struct DeviceIdentity {
+    pub device_info: DeviceInfo,
+    pub gpu_info: GpuInfo,
+    pub hardware_info: HardwareInfo,
+    pub network: NetworkInfo,
+    pub capabilities: Capabilities,
+}
+
+/// 注册流程状态
+pub enum RegistrationState {
+    /// Requesting device code number `cycle`
+    Initializing { cycle: u32 },
+    /// Waiting for the user to approve code number `cycle`
+    AwaitingApproval {
+        cycle: u32,
+        user_code: String,
+        expires_at: DateTime<Utc>,
+    },
+    /// Device approved, tokens issued
+    Approved(DeviceVerifyResponse),
+    /// Code number `cycle` expired before approval
+    Expired { cycle: u32 },
+    /// Backend disabled the device
+    Disabled,
+    /// Overall time budget or code limit exhausted
+    GaveUp,
+}
+
+impl fmt::Display for RegistrationState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Initializing { cycle } => write!(f, "initializing (code #{})", cycle),
+            Self::AwaitingApproval { cycle, .. } => write!(f, "awaiting-approval (code #{})", cycle),
+            Self::Approved(_) => f.write_str("approved"),
+            Self::Expired { cycle } => write!(f, "expired (code #{})", cycle),
+            Self::Disabled => f.write_str("disabled"),
+            Self::GaveUp => f.write_str("gave-up"),
+        }
+    }
+}
+
+/// 注册未能完成的原因
+#[derive(Debug, thiserror::Error)]
+pub enum RegistrationError {
+    #[error("设备已被禁用")]
+    Disabled,
+    #[error("已申请 {0} 个设备码，均在验证前过期")]
+    CodesExhausted(u32),
+    #[error("注册未在 {0} 秒内完成")]
+    TimedOut(u64),
+}
+
+/// 设备注册状态机
+///
+/// 申请设备码后轮询验证状态；设备码过期时自动重新申请，直到达到设备码数量上限或总时限。
+/// 后端不可达时（申请与轮询）按指数退避重试。
+pub struct Registration<'a> {
+    manager: &'a DeviceManager,
+    config: RegistrationConfig,
+    started: Instant,
+    network_failures: u32,
+}
+
+impl<'a> Registration<'a> {
+    pub fn new(manager: &'a DeviceManager, config: RegistrationConfig) -> Self {
+        Self {
+            manager,
+            config,
+            started: Instant::now(),
+            network_failures: 0,
+        }
+    }
+
+    /// 执行注册直到批准或失败
+    ///
+    /// 每次获得新设备码时调用 `on_code`（保存并展示设备码）。
+    pub async fn run(
+        mut self,
+        identity: &DeviceIdentity,
+        mut on_code: impl FnMut(&DeviceInitResponse, DateTime<Utc>) -> Result<()>,
+    ) -> Result<DeviceVerifyResponse> {
+        let mut state = RegistrationState::Initializing { cycle: 1 };
+        log::info!("Registration started: {} (up to {} code(s), {}s budget)",
+            state, self.config.max_cycles, self.config.timeout.as_secs());
+
+        loop {
+            let next = match state {
+                RegistrationState::Initializing { cycle } => self.request_code(cycle, identity, &mut on_code).await?,
+                RegistrationState::AwaitingApproval { cycle, ref user_code, expires_at } => {
+                    self.poll(cycle, user_code, expires_at).await
+                }
+                RegistrationState::Expired { cycle } if cycle < self.config.max_cycles => {
+                    RegistrationState::Initializing { cycle: cycle + 1 }
+                }
+                RegistrationState::Expired { .. } => RegistrationState::GaveUp,
+                RegistrationState::Approved(response) => return Ok(response),
+                RegistrationState::Disabled => return Err(RegistrationError::Disabled.into()),
+                RegistrationState::GaveUp => {
+                    return Err(match self.remaining() {
+                        None => RegistrationError::TimedOut(self.config.timeout.as_secs()),
+                        Some(_) => RegistrationError::CodesExhausted(self.config.max_cycles),
+                    }
+                    .into());
+                }
+            };
+            log::info!("Registration: {} -> {}", state, next);
+            state = next;
+        }
+    }
+
+    /// 申请设备码；后端不可达时在总时限内退避重试
+    async fn request_code(
+        &mut self,
+        cycle: u32,
+        identity: &DeviceIdentity,
+        on_code: &mut impl FnMut(&DeviceInitResponse, DateTime<Utc>) -> Result<()>,
+    ) -> Result<RegistrationState> {
+        loop {
+            let Some(remaining) = self.remaining() else {
+                return Ok(RegistrationState::GaveUp);
+            };
+            let init = self.manager.init_device(
+                identity.device_info.clone(),
+                identity.gpu_info.clone(),
+                identity.hardware_info.clone(),
+                identity.network.clone(),
+                identity.capabilities.clone(),
+            );
+            match init.await {
+                Ok(response) => {
+                    self.network_failures = 0;
+                    let expires_at = DateTime::parse_from_rfc3339(&response.expires_at)
+                        .map_err(|e| DeviceError::InitError(format!("Invalid expires_at '{}': {}", response.expires_at, e)))?
+                        .with_timezone(&Utc);
+                    on_code(&response, expires_at)?;
+                    return Ok(RegistrationState::AwaitingApproval {
+                        cycle,
+                        user_code: response.user_code,
+                        expires_at,
+                    });
+                }
+                Err(DeviceError::NetworkError(e)) => {
+                    let delay = self.network_backoff().min(remaining);
+                    log::warn!("Backend unreachable while requesting a device code ({}), retrying in {}s",
+                        e, delay.as_secs());
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// 轮询验证状态直到批准、设备码过期、设备被禁用或超出总时限
+    async fn poll(&mut self, cycle: u32, user_code: &str, expires_at: DateTime<Utc>) -> RegistrationState {
+        loop {
+            if Utc::now() >= expires_at {
+                return RegistrationState::Expired { cycle };
+            }
+            let Some(remaining) = self.remaining() else {
+                return RegistrationState::GaveUp;
+            };
+
+            let delay = match self.manager.verify_device(user_code).await {
+                Ok(response) => return RegistrationState::Approved(response),
+                Err(DeviceError::CodeExpired) => return RegistrationState::Expired { cycle },
+                Err(DeviceError::DeviceDisabled) => return RegistrationState::Disabled,
+                Err(DeviceError::NetworkError(e)) => {
+                    let delay = self.network_backoff();
+                    log::warn!(
+                        "Backend unreachable during verification ({}), retrying in {}s",
+                        e, delay.as_secs()
+                    );
+                    delay
+                }
+                Err(e) => {
+                    log::debug!("Verification pending: {}", e);
+                    self.network_failures = 0;
+                    Duration::from_secs(DEVICE_VERIFY_POLL_INTERVAL)
+                }
+            };
+            tokio::time::sleep(delay.min(remaining)).await;
+        }
+    }
+
+    /// 总时限内的剩余时间，已用尽时为 `None`
+    fn remaining(&self) -> Option<Duration> {
+        self.config
+            .timeout
+            .checked_sub(self.started.elapsed())
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    /// 记录一次网络错误并返回退避时间
+    fn network_backoff(&mut self) -> Duration {
+        self.network_failures += 1;
+        verify_backoff(self.network_failures)
+    }
+}
+
+/// 后端不可达时的等待时间：轮询间隔 × 2^失败次数，不超过上限
+fn verify_backoff(failures: u32) -> Duration {
+    let delay = DEVICE_VERIFY_POLL_INTERVAL.saturating_mul(1 << failures.min(10));
+    Duration::from_secs(delay.min(DEVICE_VERIFY_MAX_BACKOFF_SECONDS))
+}
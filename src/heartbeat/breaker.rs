@@ -0,0 +1,51 @@
+use std::time::Duration;
+
+/// 心跳熔断器
+///
+/// 连续失败达到阈值后打开，心跳间隔按指数退避延长；任意一轮成功即关闭。
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    max_backoff: Duration,
+    consecutive_failures: u32,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, max_backoff: Duration) -> Self {
+        Self {
+            threshold: threshold.max(1),
+            max_backoff,
+            consecutive_failures: 0,
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.consecutive_failures >= self.threshold
+    }
+
+    /// 记录一轮成功，返回熔断器此前是否处于打开状态
+    pub fn record_success(&mut self) -> bool {
+        let was_open = self.is_open();
+        self.consecutive_failures = 0;
+        was_open
+    }
+
+    /// 记录一轮失败，返回熔断器是否因此次失败刚刚打开
+    pub fn record_failure(&mut self) -> bool {
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        self.consecutive_failures == self.threshold
+    }
+
+    pub fn consecutive_failures(&self) -> u32 {
+        self.consecutive_failures
+    }
+
+    /// 打开状态下的等待时间：基础间隔 × 2^(超出阈值的失败次数)，不超过上限
+    pub fn backoff(&self, base: Duration) -> Option<Duration> {
+        if !self.is_open() {
+            return None;
+        }
+        let exponent = (self.consecutive_failures - self.threshold + 1).min(16);
+        Some(base.saturating_mul(1 << exponent).min(self.max_backoff))
+    }
+}
@@ -0,0 +1,562 @@
+use crate::config::ConfigManager;
+use crate::metrics::Metrics;
+use anyhow::Result;
+use chrono::{TimeZone, Utc};
+use crate::consts::*;
+use crate::device::{self, DeviceError, DeviceHeartbeatResponse, DeviceManager, DeviceMetrics, GpuStatus, HardwareCollector, NetworkInfo};
+use breaker::CircuitBreaker;
+use sampler::{GpuSampler, MetricAggregation};
+use sanitize::MetricsSanitizer;
+use rand::Rng;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+pub mod breaker;
+pub mod sampler;
+pub mod sanitize;
+
+/// 心跳配置
+#[derive(Debug, Clone)]
+pub struct HeartbeatConfig {
+    pub interval_secs: u64,
+    /// Randomize each sleep by ±fraction of the interval (0 disables jitter),
+    /// so a fleet started together doesn't heartbeat in synchronized waves
+    pub jitter_fraction: f64,
+    /// Rebuild the backend HTTP client once it is this old (seconds), so stale
+    /// keepalive connections and DNS changes are picked up over long uptimes
+    pub client_max_age_secs: u64,
+    /// Consecutive failed heartbeat rounds before backing off
+    pub failure_threshold: u32,
+    /// Lifetime assumed for access tokens that aren't JWTs (seconds). Unset
+    /// refreshes opaque tokens only when the backend rejects them with 401
+    pub opaque_token_lifetime_secs: Option<u64>,
+    /// GPU load readings per interval; above 1 a background sampler spreads the extra
+    /// readings (one nvidia-smi run each) across the interval
+    pub samples_per_interval: u32,
+    /// How the readings of an interval are combined into the reported values
+    pub aggregation: MetricAggregation,
+}
+
+/// 心跳中上报的节点负载
+#[derive(Debug, Clone)]
+pub struct NodeLoad {
+    pub node_id: String,
+    pub max_concurrent_tasks: usize,
+    /// Tasks currently being processed, shared with the node's task processor
+    pub in_flight: Arc<AtomicUsize>,
+    /// GPU the node runs on; `None` watches every GPU
+    pub gpu_index: Option<u32>,
+    /// Set while the node's GPU is throttling, shared with the node's task processor
+    pub throttled: Arc<AtomicBool>,
+}
+
+impl NodeLoad {
+    /// 根据本轮GPU状态更新节点的降频标记，状态变化时记录日志
+    fn update_throttled(&self, gpus: &[GpuStatus]) {
+        let throttled = gpus
+            .iter()
+            .filter(|gpu| self.gpu_index.is_none_or(|index| gpu.index == index))
+            .any(|gpu| gpu.throttling);
+        if self.throttled.swap(throttled, Ordering::Relaxed) != throttled {
+            if throttled {
+                log::warn!("GPU throttling affects node {}", self.node_id);
+            } else {
+                log::info!("GPU throttling on node {} cleared", self.node_id);
+            }
+        }
+    }
+}
+
+/// 心跳上报服务
+///
+/// 为进程内的所有逻辑节点按相同节奏上报心跳，共享同一组访问令牌。
+pub struct HeartbeatService {
+    device_manager: DeviceManager,
+    nodes: Vec<NodeLoad>,
+    access_token: String,
+    refresh_token: String,
+    config: HeartbeatConfig,
+    metrics: Arc<Metrics>,
+    warned_version: Option<String>,
+    /// Interval requested by the backend for the next sleep only
+    server_interval_secs: Option<u64>,
+    breaker: CircuitBreaker,
+    network: NetworkInfo,
+    sanitizer: MetricsSanitizer,
+    /// Background GPU load readings, when more than one per interval is configured
+    sampler: Option<GpuSampler>,
+    /// When the current access token was obtained (or loaded), for opaque tokens
+    token_obtained_at: Instant,
+    /// Whether the current access token is known to be opaque (not a JWT)
+    opaque_token: bool,
+}
+
+impl HeartbeatService {
+    pub fn new(
+        base_url: String,
+        nodes: Vec<NodeLoad>,
+        access_token: String,
+        refresh_token: String,
+        config: HeartbeatConfig,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        let breaker = CircuitBreaker::new(
+            config.failure_threshold,
+            Duration::from_secs(HEARTBEAT_MAX_BACKOFF_SECONDS),
+        );
+        let sanitizer = MetricsSanitizer::new(HardwareCollector::new().get_gpu_memory());
+        Self {
+            device_manager: DeviceManager::new(base_url),
+            nodes,
+            access_token,
+            refresh_token,
+            config,
+            metrics,
+            warned_version: None,
+            server_interval_secs: None,
+            breaker,
+            network: NetworkInfo::default(),
+            sanitizer,
+            sampler: None,
+            token_obtained_at: Instant::now(),
+            opaque_token: false,
+        }
+    }
+
+    /// 运行心跳循环
+    ///
+    /// 仅在后端报告当前客户端版本已不受支持时返回错误。
+    pub async fn run(mut self) -> Result<()> {
+        log::info!(
+            "Starting heartbeat reporting for {} node(s), interval: {} seconds, jitter: ±{:.0}%",
+            self.nodes.len(),
+            self.config.interval_secs,
+            self.config.jitter_fraction * 100.0
+        );
+
+        self.network = device::network::network_info().await;
+        self.start_sampler();
+
+        // 随机延迟首次心跳，打散同时启动的节点
+        let first_delay = self.initial_delay();
+        log::info!("First heartbeat in {:.1}s", first_delay.as_secs_f64());
+        tokio::time::sleep(first_delay).await;
+
+        // 创建配置管理器，处理错误而不是传播
+        let mut config_manager = match ConfigManager::new() {
+            Ok(cm) => cm,
+            Err(e) => {
+                log::error!("Failed to create config manager: {}", e);
+                return Ok(());
+            }
+        };
+
+        loop {
+            self.rebuild_stale_client();
+            self.update_token_expiry_metric();
+
+            // 检查令牌是否即将过期，如果是，则刷新
+            if self.should_refresh_token() {
+                log::info!("Access token about to expire, starting active refresh");
+                self.refresh_access_token(&mut config_manager).await;
+            }
+
+            // 收集GPU指标；nvidia-smi 是阻塞调用，放到阻塞线程池执行
+            let gpu_metrics = tokio::task::spawn_blocking(device::hardware::collect_gpu_metrics)
+                .await
+                .map_err(|e| anyhow::anyhow!(e))
+                .and_then(|metrics| metrics);
+            match gpu_metrics {
+                Ok(gpu_metrics) => {
+                    let gpu_metrics = match &self.sampler {
+                        Some(sampler) => self.config.aggregation.aggregate(gpu_metrics, &sampler.take()),
+                        None => gpu_metrics,
+                    };
+                    let gpu_metrics = self.sanitizer.sanitize(gpu_metrics);
+                    self.metrics.gpu_history.record(gpu_metrics.clone());
+                    let hardware_healthy = gpu_metrics.ecc_errors.unwrap_or(0) == 0;
+                    if !hardware_healthy {
+                        log::warn!(
+                            "GPU reports {} uncorrected ECC errors",
+                            gpu_metrics.ecc_errors.unwrap_or(0)
+                        );
+                    }
+                    for gpu in gpu_metrics.gpus.iter().filter(|gpu| gpu.throttling) {
+                        log::warn!(
+                            "GPU {} is throttling ({}), temperature: {}",
+                            gpu.index,
+                            gpu.throttle_reason_names().join(", "),
+                            gpu.temperature.map_or("unknown".to_string(), |t| format!("{}°C", t))
+                        );
+                    }
+                    for node in &self.nodes {
+                        node.update_throttled(&gpu_metrics.gpus);
+                    }
+
+                    // 转换为设备指标
+                    let device_metrics = DeviceMetrics {
+                        gpu_utilization: gpu_metrics.utilization,
+                        gpu_memory_used: gpu_metrics.memory_used,
+                        gpu_temperature: gpu_metrics.temperature,
+                        ecc_errors: gpu_metrics.ecc_errors,
+                        hardware_healthy,
+                        max_concurrent_tasks: 0,
+                        tasks_in_flight: 0,
+                        gpus: gpu_metrics.gpus,
+                        timestamp: gpu_metrics.timestamp,
+                    };
+
+                    let mut delivered = true;
+                    for node in self.nodes.clone() {
+                        let metrics = DeviceMetrics {
+                            max_concurrent_tasks: node.max_concurrent_tasks as u32,
+                            tasks_in_flight: node.in_flight.load(Ordering::Relaxed) as u32,
+                            ..device_metrics.clone()
+                        };
+                        delivered &= self
+                            .send_heartbeat(&node.node_id, metrics, &mut config_manager)
+                            .await?;
+                    }
+                    self.record_round(delivered);
+                }
+                Err(e) => {
+                    log::error!("Failed to collect GPU metrics: {}", e);
+                }
+            }
+
+            // 等待下一次心跳
+            tokio::time::sleep(self.next_delay()).await;
+        }
+    }
+
+    /// 每个间隔需要多次读数时启动后台采样，读数均匀分布在心跳间隔内
+    fn start_sampler(&mut self) {
+        let samples = self.config.samples_per_interval;
+        if samples <= 1 {
+            return;
+        }
+        let period = Duration::from_millis(
+            (self.config.interval_secs * 1000 / samples as u64).max(HEARTBEAT_MIN_SAMPLE_PERIOD_MS),
+        );
+        log::info!(
+            "Sampling GPU load every {:.1}s, reporting the {} of each interval",
+            period.as_secs_f64(),
+            self.config.aggregation
+        );
+        // 心跳时刻另有一次读数，后台只需保留其余的读数
+        self.sampler = Some(GpuSampler::spawn(period, samples as usize - 1));
+    }
+
+    /// 首次心跳前的随机延迟，范围 [0, interval × jitter_fraction]
+    fn initial_delay(&self) -> Duration {
+        let max = self.config.interval_secs as f64 * self.jitter_fraction();
+        if max <= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(rand::thread_rng().gen_range(0.0..=max))
+    }
+
+    /// 下一次心跳前的等待时间，范围 interval × (1 ± jitter_fraction)
+    ///
+    /// 后端在上一次心跳响应中指定了间隔时使用该值，否则使用配置的间隔。
+    fn next_delay(&mut self) -> Duration {
+        let interval = self
+            .server_interval_secs
+            .take()
+            .unwrap_or(self.config.interval_secs);
+        let interval = self
+            .breaker
+            .backoff(Duration::from_secs(interval))
+            .map(|backoff| backoff.as_secs_f64())
+            .unwrap_or(interval as f64);
+        let fraction = self.jitter_fraction();
+        if fraction <= 0.0 {
+            return Duration::from_secs_f64(interval);
+        }
+        let factor = rand::thread_rng().gen_range(1.0 - fraction..=1.0 + fraction);
+        Duration::from_secs_f64(interval * factor)
+    }
+
+    fn jitter_fraction(&self) -> f64 {
+        self.config.jitter_fraction.clamp(0.0, 1.0)
+    }
+
+    /// 客户端存活时间超过上限时重建
+    fn rebuild_stale_client(&mut self) {
+        let age = self.device_manager.client_age();
+        if age.as_secs() >= self.config.client_max_age_secs {
+            log::info!("Rebuilding heartbeat backend client after {}s of uptime", age.as_secs());
+            self.device_manager.rebuild_client();
+        }
+    }
+
+    /// 根据本轮心跳是否全部送达更新熔断器
+    fn record_round(&mut self, delivered: bool) {
+        if delivered {
+            if self.breaker.record_success() {
+                log::info!("Backend reachable again, resuming normal heartbeat interval");
+            }
+        } else if self.breaker.record_failure() {
+            log::warn!(
+                "{} consecutive heartbeat rounds failed, backing off",
+                self.breaker.consecutive_failures()
+            );
+        }
+    }
+
+    /// 发送单个节点的心跳，授权失败时刷新令牌
+    ///
+    /// 网络错误时使用新建的客户端立即重试一次。返回后端是否可达。
+    async fn send_heartbeat(
+        &mut self,
+        node_id: &str,
+        metrics: DeviceMetrics,
+        config_manager: &mut ConfigManager,
+    ) -> Result<bool> {
+        let mut result = self
+            .device_manager
+            .send_heartbeat(node_id, metrics.clone(), self.network.clone(), &self.access_token)
+            .await;
+        if let Err(DeviceError::NetworkError(e)) = &result {
+            log::warn!(
+                "Heartbeat for node {} failed with network error ({}), retrying with a fresh connection",
+                node_id, e
+            );
+            self.device_manager.rebuild_client();
+            log::info!("Heartbeat backend client rebuilt");
+            result = self
+                .device_manager
+                .send_heartbeat(node_id, metrics, self.network.clone(), &self.access_token)
+                .await;
+        }
+
+        match result {
+            Ok(response) => {
+                log::debug!("Heartbeat sent successfully for node {}: {}", node_id, response.message);
+                self.check_version(&response)?;
+
+                if let Some(requested) = response.next_interval_secs {
+                    let interval = requested.clamp(HEARTBEAT_MIN_INTERVAL_SECONDS, HEARTBEAT_MAX_INTERVAL_SECONDS);
+                    if interval != requested {
+                        log::warn!("Server requested heartbeat interval {}s, clamped to {}s", requested, interval);
+                    }
+                    self.server_interval_secs = Some(interval);
+                }
+                Ok(true)
+            }
+            Err(e) => {
+                // 检查是否是授权错误 (假设401状态码导致了特定的错误信息)
+                if e.to_string().contains("401") {
+                    log::warn!("Access token expired, attempting to refresh token");
+                    self.refresh_access_token(config_manager).await;
+                    Ok(true)
+                } else {
+                    log::error!("Failed to send heartbeat for node {}: {}", node_id, e);
+                    Ok(false)
+                }
+            }
+        }
+    }
+
+    /// 访问令牌是否需要主动刷新
+    ///
+    /// JWT 按其过期时间判断；非 JWT 的不透明令牌按配置的固定有效期（自获取或启动时起）
+    /// 判断，未配置有效期时只在后端返回 401 时刷新。不透明令牌只在首次发现时提示一次。
+    fn should_refresh_token(&mut self) -> bool {
+        match self
+            .device_manager
+            .should_refresh_token(&self.access_token, TOKEN_REFRESH_THRESHOLD_SECONDS)
+        {
+            Ok(should_refresh) => should_refresh,
+            Err(e) => {
+                if !self.opaque_token {
+                    self.opaque_token = true;
+                    match self.config.opaque_token_lifetime_secs {
+                        Some(lifetime) => log::info!(
+                            "Access token expiry unreadable ({}), treating it as opaque with a {}s lifetime",
+                            e, lifetime
+                        ),
+                        None => log::info!(
+                            "Access token expiry unreadable ({}), treating it as opaque and refreshing on 401 only",
+                            e
+                        ),
+                    }
+                }
+                self.config.opaque_token_lifetime_secs.is_some_and(|lifetime| {
+                    self.token_obtained_at.elapsed().as_secs() + TOKEN_REFRESH_THRESHOLD_SECONDS >= lifetime
+                })
+            }
+        }
+    }
+
+    /// 检查后端返回的客户端版本信息
+    fn check_version(&mut self, response: &DeviceHeartbeatResponse) -> Result<()> {
+        if let Some(min_version) = &response.min_supported_version
+            && device::is_version_older(CLIENT_VERSION, min_version)
+        {
+            log::error!(
+                "{} (current: {}, minimum supported: {})",
+                MSG_CLIENT_UNSUPPORTED, CLIENT_VERSION, min_version
+            );
+            anyhow::bail!(
+                "Client version {} is older than minimum supported version {}",
+                CLIENT_VERSION,
+                min_version
+            );
+        }
+
+        if let Some(latest) = &response.latest_version
+            && device::is_version_older(CLIENT_VERSION, latest)
+            && self.warned_version.as_ref() != Some(latest)
+        {
+            log::warn!("{} (current: {}, latest: {})", MSG_CLIENT_OUTDATED, CLIENT_VERSION, latest);
+            self.warned_version = Some(latest.clone());
+        }
+        Ok(())
+    }
+
+    /// 更新访问令牌剩余有效期指标
+    fn update_token_expiry_metric(&self) {
+        let expires_in = self
+            .device_manager
+            .get_token_expiry(&self.access_token)
+            .ok()
+            .map(|expiry| expiry as i64 - Utc::now().timestamp());
+        self.metrics.set_token_expires_in(expires_in);
+    }
+
+    /// 刷新访问令牌并保存到配置
+    async fn refresh_access_token(&mut self, config_manager: &mut ConfigManager) {
+        match self.device_manager.refresh_token(&self.refresh_token).await {
+            Ok(refresh_response) => {
+                self.metrics.record_token_refresh(true);
+
+                // 更新当前使用的令牌
+                self.access_token = refresh_response.access_token.clone();
+                self.token_obtained_at = Instant::now();
+                self.opaque_token = false;
+
+                self.update_token_expiry_metric();
+                let expires_at = self
+                    .device_manager
+                    .get_token_expiry(&self.access_token)
+                    .ok()
+                    .and_then(|expiry| Utc.timestamp_opt(expiry as i64, 0).single());
+                match expires_at {
+                    Some(expires_at) => log::info!(
+                        "Token refresh successful, new token expires at {} (in {}s)",
+                        expires_at.to_rfc3339(),
+                        (expires_at - Utc::now()).num_seconds()
+                    ),
+                    None => log::info!("Token refresh successful, new token expiry unknown"),
+                }
+
+                // 后端轮换了刷新令牌时同步使用新令牌
+                if let Some(refresh_token) = &refresh_response.refresh_token {
+                    self.refresh_token = refresh_token.clone();
+                }
+
+                // 保存新的令牌到配置
+                if let Err(save_err) =
+                    config_manager.update_tokens(refresh_response.access_token, refresh_response.refresh_token).await
+                {
+                    log::error!("Failed to save new access token: {:#}", save_err);
+                }
+            }
+            Err(refresh_err) => {
+                self.metrics.record_token_refresh(false);
+                log::error!("Token refresh failed: {}", refresh_err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::history::GpuHistoryConfig;
+    use base64::{Engine as _, engine::general_purpose};
+
+    fn service(access_token: &str, opaque_token_lifetime_secs: Option<u64>) -> HeartbeatService {
+        let config = HeartbeatConfig {
+            interval_secs: 30,
+            jitter_fraction: 0.0,
+            client_max_age_secs: 3600,
+            failure_threshold: 3,
+            opaque_token_lifetime_secs,
+            samples_per_interval: 1,
+            aggregation: MetricAggregation::Mean,
+        };
+        let metrics = Arc::new(Metrics::new(GpuHistoryConfig { capacity: 1, file: None, max_file_bytes: 0 }));
+        HeartbeatService::new(
+            "http://127.0.0.1:1".to_string(),
+            Vec::new(),
+            access_token.to_string(),
+            String::new(),
+            config,
+            metrics,
+        )
+    }
+
+    fn jwt(payload: serde_json::Value) -> String {
+        let encode = |value: &serde_json::Value| general_purpose::URL_SAFE_NO_PAD.encode(value.to_string());
+        format!("{}.{}.signature", encode(&serde_json::json!({"alg": "HS256"})), encode(&payload))
+    }
+
+    /// 不透明字符串、payload 无法解码的令牌、没有 exp 的 JWT
+    fn unreadable_tokens() -> [String; 3] {
+        [
+            "opaque-access-token".to_string(),
+            "header.not*base64!.signature".to_string(),
+            jwt(serde_json::json!({"sub": "node"})),
+        ]
+    }
+
+    fn now() -> u64 {
+        Utc::now().timestamp() as u64
+    }
+
+    #[test]
+    fn refreshes_jwt_by_its_expiry() {
+        let mut expiring = service(&jwt(serde_json::json!({"exp": now() + 60})), None);
+        assert!(expiring.should_refresh_token());
+
+        let mut fresh = service(&jwt(serde_json::json!({"exp": now() + 24 * 3600})), None);
+        assert!(!fresh.should_refresh_token());
+        assert!(!fresh.opaque_token);
+    }
+
+    #[test]
+    fn rejects_tokens_without_a_readable_expiry() {
+        let device_manager = DeviceManager::new(String::new());
+        for token in &unreadable_tokens() {
+            assert!(
+                device_manager.should_refresh_token(token, TOKEN_REFRESH_THRESHOLD_SECONDS).is_err(),
+                "{}",
+                token
+            );
+        }
+    }
+
+    #[test]
+    fn opaque_token_without_lifetime_waits_for_401() {
+        for token in &unreadable_tokens() {
+            let mut service = service(token, None);
+            service.token_obtained_at = Instant::now() - Duration::from_secs(30 * 24 * 3600);
+            assert!(!service.should_refresh_token(), "{}", token);
+            assert!(service.opaque_token);
+        }
+    }
+
+    #[test]
+    fn opaque_token_refreshes_after_configured_lifetime() {
+        let mut service = service("opaque-access-token", Some(3600));
+        assert!(!service.should_refresh_token());
+        assert!(service.opaque_token);
+
+        // 剩余有效期小于刷新阈值时刷新
+        service.token_obtained_at = Instant::now() - Duration::from_secs(3600 - TOKEN_REFRESH_THRESHOLD_SECONDS);
+        assert!(service.should_refresh_token());
+    }
+}
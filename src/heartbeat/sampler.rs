@@ -0,0 +1,128 @@
+use crate::consts::*;
+use crate::device::hardware::sample_gpu_load;
+use crate::device::{GpuMetrics, LoadSample};
+use anyhow::Result;
+use std::collections::VecDeque;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// 心跳间隔内多次读数的聚合方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricAggregation {
+    /// Average of the readings
+    Mean,
+    /// Highest reading
+    Peak,
+}
+
+impl MetricAggregation {
+    /// 用心跳时刻的读数与间隔内的采样计算上报的利用率、显存与温度
+    ///
+    /// 心跳时刻某项读数失败时只用间隔内的采样。ECC 与每块GPU的降频状态保持心跳时刻的值。
+    pub fn aggregate(self, mut metrics: GpuMetrics, samples: &[LoadSample]) -> GpuMetrics {
+        if samples.is_empty() {
+            return metrics;
+        }
+        metrics.utilization = self
+            .combine(samples.iter().map(|s| s.utilization as u64).chain(metrics.utilization.map(u64::from)))
+            .map(|value| value as u8);
+        metrics.memory_used = self.combine(samples.iter().map(|s| s.memory_used).chain(metrics.memory_used));
+        metrics.temperature = self
+            .combine(samples.iter().map(|s| s.temperature as u64).chain(metrics.temperature.map(u64::from)))
+            .map(|value| value as u8);
+        metrics
+    }
+
+    /// 平均值（四舍五入）或最大值，`values` 为空时为空
+    fn combine(self, values: impl Iterator<Item = u64>) -> Option<u64> {
+        match self {
+            Self::Mean => {
+                let (sum, count) = values.fold((0, 0), |(sum, count), value| (sum + value, count + 1));
+                (sum + count / 2).checked_div(count)
+            }
+            Self::Peak => values.max(),
+        }
+    }
+}
+
+impl FromStr for MetricAggregation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "mean" | "avg" => Ok(Self::Mean),
+            "peak" | "max" => Ok(Self::Peak),
+            other => Err(anyhow::anyhow!("Unknown metric aggregation: {} (expected mean or peak)", other)),
+        }
+    }
+}
+
+impl Display for MetricAggregation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Mean => f.write_str("mean"),
+            Self::Peak => f.write_str("peak"),
+        }
+    }
+}
+
+/// GPU负载后台采样
+///
+/// 按固定周期读取利用率、显存与温度，心跳时取出并聚合，避免单点读数漏掉两次心跳之间的负载峰值。
+/// 每次读数执行一次 nvidia-smi；只保留最近 `capacity` 个读数，心跳退避期间不会无限增长。
+pub struct GpuSampler {
+    samples: Arc<Mutex<VecDeque<LoadSample>>>,
+    task: JoinHandle<()>,
+}
+
+impl GpuSampler {
+    pub fn spawn(period: Duration, capacity: usize) -> Self {
+        let samples = Arc::new(Mutex::new(VecDeque::with_capacity(capacity)));
+        let buffer = Arc::clone(&samples);
+        let task = tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(period);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                ticker.tick().await;
+                let sample = match tokio::task::spawn_blocking(sample_gpu_load).await {
+                    Ok(Ok(sample)) => sample,
+                    Ok(Err(e)) => {
+                        log::debug!("GPU load sample skipped: {}", e);
+                        continue;
+                    }
+                    Err(e) => {
+                        log::debug!("GPU load sample panicked: {}", e);
+                        continue;
+                    }
+                };
+                // 明显错误的读数不参与聚合
+                if sample.utilization > 100
+                    || !(GPU_TEMPERATURE_MIN_C..=GPU_TEMPERATURE_MAX_C).contains(&sample.temperature)
+                {
+                    log::debug!("Implausible GPU load sample skipped: {:?}", sample);
+                    continue;
+                }
+                let mut buffer = buffer.lock().unwrap_or_else(|e| e.into_inner());
+                if buffer.len() == capacity {
+                    buffer.pop_front();
+                }
+                buffer.push_back(sample);
+            }
+        });
+        Self { samples, task }
+    }
+
+    /// 取出上次心跳以来的读数
+    pub fn take(&self) -> Vec<LoadSample> {
+        self.samples.lock().unwrap_or_else(|e| e.into_inner()).drain(..).collect()
+    }
+}
+
+impl Drop for GpuSampler {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
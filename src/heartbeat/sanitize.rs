@@ -0,0 +1,169 @@
+use crate::consts::*;
+use crate::device::GpuMetrics;
+
+/// 最近一次合理的各项读数
+#[derive(Debug, Default)]
+struct LastGood {
+    utilization: Option<u8>,
+    memory_used: Option<u64>,
+    temperature: Option<u8>,
+}
+
+/// GPU指标合理性校验
+///
+/// nvidia-smi 偶发的错误读数（解析失败、超出范围的温度、工具出错时的全零读数等）不直接上报：
+/// 不合理的读数沿用该项上一次的合理值，没有历史值时省略该项，而不是上报编造的数值。
+#[derive(Debug)]
+pub struct MetricsSanitizer {
+    /// Total GPU memory (MB), upper bound for `memory_used` when known
+    total_memory: Option<u64>,
+    last_good: LastGood,
+}
+
+impl MetricsSanitizer {
+    pub fn new(total_memory: Option<u64>) -> Self {
+        Self {
+            total_memory: total_memory.filter(|&memory| memory > 0),
+            last_good: LastGood::default(),
+        }
+    }
+
+    pub fn sanitize(&mut self, mut metrics: GpuMetrics) -> GpuMetrics {
+        let temperature_ok = metrics
+            .temperature
+            .is_some_and(|temperature| (GPU_TEMPERATURE_MIN_C..=GPU_TEMPERATURE_MAX_C).contains(&temperature));
+        // 工具出错时各项读数通常一起为 0；温度读数无效时，同一次读数中为 0 的利用率与显存也视为失败
+        let zeroed = !temperature_ok;
+
+        let utilization_ok = metrics
+            .utilization
+            .is_some_and(|utilization| utilization <= 100 && !(zeroed && utilization == 0));
+        metrics.utilization = settle("GPU utilization", metrics.utilization, utilization_ok, &mut self.last_good.utilization);
+
+        let memory_ok = metrics.memory_used.is_some_and(|memory_used| {
+            self.total_memory.is_none_or(|total| memory_used <= total) && !(zeroed && memory_used == 0)
+        });
+        metrics.memory_used = settle("GPU memory used", metrics.memory_used, memory_ok, &mut self.last_good.memory_used);
+
+        metrics.temperature = settle("GPU temperature", metrics.temperature, temperature_ok, &mut self.last_good.temperature);
+
+        // 单块GPU的异常温度读数不上报
+        for gpu in &mut metrics.gpus {
+            if let Some(temperature) = gpu.temperature
+                && !(GPU_TEMPERATURE_MIN_C..=GPU_TEMPERATURE_MAX_C).contains(&temperature)
+            {
+                log::warn!("Implausible temperature {}°C on GPU {}, not reporting it", temperature, gpu.index);
+                gpu.temperature = None;
+            }
+        }
+
+        metrics
+    }
+}
+
+/// 合理的读数记为最近合理值并原样返回；否则返回最近合理值，没有时为空
+fn settle<T: Copy + std::fmt::Debug>(name: &str, value: Option<T>, ok: bool, last_good: &mut Option<T>) -> Option<T> {
+    if ok {
+        *last_good = value;
+        return value;
+    }
+    match (value, *last_good) {
+        (Some(value), Some(last)) => log::warn!("Implausible {} reading {:?}, reporting last good {:?}", name, value, last),
+        (Some(value), None) => log::warn!("Implausible {} reading {:?}, omitting it", name, value),
+        (None, Some(last)) => log::warn!("{} could not be read, reporting last good {:?}", name, last),
+        (None, None) => log::warn!("{} could not be read, omitting it", name),
+    }
+    *last_good
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::GpuStatus;
+
+    fn metrics(utilization: Option<u8>, memory_used: Option<u64>, temperature: Option<u8>) -> GpuMetrics {
+        GpuMetrics {
+            utilization,
+            memory_used,
+            temperature,
+            ecc_errors: None,
+            gpus: Vec::new(),
+            timestamp: String::new(),
+        }
+    }
+
+    fn readings(metrics: &GpuMetrics) -> (Option<u8>, Option<u64>, Option<u8>) {
+        (metrics.utilization, metrics.memory_used, metrics.temperature)
+    }
+
+    #[test]
+    fn passes_plausible_readings_through() {
+        let mut sanitizer = MetricsSanitizer::new(Some(24576));
+        let sanitized = sanitizer.sanitize(metrics(Some(87), Some(20000), Some(71)));
+        assert_eq!(readings(&sanitized), (Some(87), Some(20000), Some(71)));
+
+        // 温度正常时 0 利用率是空闲GPU的真实读数
+        let idle = sanitizer.sanitize(metrics(Some(0), Some(300), Some(35)));
+        assert_eq!(readings(&idle), (Some(0), Some(300), Some(35)));
+    }
+
+    #[test]
+    fn omits_implausible_readings_without_history() {
+        let mut sanitizer = MetricsSanitizer::new(Some(8192));
+        let sanitized = sanitizer.sanitize(metrics(Some(150), Some(9000), Some(0)));
+        assert_eq!(readings(&sanitized), (None, None, None));
+
+        let sanitized = sanitizer.sanitize(metrics(None, None, Some(200)));
+        assert_eq!(readings(&sanitized), (None, None, None));
+    }
+
+    #[test]
+    fn reuses_last_good_value_for_each_branch() {
+        let mut sanitizer = MetricsSanitizer::new(Some(8192));
+        sanitizer.sanitize(metrics(Some(40), Some(4000), Some(60)));
+
+        // 利用率超过 100%
+        let sanitized = sanitizer.sanitize(metrics(Some(101), Some(4100), Some(61)));
+        assert_eq!(readings(&sanitized), (Some(40), Some(4100), Some(61)));
+
+        // 显存超过总量
+        let sanitized = sanitizer.sanitize(metrics(Some(50), Some(9000), Some(62)));
+        assert_eq!(readings(&sanitized), (Some(50), Some(4100), Some(62)));
+
+        // 温度超出范围
+        let sanitized = sanitizer.sanitize(metrics(Some(55), Some(4200), Some(4)));
+        assert_eq!(readings(&sanitized), (Some(55), Some(4200), Some(62)));
+        let sanitized = sanitizer.sanitize(metrics(Some(55), Some(4200), Some(111)));
+        assert_eq!(readings(&sanitized), (Some(55), Some(4200), Some(62)));
+
+        // 解析失败
+        let sanitized = sanitizer.sanitize(metrics(None, None, None));
+        assert_eq!(readings(&sanitized), (Some(55), Some(4200), Some(62)));
+    }
+
+    #[test]
+    fn treats_zeroed_sample_as_failed_read() {
+        let mut sanitizer = MetricsSanitizer::new(None);
+        sanitizer.sanitize(metrics(Some(90), Some(7000), Some(75)));
+
+        let sanitized = sanitizer.sanitize(metrics(Some(0), Some(0), Some(0)));
+        assert_eq!(readings(&sanitized), (Some(90), Some(7000), Some(75)));
+
+        // 失败的读数不会成为之后沿用的值
+        let sanitized = sanitizer.sanitize(metrics(Some(0), Some(0), None));
+        assert_eq!(readings(&sanitized), (Some(90), Some(7000), Some(75)));
+    }
+
+    #[test]
+    fn drops_implausible_per_gpu_temperatures() {
+        let mut sanitizer = MetricsSanitizer::new(None);
+        let mut sample = metrics(Some(10), Some(100), Some(40));
+        sample.gpus = vec![
+            GpuStatus { index: 0, temperature: Some(40), throttle_reasons: None, throttling: false },
+            GpuStatus { index: 1, temperature: Some(0), throttle_reasons: None, throttling: false },
+        ];
+        let sanitized = sanitizer.sanitize(sample);
+        assert_eq!(sanitized.gpus[0].temperature, Some(40));
+        assert_eq!(sanitized.gpus[1].temperature, None);
+    }
+}
@@ -1,54 +1,122 @@
+mod cli;
 mod config;
 mod consts;
 mod device;
+mod heartbeat;
+mod metrics;
 mod runtime;
 mod stable_diffusion;
 mod task;
+mod upload;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use chrono::{DateTime, Utc};
+use cli::Command;
 use config::ConfigManager;
+use config::settings::Settings;
 use consts::*;
-use device::{DeviceInfo, DeviceManager, DeviceMetrics, GpuInfo, HardwareCollector, HardwareInfo};
+use device::registration::{DeviceIdentity, Registration};
+use device::{Capabilities, DeviceError, DeviceInfo, DeviceManager, FingerprintCheck, GpuInfo, HardwareCollector, HardwareInfo};
+use heartbeat::{HeartbeatService, NodeLoad};
+use metrics::Metrics;
 use runtime::RuntimeChecker;
+use stable_diffusion::{RetryableErrors, SDConfig, StableDiffusion};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
+use task::lifecycle::{NodeSummary, StopReason};
 use task::{TaskProcessor, TaskProcessorConfig};
+use upload::ImageCache;
+use tokio::sync::watch;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     // 初始化日志
-    if std::env::var("RUST_LOG").is_err() {
+    let logger = if std::env::var("RUST_LOG").is_err() {
         // 使用 env_logger::Builder 而不是直接设置环境变量
         env_logger::Builder::new()
             .filter_level(log::LevelFilter::Info)
             .filter_module("zkom_client", log::LevelFilter::Debug)
             .filter_module("async_nats", log::LevelFilter::Debug)
-            .init();
+            .build()
     } else {
         // 如果已设置 RUST_LOG，使用默认初始化
-        env_logger::init();
-    }
+        env_logger::Builder::from_default_env().build()
+    };
+    // 包装日志输出，使失败任务可以附带其执行期间的日志
+    log::set_max_level(logger.filter());
+    log::set_boxed_logger(Box::new(task::logs::CapturingLogger::new(logger)))?;
     
-    log::info!("{}", MSG_STARTING_NODE);
-    log::debug!("NATS server URL: {}", NATS_SERVER_URL);
+    let command = cli::parse_args(std::env::args().skip(1))?;
+    let result = match command {
+        Command::Status => print_status(),
+        Command::RefreshToken => refresh_token().await,
+        Command::Reconfigure { yes } => reconfigure(yes).await,
+        Command::ConfigDump { show_secrets } => dump_config(show_secrets),
+        Command::Run { once } => run(once).await,
+    };
+    
+    // 空闲退出使用单独的退出码，便于自动伸缩环境区分空闲退出与故障
+    if let Err(e) = &result
+        && e.is::<IdleExit>()
+    {
+        log::info!("{}, exiting with code {}", e, IDLE_EXIT_CODE);
+        log::logger().flush();
+        std::process::exit(IDLE_EXIT_CODE);
+    }
+    result
+}
+
+/// 节点因空闲超时而退出
+#[derive(Debug, thiserror::Error)]
+#[error("No tasks for {0}s")]
+struct IdleExit(u64);
 
-    // 检查运行时环境
-    let runtime_checker = RuntimeChecker::new();
-    runtime_checker.check_environment()?;
+/// 注册（如需要）并启动节点
+async fn run(once: bool) -> Result<()> {
+    log::info!("{} (version {})", MSG_STARTING_NODE, CLIENT_VERSION);
 
     // 初始化配置管理器
     let mut config_manager = ConfigManager::new()?;
     let config = config_manager.get_config();
+    let needs_registration = config.access_token.is_none();
 
-    // 如果已经配置了访问令牌，直接启动节点
-    if config.access_token.is_some() {
-        log::info!("{}", MSG_NODE_CONFIGURED);
-        return start_node(config_manager.get_config()).await;
+    // 运行时环境检查与设备信息收集互不依赖，并行执行以缩短启动时间
+    // （nvidia-smi/nvcc/docker 每次调用耗时 200ms 时，由串行的 1.43s 降至 0.22s）
+    let started = std::time::Instant::now();
+    let (environment, hardware) = tokio::join!(
+        tokio::task::spawn_blocking(|| RuntimeChecker::new().check_environment()),
+        tokio::task::spawn_blocking(|| HardwareCollector::new().collect_info()),
+    );
+    // 环境检查失败优先报告，避免被设备信息收集的错误掩盖
+    environment.context("Runtime environment check panicked")??;
+    // 已注册的节点只用设备信息核对指纹，收集失败时跳过核对
+    let hardware_info = match hardware.context("Hardware collection panicked")? {
+        Ok(info) => Some(info),
+        Err(e) if !needs_registration => {
+            log::warn!("Failed to collect device info, skipping the fingerprint check: {:#}", e);
+            None
+        }
+        Err(e) => return Err(e),
+    };
+    log::debug!("Startup checks finished in {:.2}s", started.elapsed().as_secs_f64());
+
+    // 显存不足的节点拒绝启动
+    let gpu_memory = match &hardware_info {
+        Some(info) => info.gpu_memory,
+        None => HardwareCollector::new().get_gpu_memory(),
+    };
+    let settings = Settings::resolve(config, gpu_memory);
+    if settings.skip_gpu_memory_check.value {
+        log::warn!("GPU memory check skipped (SKIP_GPU_MEMORY_CHECK)");
+    } else {
+        RuntimeChecker::new().check_gpu_memory(gpu_memory, settings.min_gpu_memory_mb.value)?;
     }
 
-    // 收集设备信息
-    let hardware_collector = HardwareCollector::new();
-    let hardware_info = hardware_collector.collect_info()?;
+    let Some(hardware_info) = hardware_info else {
+        log::info!("{}", MSG_NODE_CONFIGURED);
+        return start_node(config_manager.get_config(), once).await;
+    };
 
     let cpu_serial = hardware_info.cpu_serial.clone();
     let gpu_uuid = hardware_info.gpu_uuid.clone();
@@ -66,101 +134,294 @@ async fn main() -> Result<()> {
         cpu_serial: cpu_serial.clone(),
         gpu_uuid: gpu_uuid.clone(),
         system_fingerprint: system_fingerprint.clone(),
-        installation_hash: device_manager.generate_installation_hash(),
+        installation_hash: device_manager.generate_installation_hash(&config_manager.installation_id()?),
     };
+    let fingerprint = device_manager.generate_device_fingerprint(&device_info);
 
-    // 请求设备初始化
-    let init_response = device_manager
-        .init_device(
-            device_info,
-            GpuInfo {
-                model: gpu_model.as_ref().unwrap_or(&"Unknown".to_string()).to_string(),
-                memory: gpu_memory.unwrap_or(0),
-                cuda_version: cuda_version.as_ref().unwrap_or(&"Unknown".to_string()).to_string(),
-            },
-            HardwareInfo {
-                cpu_serial,
-                gpu_uuid,
-                system_fingerprint,
-                gpu_model,
-                gpu_memory,
-                cuda_version,
-                driver_version,
-            },
-        )
-        .await?;
-
-    // 保存设备码
-    config_manager.set_device_code(init_response.device_code.clone())?;
-    config_manager.set_user_code(init_response.user_code.clone())?;
-
-    // 显示验证信息
-    println!("{}", MSG_VERIFY_INSTRUCTIONS);
-    println!(
-        "{}",
-        MSG_VERIFY_URI.replace("{}", &init_response.verification_uri)
-    );
+    // 如果已经配置了访问令牌，核对设备指纹后直接启动节点
+    if !needs_registration
+        && !check_fingerprint(&mut config_manager, &fingerprint, settings.reregister_on_fingerprint_mismatch.value)?
+    {
+        log::info!("{}", MSG_NODE_CONFIGURED);
+        return start_node(config_manager.get_config(), once).await;
+    }
+
+    // 申请设备码并等待验证，设备码过期时自动重新申请
+    let capabilities = detect_capabilities(&settings).await;
+    let identity = DeviceIdentity {
+        device_info,
+        gpu_info: GpuInfo {
+            model: gpu_model.as_ref().unwrap_or(&"Unknown".to_string()).to_string(),
+            memory: gpu_memory.unwrap_or(0),
+            cuda_version: cuda_version.as_ref().unwrap_or(&"Unknown".to_string()).to_string(),
+        },
+        hardware_info: HardwareInfo {
+            cpu_serial,
+            gpu_uuid,
+            system_fingerprint,
+            gpu_model,
+            gpu_memory,
+            cuda_version,
+            driver_version,
+        },
+        network: device::network::network_info().await,
+        capabilities,
+    };
+    let registration = Registration::new(&device_manager, settings.registration_config());
+    let verified = registration
+        .run(&identity, |init_response, expires_at| {
+            // 保存设备码
+            config_manager.set_device_code(init_response.device_code.clone())?;
+            config_manager.set_user_code(init_response.user_code.clone())?;
+
+            // 显示验证信息
+            println!("{}", MSG_VERIFY_INSTRUCTIONS);
+            println!(
+                "{}",
+                MSG_VERIFY_URI.replace("{}", &init_response.verification_uri)
+            );
+            println!(
+                "{}",
+                MSG_DEVICE_CODE.replace("{}", &init_response.device_code)
+            );
+            println!(
+                "{}",
+                MSG_CODE_EXPIRY.replace("{}", &expires_at.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+            );
+            Ok(())
+        })
+        .await;
+    let response = match verified {
+        Ok(response) => response,
+        Err(e) => {
+            println!("{}", MSG_DEVICE_VERIFY_TIMEOUT);
+            return Err(e.context("Device registration did not complete"));
+        }
+    };
+
+    // 保存令牌和节点ID
+    config_manager.set_tokens(response.access_token, response.refresh_token)?;
+    config_manager.set_node_id(response.node_id.to_string())?;
+    config_manager.set_device_fingerprint(fingerprint)?;
+    println!("{}", MSG_DEVICE_VERIFY_SUCCESS);
+
+    // 启动节点
+    start_node(config_manager.get_config(), once).await
+}
+
+/// 核对已注册节点的设备指纹，返回是否需要重新注册
+///
+/// 指纹不一致说明配置被复制到了另一台机器（克隆的虚拟机或镜像），两台机器会以同一个节点上报。
+/// 旧版本注册、配置中没有指纹的节点记录当前指纹。
+fn check_fingerprint(config_manager: &mut ConfigManager, fingerprint: &str, reregister: bool) -> Result<bool> {
+    let config = config_manager.get_config();
+    let node_id = config.node_id.clone().unwrap_or_default();
+    let registered = config.device_fingerprint.clone().unwrap_or_default();
+    match device::compare_fingerprint(config.device_fingerprint.as_deref(), fingerprint) {
+        FingerprintCheck::Match => Ok(false),
+        FingerprintCheck::Unrecorded => {
+            log::info!("Recording device fingerprint of node {}", node_id);
+            config_manager.set_device_fingerprint(fingerprint.to_string())?;
+            Ok(false)
+        }
+        FingerprintCheck::Mismatch => {
+            log::error!(
+                "Device fingerprint mismatch for node {}: registered on {} but this machine is {}. \
+                 The config was probably copied from another machine (cloned VM or image); \
+                 both machines now report as the same node and its metrics will be inconsistent",
+                node_id, short_fingerprint(&registered), short_fingerprint(fingerprint)
+            );
+            if reregister {
+                log::warn!("Clearing the registration to register this machine as a new node (REREGISTER_ON_FINGERPRINT_MISMATCH)");
+                config_manager.clear_registration()?;
+                return Ok(true);
+            }
+            log::error!(
+                "Run `zkom_client reconfigure` on this machine to register it as its own node, \
+                 or set REREGISTER_ON_FINGERPRINT_MISMATCH=true to do so automatically"
+            );
+            Ok(false)
+        }
+    }
+}
+
+/// 日志中显示的指纹前缀
+fn short_fingerprint(fingerprint: &str) -> &str {
+    fingerprint.get(..12).unwrap_or(fingerprint)
+}
+
+/// 汇总节点能力（采样器、模型、扩展、脚本与限制），随设备初始化上报；SD不可达时只上报已知部分
+async fn detect_capabilities(settings: &Settings) -> Capabilities {
+    let sd = StableDiffusion::new(SDConfig {
+        base_url: settings.sd_url.value.clone(),
+        timeout: Some(settings.sd_probe_timeout_secs.value.max(1) * 1000),
+        auth: settings.sd_auth.value.clone(),
+        max_backoff_ms: None,
+        max_total_retry_ms: None,
+        // 同一服务端的客户端共享并发限制，由首个创建的客户端决定
+        max_concurrent_requests: Some(settings.sd_max_concurrent_requests.value),
+        retryable_errors: RetryableErrors::default(),
+        paths: settings.sd_paths(),
+    });
+    if let Err(e) = &sd {
+        log::warn!("Cannot query SD capabilities on {}: {:#}", settings.sd_url.value, e);
+    }
+    let capabilities = Capabilities::collect(
+        sd.as_ref().ok(),
+        settings.probe_config(),
+        settings.max_pixels.value,
+        settings.max_concurrent_tasks.value,
+    )
+    .await;
+    if let Some(scripts) = &capabilities.scripts {
+        log::info!("SD scripts available: {}", scripts.join(", "));
+    }
+    if !capabilities.missing.is_empty() {
+        log::warn!("Reporting capabilities without: {}", capabilities.missing.join(", "));
+    }
+    capabilities
+}
+
+/// 输出节点注册与配置状态
+fn print_status() -> Result<()> {
+    let config_manager = ConfigManager::new()?;
+    let config = config_manager.get_config();
+    
+    println!("Client version: {}", CLIENT_VERSION);
+    println!("Config file: {}", config_manager.config_path().display());
+    println!("Config storage: {}", config_manager.storage_mode());
+    println!("Base URL: {}", config.base_url);
     println!(
-        "{}",
-        MSG_DEVICE_CODE.replace("{}", &init_response.device_code)
+        "Installation ID: {}",
+        config.installation_id.as_deref().unwrap_or("not generated")
     );
+    let node_ids: Vec<String> = config.node_entries().into_iter().map(|entry| entry.node_id).collect();
+    if node_ids.is_empty() {
+        println!("Node ID: not registered");
+    } else {
+        println!("Node ID: {}", node_ids.join(", "));
+    }
     println!(
-        "{}",
-        MSG_CODE_EXPIRY.replace(
-            "{}",
-            &DateTime::parse_from_rfc3339(&init_response.expires_at)
-                .unwrap()
-                .with_timezone(&Utc)
-                .format("%Y-%m-%d %H:%M:%S UTC")
-                .to_string()
-        )
+        "Access token: {}",
+        if config.access_token.is_some() { "configured" } else { "missing" }
     );
-
-    // 轮询验证状态
-    let mut attempts = 0;
-    let expires_at = DateTime::parse_from_rfc3339(&init_response.expires_at)
-        .unwrap()
-        .with_timezone(&Utc);
-    let now = Utc::now();
-    let max_attempts = (expires_at - now).num_seconds() / DEVICE_VERIFY_POLL_INTERVAL as i64;
-    let user_code = init_response.user_code;
-
-    while attempts < max_attempts {
-        match device_manager.verify_device(&user_code).await {
-            Ok(response) => {
-                // 保存令牌和节点ID
-                config_manager.set_tokens(response.access_token, response.refresh_token)?;
-                config_manager.set_node_id(response.node_id.to_string())?;
-
-                println!("{}", MSG_DEVICE_VERIFY_SUCCESS);
-                break;
-            }
-            Err(e) => {
-                log::warn!("{}", e);
-                attempts += 1;
-                tokio::time::sleep(Duration::from_secs(DEVICE_VERIFY_POLL_INTERVAL)).await;
+    
+    // 最近的GPU指标来自运行中节点写入的历史文件
+    let settings = Settings::resolve(config, HardwareCollector::new().get_gpu_memory());
+    match settings.gpu_history_config().file {
+        Some(path) => match metrics::history::read_recent(&path, STATUS_GPU_HISTORY_SAMPLES) {
+            Ok(samples) if !samples.is_empty() => {
+                println!("Recent GPU metrics ({}):", path.display());
+                for sample in samples {
+                    println!(
+                        "  {}  util {:>3}%  mem {:>6} MB  temp {:>3}°C  ecc {}",
+                        sample.timestamp,
+                        reading(sample.utilization),
+                        reading(sample.memory_used),
+                        reading(sample.temperature),
+                        sample.ecc_errors.map_or("n/a".to_string(), |n| n.to_string())
+                    );
+                }
             }
-        }
+            Ok(_) => println!("Recent GPU metrics: none recorded in {}", path.display()),
+            Err(e) => println!("Recent GPU metrics: unavailable ({}: {})", path.display(), e),
+        },
+        None => println!("Recent GPU metrics: not persisted (set GPU_HISTORY_FILE)"),
     }
+    Ok(())
+}
 
-    if attempts >= max_attempts {
-        println!("{}", MSG_DEVICE_VERIFY_TIMEOUT);
+/// 历史读数的显示文本，读取失败的读数显示为 n/a
+fn reading(value: Option<impl std::fmt::Display>) -> String {
+    value.map_or("n/a".to_string(), |value| value.to_string())
+}
+
+/// 立即刷新访问令牌并保存，输出新令牌的过期时间
+async fn refresh_token() -> Result<()> {
+    let mut config_manager = ConfigManager::new()?;
+    let config = config_manager.get_config();
+    let Some(refresh_token) = config.refresh_token.clone() else {
+        anyhow::bail!("No refresh token configured; register the node first");
+    };
+    
+    let device_manager = DeviceManager::new(config.base_url.clone());
+    let response = match device_manager.refresh_token(&refresh_token).await {
+        Ok(response) => response,
+        Err(DeviceError::RefreshTokenRejected) => anyhow::bail!(
+            "Refresh token was rejected by the backend. Re-register the node: remove access_token and refresh_token from {} and start the client",
+            config_manager.config_path().display()
+        ),
+        Err(e) => return Err(e.into()),
+    };
+    
+    let rotated = response.refresh_token.is_some();
+    let expiry = device_manager.get_token_expiry(&response.access_token).ok();
+    config_manager.update_tokens(response.access_token, response.refresh_token).await?;
+    
+    println!("Access token refreshed{}", if rotated { " (refresh token rotated)" } else { "" });
+    match expiry.and_then(|expiry| DateTime::<Utc>::from_timestamp(expiry as i64, 0)) {
+        Some(expires_at) => println!(
+            "New token expires at {} (in {}s)",
+            expires_at.to_rfc3339(),
+            (expires_at - Utc::now()).num_seconds()
+        ),
+        None => println!("New token expiry unknown"),
+    }
+    println!("A running node picks up the new token on its next refresh or restart");
+    Ok(())
+}
+
+/// 清除注册信息后重新执行设备注册流程，保留 base_url 与其他配置
+async fn reconfigure(yes: bool) -> Result<()> {
+    let mut config_manager = ConfigManager::new()?;
+    let config = config_manager.get_config();
+    println!("Config file: {}", config_manager.config_path().display());
+    println!("Base URL: {}", config.base_url);
+    println!("Node ID: {}", config.node_id.as_deref().unwrap_or("not registered"));
+    if !config.nodes.is_empty() {
+        println!("Note: the `nodes` list is kept; update it by hand if node IDs change");
+    }
+
+    if !yes && !confirm("Clear the tokens and node ID and register this device again? [y/N] ")? {
+        println!("Reconfigure cancelled");
         return Ok(());
     }
+    config_manager.clear_registration()?;
+    println!("Registration cleared, registering again");
+    drop(config_manager);
 
-    // 启动节点
-    start_node(config_manager.get_config()).await
+    run(false).await
 }
 
-async fn start_node(config: &config::NodeConfig) -> Result<()> {
+/// 在终端询问确认，只有输入 y/yes 时返回真；标准输入已关闭时视为否
+fn confirm(prompt: &str) -> Result<bool> {
+    use std::io::Write;
+
+    print!("{}", prompt);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes"))
+}
+
+/// 输出有效配置及每项的来源
+fn dump_config(show_secrets: bool) -> Result<()> {
+    let config_manager = ConfigManager::new()?;
+    let gpu_memory = HardwareCollector::new().get_gpu_memory();
+    let settings = Settings::resolve(config_manager.get_config(), gpu_memory);
+    
+    println!("# config file: {} ({})", config_manager.config_path().display(), config_manager.storage_mode());
+    print!("{}", settings.dump(show_secrets));
+    Ok(())
+}
+
+async fn start_node(config: &config::NodeConfig, once: bool) -> Result<()> {
     // 确保节点已配置
-    let node_id = match &config.node_id {
-        Some(id) => id.clone(),
-        None => {
-            log::error!("节点未配置，请先初始化节点");
-            return Err(anyhow::anyhow!("节点未配置"));
-        }
-    };
+    let node_entries = config.node_entries();
+    if node_entries.is_empty() {
+        log::error!("节点未配置，请先初始化节点");
+        return Err(anyhow::anyhow!("节点未配置"));
+    }
 
     let access_token = match &config.access_token {
         Some(token) => token.clone(),
@@ -177,158 +438,296 @@ async fn start_node(config: &config::NodeConfig) -> Result<()> {
             return Err(anyhow::anyhow!("刷新令牌未配置"));
         }
     };
-    
-    // 克隆base_url以便在异步闭包中使用
-    let base_url = config.base_url.clone();
 
     println!("{}", MSG_NODE_STARTING);
-    println!("{}", MSG_NODE_ID.replace("{}", &node_id));
-
-    // 初始化设备管理器
-    let device_manager = DeviceManager::new(base_url.clone());
+    for entry in &node_entries {
+        println!("{}", MSG_NODE_ID.replace("{}", &entry.node_id));
+    }
     
-    // 初始化硬件信息收集器
-    let hardware_collector = HardwareCollector::new();
+    // 统一解析有效配置；单任务像素上限根据显存推导，可通过 MAX_PIXELS 覆盖
+    let hardware = HardwareCollector::new();
+    let gpu_memory = hardware.get_gpu_memory();
+    let settings = Settings::resolve(config, gpu_memory);
     
-    // 启动任务处理器
-    let task_config = TaskProcessorConfig {
-        nats_server: std::env::var("NATS_SERVER").unwrap_or_else(|_| NATS_SERVER_URL.to_string()),
-        sd_url: std::env::var("SD_URL").unwrap_or_else(|_| SD_API_URL.to_string()),
-        node_id: node_id.clone(),
-    };
+    // 所有节点共享的任务处理器配置
+    let default_sd_url = settings.sd_url.value.clone();
+    let base_task_config = settings.task_config(config);
     
     // 输出 NATS 相关配置信息
     log::info!("NATS configuration:");
-    log::info!("  Server URL: {}", task_config.nats_server);
-    log::info!("  Node ID: {}", task_config.node_id);
-    log::info!("  Subjects: tasks (subscribe), task_results (publish)");
+    log::info!("  Server URL: {}", base_task_config.nats_server);
+    log::info!("  Ack wait: {}s (task timeout: {}s)", base_task_config.ack_wait_secs, base_task_config.task_timeout_secs);
+    log::info!("  Max deliver: {}", base_task_config.max_deliver);
+    log::info!("  Max concurrent tasks: {}", base_task_config.max_concurrent_tasks);
+    if let Some(limit) = base_task_config.throttled_max_concurrent_tasks {
+        log::info!("  Max concurrent tasks while GPU throttling: {}", limit);
+    }
+    log::info!("  Fetch batch size: {}", base_task_config.fetch_batch_size);
+    log::info!("  Max pixels per task: {} (GPU memory: {:?} MB)", base_task_config.max_pixels, gpu_memory);
+    log::info!("  Default image size: {}×{} ({})",
+        settings.default_image_size.value, settings.default_image_size.value, settings.default_image_size.source);
+    log::info!("  SD busy check: {}", base_task_config.sd_busy_check);
+    if let Some(interval) = base_task_config.sd_keep_warm_interval_secs {
+        log::info!("  SD keep-warm: every {}s while idle", interval);
+    }
+    log::info!("  Prompt styles: {}", base_task_config.styles.len());
+    if let Some(allowed) = &base_task_config.allowed_params {
+        log::info!("  Allowed task params: {}", allowed.join(", "));
+    }
+    if let Some(suffix) = &base_task_config.global_prompt_suffix {
+        log::info!("  Global prompt suffix: {}", suffix);
+    }
+    if let Some(negative) = &base_task_config.global_negative_prompt {
+        log::info!("  Global negative prompt: {}", negative);
+    }
+    if let Some(auth) = &base_task_config.sd_auth {
+        log::info!("  SD auth: {:?}", auth);
+    }
+    log::info!("  Subjects: tasks (subscribe), {} / {} (publish completed / failed)",
+        base_task_config.result_subjects.completed, base_task_config.result_subjects.failed);
+    if let Some(subject) = &base_task_config.lifecycle_subject {
+        log::info!("  Lifecycle events: {}", subject);
+    }
     
-    // 创建任务处理器
-    log::info!("Initializing task processor with NATS server: {}", task_config.nats_server);
-    let task_processor = TaskProcessor::new(task_config).await?;
+    if once {
+        return run_once(&node_entries, &default_sd_url, base_task_config).await;
+    }
+    let mut base_task_config = base_task_config;
+    base_task_config.image_cache = open_image_cache(&settings);
     
-    // 启动心跳和任务处理
-    let heartbeat_handle = tokio::spawn(async move {
-        // 设置心跳间隔（秒）
-        let heartbeat_interval = HEARTBEAT_INTERVAL_SECONDS;
-        
-        log::info!("Starting heartbeat reporting, interval: {} seconds", heartbeat_interval);
+    // 收到停机信号（或空闲超时）时通知所有任务处理器停止接收任务并排空进行中的任务
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let idle_exit = Arc::new(AtomicBool::new(false));
+    
+    // 启动事件中上报的硬件概要
+    let summary = NodeSummary {
+        gpu_model: hardware.get_gpu_model(),
+        gpu_memory_mb: gpu_memory,
+        ..Default::default()
+    };
+    
+    // 为每个逻辑节点创建任务处理器
+    let mut task_handles = Vec::with_capacity(node_entries.len());
+    let mut node_loads = Vec::with_capacity(node_entries.len());
+    let mut processors = Vec::with_capacity(node_entries.len());
+    for entry in &node_entries {
+        let task_config = node_task_config(entry, &node_entries, &default_sd_url, &base_task_config);
         
-        // 初始化访问令牌
-        let mut current_access_token = access_token;
-        let current_refresh_token = refresh_token;
+        log::info!(
+            "Initializing task processor for node {} (SD: {}, consumer: {})",
+            task_config.node_id, task_config.sd_url, task_config.consumer_name
+        );
+        let task_processor = Arc::new(TaskProcessor::new(task_config).await?);
+        node_loads.push(NodeLoad {
+            node_id: entry.node_id.clone(),
+            max_concurrent_tasks: base_task_config.max_concurrent_tasks,
+            in_flight: task_processor.in_flight(),
+            gpu_index: entry.gpu_index,
+            throttled: task_processor.throttled(),
+        });
+        processors.push(Arc::clone(&task_processor));
         
-        // 创建配置管理器，处理错误而不是传播
-        let mut config_manager = match ConfigManager::new() {
-            Ok(cm) => cm,
-            Err(e) => {
-                log::error!("Failed to create config manager: {}", e);
-                return;
-            }
-        };
-        
-        loop {
-            // 检查令牌是否即将过期，如果是，则刷新
-            if let Ok(should_refresh) = device_manager.should_refresh_token(&current_access_token, TOKEN_REFRESH_THRESHOLD_SECONDS) {
-                if should_refresh {
-                    log::info!("Access token about to expire, starting active refresh");
-                    
-                    // 尝试刷新令牌
-                    match device_manager.refresh_token(&current_refresh_token).await {
-                        Ok(refresh_response) => {
-                            log::info!("Token refresh successful");
-                            
-                            // 更新当前使用的令牌
-                            current_access_token = refresh_response.access_token.clone();
-                            
-                            // 保存新的访问令牌到配置
-                            if let Err(save_err) = config_manager.update_access_token(refresh_response.access_token) {
-                                log::error!("Failed to save new access token: {}", save_err);
-                            }
-                        }
-                        Err(refresh_err) => {
-                            log::error!("Active token refresh failed: {}", refresh_err);
-                        }
-                    }
-                }
-            } else {
-                log::warn!("Unable to parse token expiry, will refresh on 401 error");
-            }
-            
-            // 收集GPU指标
-            match hardware_collector.collect_gpu_metrics() {
-                Ok(gpu_metrics) => {
-                    // 转换为设备指标
-                    let device_metrics = DeviceMetrics {
-                        gpu_utilization: gpu_metrics.utilization,
-                        gpu_memory_used: gpu_metrics.memory_used,
-                        gpu_temperature: gpu_metrics.temperature,
-                        timestamp: gpu_metrics.timestamp,
-                    };
-                    
-                    // 发送心跳
-                match device_manager.send_heartbeat(&node_id, device_metrics, &current_access_token).await {
-                    Ok(response) => {
-                        log::debug!("Heartbeat sent successfully: {}", response.message);
-                    }
-                    Err(e) => {
-                        // 检查是否是授权错误 (假设401状态码导致了特定的错误信息)
-                        if e.to_string().contains("401") {
-                            log::warn!("Access token expired, attempting to refresh token");
-                            
-                            // 尝试刷新令牌
-                            match device_manager.refresh_token(&current_refresh_token).await {
-                                Ok(refresh_response) => {
-                                    log::info!("Token refresh successful");
-                                    
-                                    // 更新当前使用的令牌
-                                    current_access_token = refresh_response.access_token.clone();
-                                    
-                                    // 保存新的访问令牌到配置
-                                    if let Err(save_err) = config_manager.update_access_token(refresh_response.access_token) {
-                                        log::error!("Failed to save new access token: {}", save_err);
-                                    }
-                                }
-                                Err(refresh_err) => {
-                                    log::error!("Token refresh failed: {}", refresh_err);
-                                }
-                            }
-                            } else {
-                                log::error!("Failed to send heartbeat: {}", e);
-                        }
-                    }
-                    }
-                }
+        // 启动任务处理
+        let node_id = entry.node_id.clone();
+        let shutdown = shutdown_rx.clone();
+        let summary = summary.clone();
+        let idle_exit = Arc::clone(&idle_exit);
+        task_handles.push(tokio::spawn(async move {
+            log::info!("Starting NATS task processor for node {}", node_id);
+            task_processor.announce_started(summary).await;
+            let reason = match Arc::clone(&task_processor).start_processing(shutdown.clone()).await {
+                Ok(()) if idle_exit.load(Ordering::SeqCst) => StopReason::Idle,
+                Ok(()) if *shutdown.borrow() => StopReason::Signal,
+                Ok(()) => StopReason::Drain,
                 Err(e) => {
-                    log::error!("Failed to collect GPU metrics: {}", e);
+                    log::error!("Task processor error for node {}: {:?}", node_id, e);
+                    log::debug!("NATS task processor error details: {:?}", e);
+                    StopReason::Error
                 }
+            };
+            task_processor.announce_stopped(reason).await;
+        }));
+    }
+    
+    // 运行指标，设置 METRICS_ADDR 时通过HTTP暴露
+    let metrics = Arc::new(Metrics::new(settings.gpu_history_config()));
+    if let Some(addr) = settings.metrics_addr.value.clone() {
+        match addr.parse() {
+            Ok(addr) => {
+                let metrics = Arc::clone(&metrics);
+                let image_cache = base_task_config.image_cache.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = metrics::serve(addr, metrics, image_cache).await {
+                        log::error!("Metrics server error: {:?}", e);
+                    }
+                });
             }
-            
-            // 等待下一次心跳
-            tokio::time::sleep(Duration::from_secs(heartbeat_interval)).await;
+            Err(e) => log::error!("Invalid METRICS_ADDR '{}': {}", addr, e),
         }
-    });
+    }
     
-    // 启动任务处理
-    let task_handle = tokio::spawn(async move {
-        log::info!("Starting NATS task processor");
-        if let Err(e) = task_processor.start_processing().await {
-            log::error!("Task processor error: {:?}", e);
-            log::debug!("NATS task processor error details: {:?}", e);
-        }
+    // 启动心跳
+    let heartbeat = HeartbeatService::new(
+        config.base_url.clone(),
+        node_loads,
+        access_token,
+        refresh_token,
+        settings.heartbeat_config(),
+        Arc::clone(&metrics),
+    );
+    let heartbeat_handle = tokio::spawn(heartbeat.run());
+    let heartbeat_abort = heartbeat_handle.abort_handle();
+    
+    // 所有节点空闲超过时限时走与停机信号相同的排空流程
+    if let Some(idle_exit_secs) = settings.idle_exit_secs.value.filter(|&secs| secs > 0) {
+        log::info!("Idle exit after {}s without tasks", idle_exit_secs);
+        tokio::spawn(idle_watch(
+            processors,
+            Duration::from_secs(idle_exit_secs),
+            shutdown_tx.clone(),
+            Arc::clone(&idle_exit),
+        ));
+    }
+    
+    let shutdown_grace_secs = base_task_config.shutdown_grace_secs;
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        log::info!("Shutdown signal received, draining in-flight tasks (grace period {}s)", shutdown_grace_secs);
+        let _ = shutdown_tx.send(true);
     });
     
     log::info!("NATS task processor and heartbeat services started");
     
-    // 等待任务结束
-    let _ = tokio::try_join!(
+    // 等待任务结束（心跳因版本不受支持而退出时停止节点）；停机时任务处理器排空后停止心跳
+    tokio::try_join!(
         async { 
-            heartbeat_handle.await.map_err(|e| anyhow::anyhow!("Heartbeat processing error: {:?}", e)) 
+            match heartbeat_handle.await {
+                Err(e) if e.is_cancelled() => Ok(()),
+                result => result.map_err(|e| anyhow::anyhow!("Heartbeat processing error: {:?}", e))?,
+            }
         },
         async { 
-            task_handle.await.map_err(|e| anyhow::anyhow!("Task processing error: {:?}", e)) 
+            futures::future::try_join_all(task_handles)
+                .await
+                .map_err(|e| anyhow::anyhow!("Task processing error: {:?}", e))?;
+            if *shutdown_rx.borrow() {
+                heartbeat_abort.abort();
+                log::info!("All task processors stopped, exiting");
+            }
+            Ok(())
+        }
+    )?;
+    
+    if idle_exit.load(Ordering::SeqCst) {
+        return Err(IdleExit(settings.idle_exit_secs.value.unwrap_or_default()).into());
+    }
+    Ok(())
+}
+
+/// 所有节点都没有进行中的任务，且超过 `idle_exit` 未收到或完成任务时触发停机
+async fn idle_watch(
+    processors: Vec<Arc<TaskProcessor>>,
+    idle_exit: Duration,
+    shutdown: watch::Sender<bool>,
+    idle: Arc<AtomicBool>,
+) {
+    let check_interval = Duration::from_secs(IDLE_CHECK_INTERVAL_SECONDS).min(idle_exit);
+    let mut ticker = tokio::time::interval(check_interval);
+    loop {
+        ticker.tick().await;
+        // 任一节点有任务在处理时为 None
+        let idle_for = processors.iter().map(|processor| processor.idle_for()).min().flatten();
+        if let Some(idle_for) = idle_for
+            && idle_for >= idle_exit
+        {
+            log::info!("No tasks for {}s, shutting down idle node", idle_for.as_secs());
+            idle.store(true, Ordering::SeqCst);
+            let _ = shutdown.send(true);
+            return;
         }
+    }
+}
+
+/// 等待 SIGTERM 或 Ctrl-C
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = sigterm.recv() => {}
+                    _ = tokio::signal::ctrl_c() => {}
+                }
+                return;
+            }
+            Err(e) => log::warn!("Failed to install SIGTERM handler, only Ctrl-C triggers shutdown: {:?}", e),
+        }
+    }
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        log::error!("Failed to listen for Ctrl-C: {:?}", e);
+        std::future::pending::<()>().await;
+    }
+}
+
+/// 打开本地图片缓存；未配置、未启用指标服务（缓存经其对外提供）或目录不可用时不启用
+fn open_image_cache(settings: &Settings) -> Option<Arc<ImageCache>> {
+    settings.image_cache_dir.value.as_ref()?;
+    let Some(config) = settings.image_cache_config() else {
+        log::error!("IMAGE_CACHE_DIR is set but METRICS_ADDR is not; the local image cache is served by the metrics server and stays disabled");
+        return None;
+    };
+    match ImageCache::open(config) {
+        Ok(cache) => Some(Arc::new(cache)),
+        Err(e) => {
+            log::error!("Failed to open the local image cache, tasks with local_cache will fail: {:#}", e);
+            None
+        }
+    }
+}
+
+/// 逻辑节点的任务处理器配置
+fn node_task_config(
+    entry: &config::NodeEntry,
+    node_entries: &[config::NodeEntry],
+    default_sd_url: &str,
+    base_task_config: &TaskProcessorConfig,
+) -> TaskProcessorConfig {
+    let consumer_name = entry.consumer_name();
+    TaskProcessorConfig {
+        node_id: entry.node_id.clone(),
+        sd_url: entry.sd_url.clone().unwrap_or_else(|| default_sd_url.to_string()),
+        shared_consumer: node_entries
+            .iter()
+            .any(|other| other.node_id != entry.node_id && other.consumer_name() == consumer_name),
+        consumer_name,
+        ..base_task_config.clone()
+    }
+}
+
+/// 单任务模式：为第一个逻辑节点处理一个任务后退出，任务失败时返回错误（非零退出码）
+///
+/// 只处理发给该节点的任务，其他节点的任务延迟后重新投递。不启动心跳与指标服务，用于新部署节点的冒烟测试。
+async fn run_once(
+    node_entries: &[config::NodeEntry],
+    default_sd_url: &str,
+    base_task_config: TaskProcessorConfig,
+) -> Result<()> {
+    let entry = &node_entries[0];
+    if node_entries.len() > 1 {
+        log::warn!("--once only processes a task for the first node ({})", entry.node_id);
+    }
+    
+    let task_config = node_task_config(entry, node_entries, default_sd_url, &base_task_config);
+    log::info!(
+        "Processing a single task for node {} (SD: {}, consumer: {})",
+        task_config.node_id, task_config.sd_url, task_config.consumer_name
     );
+    let task_processor = Arc::new(TaskProcessor::new(task_config).await?);
     
+    if !task_processor.process_one().await? {
+        anyhow::bail!("Task failed");
+    }
+    log::info!("Task completed, exiting");
     Ok(())
 }
@@ -0,0 +1,98 @@
+use crate::device::hardware::GpuMetrics;
+use anyhow::Result;
+use std::collections::VecDeque;
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// GPU指标历史持久化配置
+#[derive(Debug, Clone)]
+pub struct GpuHistoryConfig {
+    /// Samples kept in memory
+    pub capacity: usize,
+    /// JSONL file samples are appended to; in-memory only when unset
+    pub file: Option<PathBuf>,
+    /// File size after which it is rotated to `<file>.1`
+    pub max_file_bytes: u64,
+}
+
+/// 最近的GPU指标采样
+///
+/// 内存中保留固定数量的样本，并可追加写入本地JSONL文件，
+/// 即使心跳未送达后端也能保留现场数据用于事后分析。
+#[derive(Debug)]
+pub struct GpuHistory {
+    config: GpuHistoryConfig,
+    samples: Mutex<VecDeque<GpuMetrics>>,
+}
+
+impl GpuHistory {
+    pub fn new(config: GpuHistoryConfig) -> Self {
+        Self {
+            samples: Mutex::new(VecDeque::with_capacity(config.capacity)),
+            config,
+        }
+    }
+
+    /// 记录一次采样，超出容量时丢弃最旧的样本
+    pub fn record(&self, sample: GpuMetrics) {
+        if let Some(path) = &self.config.file
+            && let Err(e) = append(path, &sample, self.config.max_file_bytes)
+        {
+            log::warn!("Failed to write GPU metrics history to {:?}: {}", path, e);
+        }
+
+        let mut samples = self.samples.lock().unwrap_or_else(|e| e.into_inner());
+        if self.config.capacity == 0 {
+            return;
+        }
+        while samples.len() >= self.config.capacity {
+            samples.pop_front();
+        }
+        samples.push_back(sample);
+    }
+
+    /// 按时间顺序返回内存中的样本
+    pub fn recent(&self) -> Vec<GpuMetrics> {
+        let samples = self.samples.lock().unwrap_or_else(|e| e.into_inner());
+        samples.iter().cloned().collect()
+    }
+}
+
+/// 追加一条样本，文件超过上限时先轮转
+fn append(path: &Path, sample: &GpuMetrics, max_file_bytes: u64) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if let Ok(metadata) = fs::metadata(path)
+        && metadata.len() >= max_file_bytes
+    {
+        let mut rotated = path.as_os_str().to_owned();
+        rotated.push(".1");
+        fs::rename(path, rotated)?;
+    }
+
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", serde_json::to_string(sample)?)?;
+    Ok(())
+}
+
+/// 读取历史文件中最近的 `limit` 条样本，供其他进程（如 status 命令）使用
+pub fn read_recent(path: &Path, limit: usize) -> Result<Vec<GpuMetrics>> {
+    if limit == 0 {
+        return Ok(Vec::new());
+    }
+    let file = fs::File::open(path)?;
+    let mut samples = VecDeque::with_capacity(limit);
+    for line in BufReader::new(file).lines() {
+        let Ok(sample) = serde_json::from_str::<GpuMetrics>(&line?) else {
+            continue;
+        };
+        if samples.len() == limit {
+            samples.pop_front();
+        }
+        samples.push_back(sample);
+    }
+    Ok(samples.into())
+}
@@ -0,0 +1,137 @@
+use crate::upload::ImageCache;
+use anyhow::Result;
+use history::{GpuHistory, GpuHistoryConfig};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use std::convert::Infallible;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+
+pub mod history;
+
+/// 节点运行指标
+///
+/// 即使未启用指标服务也会更新，供日志和状态输出使用。
+#[derive(Debug)]
+pub struct Metrics {
+    pub token_refresh_success: AtomicU64,
+    pub token_refresh_failure: AtomicU64,
+    /// Seconds until the access token expires, negative once expired
+    pub token_expires_in_secs: AtomicI64,
+    /// Whether `token_expires_in_secs` holds a value
+    pub token_expiry_known: AtomicBool,
+    /// Recent GPU samples, served as JSON on `/gpu-history`
+    pub gpu_history: GpuHistory,
+}
+
+impl Metrics {
+    pub fn new(gpu_history: GpuHistoryConfig) -> Self {
+        Self {
+            token_refresh_success: AtomicU64::new(0),
+            token_refresh_failure: AtomicU64::new(0),
+            token_expires_in_secs: AtomicI64::new(0),
+            token_expiry_known: AtomicBool::new(false),
+            gpu_history: GpuHistory::new(gpu_history),
+        }
+    }
+
+    pub fn record_token_refresh(&self, success: bool) {
+        if success {
+            self.token_refresh_success.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.token_refresh_failure.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn set_token_expires_in(&self, seconds: Option<i64>) {
+        self.token_expires_in_secs.store(seconds.unwrap_or(0), Ordering::Relaxed);
+        self.token_expiry_known.store(seconds.is_some(), Ordering::Relaxed);
+    }
+
+    /// Prometheus 文本格式
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "# HELP zkom_token_refresh_total Access token refresh attempts by result");
+        let _ = writeln!(out, "# TYPE zkom_token_refresh_total counter");
+        let _ = writeln!(
+            out,
+            "zkom_token_refresh_total{{result=\"success\"}} {}",
+            self.token_refresh_success.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "zkom_token_refresh_total{{result=\"failure\"}} {}",
+            self.token_refresh_failure.load(Ordering::Relaxed)
+        );
+        if self.token_expiry_known.load(Ordering::Relaxed) {
+            let _ = writeln!(out, "# HELP zkom_token_expires_in_seconds Seconds until the access token expires");
+            let _ = writeln!(out, "# TYPE zkom_token_expires_in_seconds gauge");
+            let _ = writeln!(
+                out,
+                "zkom_token_expires_in_seconds {}",
+                self.token_expires_in_secs.load(Ordering::Relaxed)
+            );
+        }
+        out
+    }
+}
+
+/// 启动指标HTTP服务（`/metrics`、`/gpu-history` 与 `/health`），启用本地图片缓存时
+/// 同时提供 `/images/<sha256>.<ext>`
+pub async fn serve(addr: SocketAddr, metrics: Arc<Metrics>, image_cache: Option<Arc<ImageCache>>) -> Result<()> {
+    let make_service = make_service_fn(move |_| {
+        let metrics = Arc::clone(&metrics);
+        let image_cache = image_cache.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let metrics = Arc::clone(&metrics);
+                let image_cache = image_cache.clone();
+                async move { Ok::<_, Infallible>(handle(request, &metrics, image_cache.as_deref()).await) }
+            }))
+        }
+    });
+
+    let server = Server::try_bind(&addr)?.serve(make_service);
+    log::info!("Metrics server listening on http://{}", addr);
+    server.await?;
+    Ok(())
+}
+
+async fn handle(request: Request<Body>, metrics: &Metrics, image_cache: Option<&ImageCache>) -> Response<Body> {
+    if let (&Method::GET, Some(name), Some(cache)) =
+        (request.method(), request.uri().path().strip_prefix("/images/"), image_cache)
+    {
+        return match cache.get(name).await {
+            // 内容寻址，同名图片内容不会变化
+            Some((data, content_type)) => Response::builder()
+                .header("Content-Type", content_type)
+                .header("Cache-Control", "public, max-age=31536000, immutable")
+                .body(Body::from(data))
+                .unwrap_or_default(),
+            None => Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .unwrap_or_default(),
+        };
+    }
+
+    match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => Response::builder()
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .body(Body::from(metrics.render()))
+            .unwrap_or_default(),
+        (&Method::GET, "/gpu-history") => Response::builder()
+            .header("Content-Type", "application/json")
+            .body(Body::from(
+                serde_json::to_string(&metrics.gpu_history.recent()).unwrap_or_default(),
+            ))
+            .unwrap_or_default(),
+        (&Method::GET, "/health") => Response::new(Body::from("ok")),
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .unwrap_or_default(),
+    }
+}
@@ -8,12 +8,44 @@ impl RuntimeChecker {
         Self
     }
 
+    /// 并行执行各项检查；多项失败时返回 CUDA 检查的错误，其余记录到日志
     pub fn check_environment(&self) -> Result<()> {
-        self.check_cuda()?;
-        self.check_docker()?;
+        let (cuda, docker) = std::thread::scope(|s| {
+            let cuda = s.spawn(|| self.check_cuda());
+            let docker = s.spawn(|| self.check_docker());
+            (join_check(cuda, "CUDA"), join_check(docker, "Docker"))
+        });
+        if let (Err(_), Err(e)) = (&cuda, &docker) {
+            log::error!("{:#}", e);
+        }
+        cuda?;
+        docker?;
         Ok(())
     }
 
+    /// 检查显存是否满足最低要求，`required_mb` 为 0 时不检查
+    ///
+    /// 无法检测显存时只记录警告，由 CUDA 检查负责发现驱动问题。
+    pub fn check_gpu_memory(&self, detected_mb: Option<u64>, required_mb: u64) -> Result<()> {
+        if required_mb == 0 {
+            return Ok(());
+        }
+        match detected_mb {
+            Some(detected) if detected < required_mb => anyhow::bail!(
+                "GPU memory check failed: detected {} MB, at least {} MB required (set MIN_GPU_MEMORY_MB or SKIP_GPU_MEMORY_CHECK=true to override)",
+                detected, required_mb
+            ),
+            Some(detected) => {
+                log::info!("GPU memory check passed ({} MB, required {} MB)", detected, required_mb);
+                Ok(())
+            }
+            None => {
+                log::warn!("Unable to detect GPU memory, skipping the {} MB minimum check", required_mb);
+                Ok(())
+            }
+        }
+    }
+
     fn check_cuda(&self) -> Result<()> {
         // Check if nvidia-smi is available
         let output = Command::new("nvidia-smi")
@@ -42,4 +74,10 @@ impl RuntimeChecker {
         log::info!("Docker environment check passed");
         Ok(())
     }
-} 
\ No newline at end of file
+}
+
+fn join_check(handle: std::thread::ScopedJoinHandle<'_, Result<()>>, name: &str) -> Result<()> {
+    handle
+        .join()
+        .unwrap_or_else(|_| Err(anyhow::anyhow!("{} environment check panicked", name)))
+}
@@ -1,7 +1,23 @@
 use anyhow::Result;
-use reqwest::{Client, ClientBuilder, Url};
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use reqwest::header::{AUTHORIZATION, HeaderMap, HeaderValue};
+use reqwest::{Client, ClientBuilder, Response, Url};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Default width/height used when a request doesn't specify one
+pub const DEFAULT_IMAGE_SIZE: u32 = 512;
+
+/// Request timeout used when `SDConfig.timeout` is unset (2 minutes)
+const DEFAULT_TIMEOUT_MS: u64 = 120000;
+
+/// Error body substrings always treated as transient
+const BUILTIN_RETRYABLE_ERRORS: &[&str] = &["'NoneType' object", "CUDA out of memory", "expected scalar type"];
 
 /// Configuration for Stable Diffusion API client
 #[derive(Debug, Clone)]
@@ -10,6 +26,198 @@ pub struct SDConfig {
     pub base_url: String,
     /// Timeout in milliseconds (defaults to 120000 - 2 minutes)
     pub timeout: Option<u64>,
+    /// Authentication sent with every request, for servers behind an auth proxy
+    pub auth: Option<SDAuth>,
+    /// Upper bound for a single retry backoff in milliseconds (uncapped when unset)
+    pub max_backoff_ms: Option<u64>,
+    /// Total time budget for retrying a request in milliseconds, measured from the
+    /// first attempt; no further retry starts once it would be exceeded (unbounded when unset)
+    pub max_total_retry_ms: Option<u64>,
+    /// Max generation requests in flight toward this server (defaults to 1). Shared by
+    /// all clients with the same `base_url`; the first client created sets the limit and
+    /// a later client asking for a different one logs a warning. Only txt2img, options and
+    /// png-info take a slot; ping, progress and the model/LoRA/script/sampler/extension
+    /// lists are exempt so status queries aren't stuck behind a running generation
+    pub max_concurrent_requests: Option<usize>,
+    /// Error responses retried besides HTTP 5xx
+    pub retryable_errors: RetryableErrors,
+    /// Endpoint paths appended to `base_url`
+    pub paths: SDPaths,
+}
+
+/// Endpoint paths of the Stable Diffusion API, relative to `SDConfig.base_url`
+///
+/// Defaults are the Automatic1111 API paths. Override them for other backends
+/// (e.g. a ComfyUI shim) in the config file's `sd_paths` section or with the
+/// `SD_PATH_<NAME>` environment variables, e.g. `SD_PATH_TXT2IMG=/api/generate`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SDPaths {
+    /// Text-to-image generation (`POST`)
+    pub txt2img: String,
+    /// Server options, read by keep-warm pings and set at startup (`GET`/`POST`)
+    pub options: String,
+    /// Generation parameters embedded in a PNG (`POST`)
+    pub png_info: String,
+    /// Available checkpoints (`GET`)
+    pub sd_models: String,
+    /// Available LoRAs (`GET`)
+    pub loras: String,
+    /// Installed scripts (`GET`)
+    pub scripts: String,
+    /// Available samplers (`GET`)
+    pub samplers: String,
+    /// Installed extensions (`GET`)
+    pub extensions: String,
+    /// Current job progress (`GET`)
+    pub progress: String,
+}
+
+impl Default for SDPaths {
+    fn default() -> Self {
+        Self {
+            txt2img: "/sdapi/v1/txt2img".to_string(),
+            options: "/sdapi/v1/options".to_string(),
+            png_info: "/sdapi/v1/png-info".to_string(),
+            sd_models: "/sdapi/v1/sd-models".to_string(),
+            loras: "/sdapi/v1/loras".to_string(),
+            scripts: "/sdapi/v1/scripts".to_string(),
+            samplers: "/sdapi/v1/samplers".to_string(),
+            extensions: "/sdapi/v1/extensions".to_string(),
+            progress: "/sdapi/v1/progress".to_string(),
+        }
+    }
+}
+
+impl SDPaths {
+    /// 各端点的名称与路径，名称对应 `SD_PATH_<NAME>` 环境变量
+    pub fn entries(&self) -> [(&'static str, &str); 9] {
+        [
+            ("txt2img", &self.txt2img),
+            ("options", &self.options),
+            ("png_info", &self.png_info),
+            ("sd_models", &self.sd_models),
+            ("loras", &self.loras),
+            ("scripts", &self.scripts),
+            ("samplers", &self.samplers),
+            ("extensions", &self.extensions),
+            ("progress", &self.progress),
+        ]
+    }
+
+    /// 拼接并校验端点URL：路径须以 `/` 开头，拼接结果须为合法的 http(s) URL
+    fn url(base_url: &str, name: &str, path: &str) -> Result<Url> {
+        if !path.starts_with('/') {
+            anyhow::bail!("SD {} path '{}' must start with '/'", name, path);
+        }
+        let url = Url::parse(&format!("{}{}", base_url, path))
+            .map_err(|e| anyhow::anyhow!("Invalid SD {} URL '{}{}': {}", name, base_url, path, e))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            anyhow::bail!("SD {} URL '{}' is not http(s)", name, url);
+        }
+        Ok(url)
+    }
+
+    /// 校验所有端点，配置错误时在启动阶段而不是首个任务时报错
+    fn validate(&self, base_url: &str) -> Result<()> {
+        for (name, path) in self.entries() {
+            Self::url(base_url, name, path)?;
+        }
+        Ok(())
+    }
+}
+
+/// 判定SD错误响应是否为临时性故障
+///
+/// 内置规则之外，可通过配置追加不同 SD 分支或扩展特有的错误信息。
+#[derive(Debug, Clone)]
+pub struct RetryableErrors {
+    substrings: Vec<String>,
+    ignore_case: bool,
+}
+
+impl RetryableErrors {
+    /// 内置规则加上 `extra`；`ignore_case` 为真时忽略大小写匹配
+    pub fn new(extra: &[String], ignore_case: bool) -> Self {
+        let substrings = BUILTIN_RETRYABLE_ERRORS
+            .iter()
+            .map(|s| s.to_string())
+            .chain(extra.iter().map(|s| s.trim().to_string()).filter(|s| !s.is_empty()))
+            .map(|s| if ignore_case { s.to_lowercase() } else { s })
+            .collect();
+        Self { substrings, ignore_case }
+    }
+
+    /// 错误响应内容是否包含任一可重试的子串
+    pub fn matches(&self, body: &str) -> bool {
+        if self.ignore_case {
+            let body = body.to_lowercase();
+            self.substrings.iter().any(|s| body.contains(s.as_str()))
+        } else {
+            self.substrings.iter().any(|s| body.contains(s.as_str()))
+        }
+    }
+}
+
+impl Default for RetryableErrors {
+    fn default() -> Self {
+        Self::new(&[], false)
+    }
+}
+
+/// Authentication for the Stable Diffusion API
+#[derive(Clone)]
+pub enum SDAuth {
+    /// `Authorization: Bearer <token>`
+    Bearer(String),
+    /// HTTP basic authentication
+    Basic { username: String, password: String },
+}
+
+impl SDAuth {
+    /// Read auth from `SD_API_KEY`, falling back to `SD_USERNAME`/`SD_PASSWORD`
+    pub fn from_env() -> Option<Self> {
+        if let Ok(token) = std::env::var("SD_API_KEY") {
+            return Some(Self::Bearer(token));
+        }
+        match (std::env::var("SD_USERNAME"), std::env::var("SD_PASSWORD")) {
+            (Ok(username), Ok(password)) => Some(Self::Basic { username, password }),
+            _ => None,
+        }
+    }
+
+    fn header_value(&self) -> Result<HeaderValue> {
+        let value = match self {
+            Self::Bearer(token) => format!("Bearer {}", token),
+            Self::Basic { username, password } => {
+                format!("Basic {}", BASE64.encode(format!("{}:{}", username, password)))
+            }
+        };
+        let mut header = HeaderValue::from_str(&value)?;
+        // 防止在调试日志中输出凭据
+        header.set_sensitive(true);
+        Ok(header)
+    }
+}
+
+impl fmt::Debug for SDAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bearer(_) => f.write_str("Bearer(<redacted>)"),
+            Self::Basic { username, .. } => write!(f, "Basic {{ username: {:?}, password: <redacted> }}", username),
+        }
+    }
+}
+
+/// Errors returned by the Stable Diffusion API
+#[derive(Debug, thiserror::Error)]
+pub enum SDError {
+    /// Non-success HTTP response
+    #[error("Stable Diffusion API request failed: HTTP {status}: {body}")]
+    Api { status: u16, body: String },
+    /// Request could not be sent or its response body could not be read or decoded
+    #[error("Stable Diffusion API request failed: {0}")]
+    Request(#[source] reqwest::Error),
 }
 
 /// Parameters for text-to-image generation
@@ -35,6 +243,118 @@ pub struct TextToImageParams {
     /// Random seed (-1 for random)
     #[serde(skip_serializing_if = "Option::is_none")]
     pub seed: Option<i64>,
+    /// Variation seed blended into the main seed (-1 for random)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subseed: Option<i64>,
+    /// How strongly the variation seed is blended in (0.0 - 1.0)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subseed_strength: Option<f32>,
+    /// Number of images generated in one batch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub batch_size: Option<u32>,
+    /// Deadline for this task in milliseconds, covering all retries; replaces the client
+    /// timeout, which otherwise limits each attempt separately
+    #[serde(skip)]
+    pub timeout_ms: Option<u64>,
+    /// SDXL refiner checkpoint (title or model name). Requires an SDXL-capable server
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refiner_checkpoint: Option<String>,
+    /// Fraction of sampling steps after which generation switches to the refiner (0.0 - 1.0)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refiner_switch_at: Option<f32>,
+    /// Selectable script to run instead of plain txt2img, e.g. `x/y/z plot`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub script_name: Option<String>,
+    /// Positional arguments for `script_name`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub script_args: Vec<serde_json::Value>,
+    /// Always-on scripts (extensions such as ControlNet or ADetailer) and their `args`
+    #[serde(skip_serializing_if = "serde_json::Map::is_empty")]
+    pub alwayson_scripts: serde_json::Map<String, serde_json::Value>,
+    /// Whether failed requests are retried
+    #[serde(skip)]
+    pub retry: RetryPolicy,
+}
+
+/// Retry policy for generation requests
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RetryPolicy {
+    /// Retry transient failures with exponential backoff
+    #[default]
+    Retry,
+    /// Send the request once and report its failure directly, for callers that
+    /// treat generation as non-idempotent (e.g. metered server-side)
+    SingleAttempt,
+}
+
+/// Checkpoint entry returned by the models endpoint
+#[derive(Debug, Clone, Deserialize)]
+pub struct SDModel {
+    /// Display title, e.g. `sd_xl_refiner_1.0.safetensors [7440042bbd]`
+    pub title: String,
+    /// Model name without extension or hash
+    pub model_name: String,
+}
+
+impl SDModel {
+    /// Whether `name` refers to this checkpoint, by title or model name
+    pub fn matches(&self, name: &str) -> bool {
+        self.title == name || self.model_name == name
+    }
+}
+
+/// LoRA entry returned by the loras endpoint
+#[derive(Debug, Clone, Deserialize)]
+pub struct SDLora {
+    /// File name without extension, as used in `<lora:name:weight>`
+    pub name: String,
+    /// Alias from the LoRA metadata, also accepted in prompts
+    #[serde(default)]
+    pub alias: Option<String>,
+}
+
+impl SDLora {
+    /// Whether `name` refers to this LoRA, by name or alias
+    pub fn matches(&self, name: &str) -> bool {
+        self.name == name || self.alias.as_deref() == Some(name)
+    }
+}
+
+/// Sampler entry returned by the samplers endpoint
+#[derive(Debug, Clone, Deserialize)]
+pub struct SDSampler {
+    /// Name accepted as `sampler_name`, e.g. `DPM++ 2M`
+    pub name: String,
+}
+
+/// Extension entry returned by the extensions endpoint
+#[derive(Debug, Clone, Deserialize)]
+pub struct SDExtension {
+    pub name: String,
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Scripts installed on the server, as returned by the scripts endpoint.
+/// Names are lowercase; extensions that hook into generation appear here as always-on scripts
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SDScripts {
+    #[serde(default)]
+    pub txt2img: Vec<String>,
+    #[serde(default)]
+    #[allow(dead_code)]
+    pub img2img: Vec<String>,
+}
+
+impl SDScripts {
+    /// Whether a txt2img script (or always-on extension) named `name` is installed
+    pub fn has_txt2img(&self, name: &str) -> bool {
+        self.txt2img.iter().any(|script| script.eq_ignore_ascii_case(name.trim()))
+    }
 }
 
 /// Response from the image generation API
@@ -43,9 +363,49 @@ pub struct ImageResponse {
     /// Array of base64-encoded images
     pub images: Vec<String>,
     /// Parameters used for generation
+    #[allow(dead_code)]
     pub parameters: serde_json::Value,
-    /// Additional information
+    /// Additional information (JSON string, includes per-image `infotexts`)
     pub info: String,
+    /// Times the SD server restarted mid-request and the job was resubmitted
+    #[serde(skip)]
+    pub restarts: u32,
+}
+
+/// Response from the png-info endpoint
+#[derive(Debug, Clone, Deserialize)]
+struct PngInfoResponse {
+    /// Generation parameters embedded in the image, empty when none
+    #[serde(default)]
+    info: String,
+}
+
+/// Response from the progress endpoint
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProgressResponse {
+    /// Progress of the current job (0.0 - 1.0)
+    #[serde(default)]
+    pub progress: f64,
+    /// Server-side job state
+    pub state: ProgressState,
+}
+
+/// Job state reported by the progress endpoint
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProgressState {
+    /// Number of jobs queued or running on the server
+    #[serde(default)]
+    pub job_count: i64,
+    /// Name of the running job, empty when idle
+    #[serde(default)]
+    pub job: String,
+}
+
+impl ProgressResponse {
+    /// Whether the server is currently running or queueing a job
+    pub fn is_busy(&self) -> bool {
+        self.state.job_count > 0 || !self.state.job.is_empty()
+    }
 }
 
 /// Client for interacting with Stable Diffusion API
@@ -53,67 +413,194 @@ pub struct ImageResponse {
 pub struct StableDiffusion {
     client: Client,
     config: SDConfig,
+    /// Limits concurrent generation requests toward `config.base_url`
+    limiter: Arc<Semaphore>,
+}
+
+/// 按服务端地址登记的请求并发上限及对应的信号量
+type Limiters = HashMap<String, (usize, Arc<Semaphore>)>;
+
+/// 同一SD服务端的所有客户端共享一个请求并发限制，避免多个节点同时生成导致显存不足
+///
+/// 限制值由该服务端的第一个客户端决定；之后的客户端要求不同的值时记录警告并沿用已有限制。
+fn request_limiter(base_url: &str, permits: usize) -> Arc<Semaphore> {
+    static LIMITERS: OnceLock<Mutex<Limiters>> = OnceLock::new();
+    let mut limiters = LIMITERS
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    let permits = permits.max(1);
+    let base_url = base_url.trim_end_matches('/');
+    let (limit, limiter) = limiters
+        .entry(base_url.to_string())
+        .or_insert_with(|| (permits, Arc::new(Semaphore::new(permits))));
+    if *limit != permits {
+        log::warn!(
+            "Stable Diffusion server {} is already limited to {} concurrent request(s); ignoring the requested limit of {}",
+            base_url, limit, permits
+        );
+    }
+    Arc::clone(limiter)
 }
 
 impl StableDiffusion {
     /// Create a new Stable Diffusion client
     pub fn new(config: SDConfig) -> Result<Self> {
-        let timeout = Duration::from_millis(config.timeout.unwrap_or(120000));
+        let timeout = Duration::from_millis(config.timeout.unwrap_or(DEFAULT_TIMEOUT_MS));
+        
+        let mut headers = HeaderMap::new();
+        if let Some(auth) = &config.auth {
+            headers.insert(AUTHORIZATION, auth.header_value()?);
+        }
         
         let client = ClientBuilder::new()
             .timeout(timeout)
+            .default_headers(headers)
             .build()?;
             
-        Ok(Self { client, config })
+        config.paths.validate(&config.base_url)?;
+        let limiter = request_limiter(&config.base_url, config.max_concurrent_requests.unwrap_or(1));
+        
+        Ok(Self { client, config, limiter })
+    }
+    
+    /// Full URL of an endpoint
+    fn endpoint(&self, name: &str, path: &str) -> Result<Url> {
+        SDPaths::url(&self.config.base_url, name, path)
     }
     
     /// Generate images from text prompts
     pub async fn text_to_image(&self, params: TextToImageParams) -> Result<ImageResponse> {
-        // 最大重试次数
-        const MAX_RETRIES: u32 = 5;
+        // 最大尝试次数，单次尝试策略下不重试
+        let max_attempts: u32 = match params.retry {
+            RetryPolicy::Retry => 5,
+            RetryPolicy::SingleAttempt => 1,
+        };
         // 初始重试延迟（毫秒）
         const INITIAL_RETRY_DELAY_MS: u64 = 1000;
         
         // Create the request parameters with defaults
-        let request_params = serde_json::json!({
+        let mut request_params = serde_json::json!({
             "prompt": params.prompt,
-            "negative_prompt": params.negative_prompt.unwrap_or_else(|| "".to_string()),
-            "width": params.width.unwrap_or(512),
-            "height": params.height.unwrap_or(512),
+            "negative_prompt": params.negative_prompt.unwrap_or_default(),
+            "width": params.width.unwrap_or(DEFAULT_IMAGE_SIZE),
+            "height": params.height.unwrap_or(DEFAULT_IMAGE_SIZE),
             "steps": params.steps.unwrap_or(20),
             "cfg_scale": params.cfg_scale.unwrap_or(7.0),
             "seed": params.seed.unwrap_or(-1),
-            "batch_size": 1,
+            "batch_size": params.batch_size.unwrap_or(1),
             "n_iter": 1,
             "restore_faces": false,
             "tiling": false,
         });
         
+        // 变体种子，仅在任务指定时发送
+        if let Some(subseed) = params.subseed {
+            request_params["subseed"] = serde_json::json!(subseed);
+        }
+        if let Some(strength) = params.subseed_strength {
+            request_params["subseed_strength"] = serde_json::json!(strength);
+        }
+        
+        // 脚本与扩展，仅在任务指定时发送
+        if let Some(script_name) = params.script_name {
+            request_params["script_name"] = serde_json::json!(script_name);
+            request_params["script_args"] = serde_json::json!(params.script_args);
+        }
+        if !params.alwayson_scripts.is_empty() {
+            request_params["alwayson_scripts"] = serde_json::Value::Object(params.alwayson_scripts);
+        }
+        
+        // SDXL 精炼模型，服务端在 refiner_switch_at 处切换到该检查点
+        if let Some(checkpoint) = params.refiner_checkpoint {
+            request_params["refiner_checkpoint"] = serde_json::json!(checkpoint);
+            request_params["refiner_switch_at"] = serde_json::json!(params.refiner_switch_at.unwrap_or(0.8));
+        }
+        
         log::debug!("Sending request to Stable Diffusion API with params: {}", 
             serde_json::to_string_pretty(&request_params).unwrap_or_else(|_| format!("{:?}", request_params)));
         
         // Build the endpoint URL
-        let url = Url::parse(&format!("{}/sdapi/v1/txt2img", self.config.base_url))?;
+        let url = self.endpoint("txt2img", &self.config.paths.txt2img)?;
         
-        // 重试逻辑
+        // 重试逻辑：单次请求受客户端超时限制；任务指定 timeout_ms 时，它是整个任务（含所有重试）的总时限
         let mut last_error = None;
+        let started = Instant::now();
+        let deadline = params.timeout_ms.map(|timeout_ms| started + Duration::from_millis(timeout_ms));
+        let remaining = || deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        // 上一次请求在处理中途断开，需要确认服务端是否已重启
+        let mut connection_lost = false;
+        let mut restarts = 0;
         
-        for retry in 0..MAX_RETRIES {
+        for retry in 0..max_attempts {
             if retry > 0 {
-                // 指数退避延迟
-                let delay = INITIAL_RETRY_DELAY_MS * 2u64.pow(retry - 1);
+                // 指数退避延迟，不超过单次上限
+                let mut delay = INITIAL_RETRY_DELAY_MS * 2u64.pow(retry - 1);
+                if let Some(max_backoff_ms) = self.config.max_backoff_ms {
+                    delay = delay.min(max_backoff_ms);
+                }
+                
+                // 总重试时间预算耗尽时返回最后一次错误
+                if let Some(budget_ms) = self.config.max_total_retry_ms
+                    && started.elapsed().as_millis() as u64 + delay > budget_ms
+                {
+                    log::warn!("Stable Diffusion retry budget of {}ms exhausted after {} attempts", budget_ms, retry);
+                    break;
+                }
+                
+                if let Some(deadline) = deadline
+                    && Instant::now() + Duration::from_millis(delay) >= deadline
+                {
+                    log::warn!("Stable Diffusion task timeout reached after {} attempts, not retrying", retry);
+                    break;
+                }
+                
                 log::warn!("Retrying Stable Diffusion API request (attempt {}/{}), waiting {}ms before retry", 
-                    retry + 1, MAX_RETRIES, delay);
+                    retry + 1, max_attempts, delay);
                 tokio::time::sleep(Duration::from_millis(delay)).await;
+                
+                // 服务端重新可用且处于空闲状态，说明它在处理中途重启、任务已丢失；探测同样受任务时限约束
+                if connection_lost {
+                    let probe = match remaining() {
+                        Some(remaining) => tokio::time::timeout(remaining, self.progress()).await.ok(),
+                        None => Some(self.progress().await),
+                    };
+                    if let Some(Ok(progress)) = probe {
+                        connection_lost = false;
+                        if !progress.is_busy() {
+                            restarts += 1;
+                            log::warn!("Stable Diffusion server restarted mid-task and lost the job, resubmitting from scratch");
+                        }
+                    }
+                }
             }
             
-            // Send the request
-            match self.client.post(url.clone())
+            // 等待SD服务端的请求名额，等待时间计入任务时限
+            let _permit = match remaining() {
+                Some(remaining) => match tokio::time::timeout(remaining, self.acquire()).await {
+                    Ok(permit) => permit?,
+                    Err(_) => {
+                        last_error = Some(anyhow::anyhow!(
+                            "Timed out waiting for a free request slot on the Stable Diffusion server"
+                        ));
+                        break;
+                    }
+                },
+                None => self.acquire().await?,
+            };
+            
+            // Send the request; a task timeout replaces the client timeout with the time left before the deadline
+            let mut request = self.client.post(url.clone())
                 .header("Content-Type", "application/json")
-                .json(&request_params)
-                .send()
-                .await 
-            {
+                .json(&request_params);
+            if let Some(remaining) = remaining() {
+                if remaining.is_zero() {
+                    break;
+                }
+                request = request.timeout(remaining);
+            }
+            
+            match request.send().await {
                 Ok(response) => {
                     // Handle non-successful status codes
                     if !response.status().is_success() {
@@ -123,18 +610,17 @@ impl StableDiffusion {
                         log::error!("Stable Diffusion API error: HTTP {}: {}", status, error_text);
                         
                         // 检查是否为服务器错误（可能是临时性故障）
-                        let retry_error = error_text.contains("'NoneType' object") || 
-                                         error_text.contains("CUDA out of memory") ||
-                                         error_text.contains("expected scalar type") ||
-                                         status.is_server_error();
+                        let retry_error = status.is_server_error() ||
+                                         self.config.retryable_errors.matches(&error_text);
                                          
-                        if retry_error && retry < MAX_RETRIES - 1 {
-                            log::warn!("Retryable error detected: HTTP {}: {}", status, error_text);
-                            last_error = Some(anyhow::anyhow!("Stable Diffusion API request failed: HTTP {}: {}", status, error_text));
+                        let api_error = SDError::Api { status: status.as_u16(), body: error_text };
+                        if retry_error && retry < max_attempts - 1 {
+                            log::warn!("Retryable error detected: {}", api_error);
+                            last_error = Some(api_error.into());
                             continue; // 继续重试
                         }
                         
-                        return Err(anyhow::anyhow!("Stable Diffusion API request failed: HTTP {}: {}", status, error_text));
+                        return Err(api_error.into());
                     }
                     
                     // 获取响应内容
@@ -143,7 +629,7 @@ impl StableDiffusion {
                     // 尝试解析JSON
                     match serde_json::from_str::<ImageResponse>(&response_text) {
                         Ok(image_response) => {
-                            if image_response.images.is_empty() && retry < MAX_RETRIES - 1 {
+                            if image_response.images.is_empty() && retry < max_attempts - 1 {
                                 log::warn!("Stable Diffusion API returned empty images array, retrying...");
                                 last_error = Some(anyhow::anyhow!("Stable Diffusion API returned empty images array"));
                                 continue; // 继续重试
@@ -153,7 +639,7 @@ impl StableDiffusion {
                             log::debug!("Successfully received {} images from Stable Diffusion API",
                                 image_response.images.len());
                                 
-                            return Ok(image_response);
+                            return Ok(ImageResponse { restarts, ..image_response });
                         },
                         Err(e) => {
                             // 日志记录响应内容的前 200 个字符
@@ -163,33 +649,359 @@ impl StableDiffusion {
                                 &response_text[..preview_len],
                                 if response_text.len() > 200 { "..." } else { "" });
                                 
-                            if retry < MAX_RETRIES - 1 {
+                            if retry < max_attempts - 1 {
                                 log::warn!("Failed to parse Stable Diffusion API response: {}, retrying...", e);
-                                last_error = Some(anyhow::anyhow!("Failed to parse response: {}", e));
+                                last_error = Some(anyhow::Error::new(e).context("Failed to parse response"));
                                 continue; // 继续重试
                             }
                             
-                            return Err(anyhow::anyhow!("Failed to parse response: {}", e));
+                            return Err(anyhow::Error::new(e).context("Failed to parse response"));
                         }
                     }
                 },
                 Err(e) => {
-                    if retry < MAX_RETRIES - 1 {
+                    // 已建立的连接在处理中途断开（非连接失败、非超时）
+                    if !e.is_connect() && !e.is_timeout() {
+                        connection_lost = true;
+                    }
+                    if retry < max_attempts - 1 {
                         log::warn!("Stable Diffusion API request failed: {}, retrying...", e);
-                        last_error = Some(anyhow::anyhow!("Request failed: {}", e));
+                        last_error = Some(anyhow::Error::new(e).context("Request failed"));
                         continue; // 继续重试
                     }
-                    return Err(anyhow::anyhow!("Request failed: {}", e));
+                    return Err(anyhow::Error::new(e).context("Request failed"));
                 }
             }
         }
         
         // 如果所有重试都失败，返回最后一个错误
-        Err(last_error.unwrap_or_else(|| anyhow::anyhow!("Failed to connect to Stable Diffusion API after {} retries", MAX_RETRIES)))
+        Err(last_error.unwrap_or_else(|| anyhow::anyhow!("Failed to connect to Stable Diffusion API after {} attempts", max_attempts)))
+    }
+    
+    /// Wait for a free request slot on this server
+    ///
+    /// Held for the duration of generation-type requests (txt2img, options, png-info);
+    /// lightweight queries such as `progress` and the model lists don't take a slot.
+    async fn acquire(&self) -> Result<OwnedSemaphorePermit> {
+        Ok(Arc::clone(&self.limiter).acquire_owned().await?)
+    }
+    
+    /// Apply server-wide options (VAE, CLIP skip, ...) via the options endpoint
+    pub async fn set_options(&self, options: serde_json::Value) -> Result<()> {
+        let url = self.endpoint("options", &self.config.paths.options)?;
+        let _permit = self.acquire().await?;
+        let response = self.client.post(url).json(&options).send().await?;
+        
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(SDError::Api { status: status.as_u16(), body }.into());
+        }
+        
+        Ok(())
+    }
+    
+    /// Read the generation parameters embedded in a base64 PNG via the png-info endpoint
+    pub async fn png_info(&self, image: &str) -> Result<String> {
+        let url = self.endpoint("png_info", &self.config.paths.png_info)?;
+        let _permit = self.acquire().await?;
+        let response = self.client
+            .post(url)
+            .json(&serde_json::json!({ "image": Self::base64_to_image_url(image, "image/png") }))
+            .send()
+            .await?;
+        
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(SDError::Api { status: status.as_u16(), body }.into());
+        }
+        
+        Ok(response.json::<PngInfoResponse>().await?.info)
+    }
+    
+    /// Lightweight request keeping an idle server responsive (`GET` options endpoint)
+    pub async fn ping(&self) -> Result<()> {
+        let url = self.endpoint("options", &self.config.paths.options)?;
+        self.get(url).await?;
+        Ok(())
+    }
+    
+    /// Checkpoint currently loaded on the server (`sd_model_checkpoint` option)
+    pub async fn current_model(&self) -> Result<Option<String>> {
+        let url = self.endpoint("options", &self.config.paths.options)?;
+        let options: serde_json::Value = self.get_json(url).await?;
+        Ok(options["sd_model_checkpoint"].as_str().map(String::from))
+    }
+    
+    /// List checkpoints available on the server
+    pub async fn list_models(&self) -> Result<Vec<SDModel>> {
+        let url = self.endpoint("sd_models", &self.config.paths.sd_models)?;
+        Ok(self.get_json(url).await?)
+    }
+    
+    /// List the LoRAs available on the server
+    pub async fn list_loras(&self) -> Result<Vec<SDLora>> {
+        let url = self.endpoint("loras", &self.config.paths.loras)?;
+        Ok(self.get_json(url).await?)
+    }
+    
+    /// List the scripts and extensions installed on the server
+    pub async fn list_scripts(&self) -> Result<SDScripts> {
+        let url = self.endpoint("scripts", &self.config.paths.scripts)?;
+        Ok(self.get_json(url).await?)
+    }
+    
+    /// List the samplers available on the server
+    pub async fn list_samplers(&self) -> Result<Vec<SDSampler>> {
+        let url = self.endpoint("samplers", &self.config.paths.samplers)?;
+        Ok(self.get_json(url).await?)
+    }
+    
+    /// List the extensions installed on the server, enabled or not
+    pub async fn list_extensions(&self) -> Result<Vec<SDExtension>> {
+        let url = self.endpoint("extensions", &self.config.paths.extensions)?;
+        Ok(self.get_json(url).await?)
+    }
+    
+    /// Query the server's current job progress
+    pub async fn progress(&self) -> Result<ProgressResponse> {
+        let mut url = self.endpoint("progress", &self.config.paths.progress)?;
+        url.query_pairs_mut().append_pair("skip_current_image", "true");
+        Ok(self.get_json(url).await?)
+    }
+    
+    /// `GET` a lightweight endpoint; these don't take a request slot
+    async fn get(&self, url: Url) -> Result<Response, SDError> {
+        let response = self.client.get(url).send().await.map_err(SDError::Request)?;
+        
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(SDError::Api { status: status.as_u16(), body });
+        }
+        
+        Ok(response)
+    }
+    
+    /// `GET` a lightweight endpoint and decode its JSON body
+    async fn get_json<T: DeserializeOwned>(&self, url: Url) -> Result<T, SDError> {
+        self.get(url).await?.json().await.map_err(SDError::Request)
     }
     
-    /// Convert base64 image data to a data URL
-    pub fn base64_to_image_url(base64_data: &str) -> String {
-        format!("data:image/png;base64,{}", base64_data)
+    /// Convert base64 image data to a data URL with the given MIME type
+    pub fn base64_to_image_url(base64_data: &str, mime_type: &str) -> String {
+        format!("data:{};base64,{}", mime_type, base64_data)
+    }
+} 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// 读完一个HTTP请求（请求头及 Content-Length 指定的请求体）
+    async fn read_request(stream: &mut TcpStream) {
+        let mut request = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            if n == 0 {
+                return;
+            }
+            request.extend_from_slice(&buf[..n]);
+            let text = String::from_utf8_lossy(&request);
+            if let Some(end) = text.find("\r\n\r\n") {
+                let length = text[..end]
+                    .lines()
+                    .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-length:").map(|v| v.trim().to_string()))
+                    .and_then(|v| v.parse::<usize>().ok())
+                    .unwrap_or(0);
+                if request.len() >= end + 4 + length {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// 对每个请求都返回 HTTP 400 与 `body` 的本地SD服务，返回地址与请求计数
+    async fn failing_server(body: &'static str) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                read_request(&mut stream).await;
+                counter.fetch_add(1, Ordering::SeqCst);
+                let reply = format!(
+                    "HTTP/1.1 400 Bad Request\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(reply.as_bytes()).await;
+            }
+        });
+        (format!("http://{}", address), requests)
+    }
+
+    /// 接受请求但从不响应的本地SD服务，返回地址与请求计数
+    async fn hanging_server() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            let mut open = Vec::new();
+            while let Ok((mut stream, _)) = listener.accept().await {
+                read_request(&mut stream).await;
+                counter.fetch_add(1, Ordering::SeqCst);
+                open.push(stream);
+            }
+        });
+        (format!("http://{}", address), requests)
+    }
+
+    /// 第一个请求读完后直接断开连接，之后的请求都不响应的本地SD服务
+    async fn dropping_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut first = true;
+            let mut open = Vec::new();
+            while let Ok((mut stream, _)) = listener.accept().await {
+                read_request(&mut stream).await;
+                if !std::mem::take(&mut first) {
+                    open.push(stream);
+                }
+            }
+        });
+        format!("http://{}", address)
+    }
+
+    fn config(base_url: String, retryable_errors: RetryableErrors) -> SDConfig {
+        SDConfig {
+            base_url,
+            timeout: Some(10_000),
+            auth: None,
+            max_backoff_ms: Some(1),
+            max_total_retry_ms: None,
+            max_concurrent_requests: None,
+            retryable_errors,
+            paths: SDPaths::default(),
+        }
     }
-} 
\ No newline at end of file
+
+    fn client(base_url: String, retryable_errors: RetryableErrors) -> StableDiffusion {
+        StableDiffusion::new(config(base_url, retryable_errors)).unwrap()
+    }
+
+    fn params() -> TextToImageParams {
+        TextToImageParams {
+            prompt: "a cat".to_string(),
+            negative_prompt: None,
+            width: None,
+            height: None,
+            steps: None,
+            cfg_scale: None,
+            seed: None,
+            subseed: None,
+            subseed_strength: None,
+            batch_size: None,
+            timeout_ms: None,
+            refiner_checkpoint: None,
+            refiner_switch_at: None,
+            script_name: None,
+            script_args: Vec::new(),
+            alwayson_scripts: serde_json::Map::new(),
+            retry: RetryPolicy::Retry,
+        }
+    }
+
+    #[test]
+    fn matches_builtin_and_configured_substrings() {
+        let errors = RetryableErrors::new(&["Model is loading".to_string(), "  ".to_string()], false);
+        assert!(errors.matches("RuntimeError: CUDA out of memory. Tried to allocate 2.00 GiB"));
+        assert!(errors.matches("{\"detail\": \"Model is loading, try again\"}"));
+        assert!(!errors.matches("{\"detail\": \"model is loading\"}"));
+        assert!(!errors.matches("{\"detail\": \"Invalid sampler\"}"));
+        // 空白的配置项被忽略，不会匹配任意内容
+        assert!(!errors.matches(""));
+    }
+
+    #[test]
+    fn matches_ignoring_case_when_configured() {
+        let errors = RetryableErrors::new(&["Model Is Loading".to_string()], true);
+        assert!(errors.matches("{\"detail\": \"model is LOADING\"}"));
+        assert!(errors.matches("cuda OUT OF MEMORY"));
+        assert!(!errors.matches("{\"detail\": \"Invalid sampler\"}"));
+    }
+
+    #[tokio::test]
+    async fn retries_configured_substring_but_not_unlisted_errors() {
+        let retryable = RetryableErrors::new(&["Model is loading".to_string()], false);
+
+        let (url, requests) = failing_server("{\"detail\": \"Model is loading\"}").await;
+        let error = client(url, retryable.clone()).text_to_image(params()).await.unwrap_err();
+        assert!(matches!(error.downcast_ref::<SDError>(), Some(SDError::Api { status: 400, .. })));
+        assert_eq!(requests.load(Ordering::SeqCst), 5);
+
+        let (url, requests) = failing_server("{\"detail\": \"Invalid sampler\"}").await;
+        client(url, retryable).text_to_image(params()).await.unwrap_err();
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn client_timeout_limits_each_attempt_and_task_timeout_the_whole_task() {
+        // 未指定任务时限时，客户端超时只限制单次请求，所有重试照常进行
+        let (url, requests) = hanging_server().await;
+        let sd = StableDiffusion::new(SDConfig { timeout: Some(100), ..config(url, RetryableErrors::default()) }).unwrap();
+        sd.text_to_image(params()).await.unwrap_err();
+        assert_eq!(requests.load(Ordering::SeqCst), 5);
+
+        // 任务时限覆盖单次请求的客户端超时，并限制所有重试的总时长
+        let (url, requests) = hanging_server().await;
+        let sd = StableDiffusion::new(SDConfig { timeout: Some(100), ..config(url, RetryableErrors::default()) }).unwrap();
+        let started = Instant::now();
+        sd.text_to_image(TextToImageParams { timeout_ms: Some(300), ..params() }).await.unwrap_err();
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn restart_probe_respects_the_task_timeout() {
+        // 连接中途断开后服务端不再响应，重启探测不能拖过任务时限等到客户端超时
+        let sd = client(dropping_server().await, RetryableErrors::default());
+        let started = Instant::now();
+        sd.text_to_image(TextToImageParams { timeout_ms: Some(500), ..params() }).await.unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn lightweight_queries_report_status_and_transport_errors() {
+        let (url, _) = failing_server("{\"detail\": \"Not Found\"}").await;
+        let error = client(url, RetryableErrors::default()).list_models().await.unwrap_err();
+        assert!(matches!(error.downcast_ref::<SDError>(), Some(SDError::Api { status: 400, .. })));
+
+        // 底层的 reqwest 错误保留在错误链中，供任务错误分类使用
+        let error = client("http://127.0.0.1:1".to_string(), RetryableErrors::default()).progress().await.unwrap_err();
+        assert!(matches!(error.downcast_ref::<SDError>(), Some(SDError::Request(_))));
+        assert!(error.chain().any(|cause| cause.downcast_ref::<reqwest::Error>().is_some_and(reqwest::Error::is_connect)));
+    }
+
+    #[test]
+    fn clients_of_one_server_share_the_first_limit() {
+        let base_url = format!("http://limiter-test-{}:7860", uuid::Uuid::new_v4());
+        let first = request_limiter(&base_url, 2);
+        // 末尾斜杠不影响归属；不同的限制值被忽略
+        let second = request_limiter(&format!("{}/", base_url), 4);
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(second.available_permits(), 2);
+
+        let other = request_limiter(&format!("{}0", base_url), 0);
+        assert!(!Arc::ptr_eq(&first, &other));
+        assert_eq!(other.available_permits(), 1);
+    }
+}
@@ -0,0 +1,85 @@
+use crate::consts::*;
+use anyhow::Result;
+use chrono::Utc;
+use dirs::config_dir;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// 同一进程内的多个节点共用同一个文件，读-改-写需要串行
+static FILE_LOCK: Mutex<()> = Mutex::new(());
+
+/// 单个任务的处理次数记录
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AttemptEntry {
+    attempts: u32,
+    /// Unix timestamp of the latest attempt
+    updated_at: i64,
+}
+
+/// 任务处理次数文件路径
+pub fn attempts_path() -> Result<PathBuf> {
+    let config_dir = config_dir().ok_or_else(|| anyhow::anyhow!("无法获取配置目录"))?;
+    Ok(config_dir.join(CONFIG_DIR).join(TASK_ATTEMPTS_FILE))
+}
+
+fn load(path: &PathBuf) -> HashMap<String, AttemptEntry> {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+fn save(path: &PathBuf, entries: &HashMap<String, AttemptEntry>) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, serde_json::to_string(entries)?)?;
+    Ok(())
+}
+
+/// 记录一次处理尝试，返回包括本次在内的累计次数
+///
+/// 记录跨进程重启保留；过期条目在写入时清理，条目数超过上限时丢弃最旧的记录。
+pub fn record(task_id: &str) -> Result<u32> {
+    let _lock = FILE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let path = attempts_path()?;
+    let mut entries = load(&path);
+
+    let now = Utc::now().timestamp();
+    entries.retain(|_, entry| now - entry.updated_at < TASK_ATTEMPTS_TTL_SECONDS);
+
+    let entry = entries
+        .entry(task_id.to_string())
+        .or_insert(AttemptEntry { attempts: 0, updated_at: now });
+    entry.attempts += 1;
+    entry.updated_at = now;
+    let attempts = entry.attempts;
+
+    if entries.len() > TASK_ATTEMPTS_MAX_ENTRIES {
+        let mut by_age: Vec<(String, i64)> = entries
+            .iter()
+            .map(|(id, entry)| (id.clone(), entry.updated_at))
+            .collect();
+        by_age.sort_by_key(|(_, updated_at)| *updated_at);
+        let excess = entries.len() - TASK_ATTEMPTS_MAX_ENTRIES;
+        for (id, _) in by_age.into_iter().filter(|(id, _)| id != task_id).take(excess) {
+            entries.remove(&id);
+        }
+    }
+
+    save(&path, &entries)?;
+    Ok(attempts)
+}
+
+/// 任务已得出最终结果，删除其记录
+pub fn clear(task_id: &str) -> Result<()> {
+    let _lock = FILE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let path = attempts_path()?;
+    let mut entries = load(&path);
+    if entries.remove(task_id).is_some() {
+        save(&path, &entries)?;
+    }
+    Ok(())
+}
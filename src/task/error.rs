@@ -0,0 +1,258 @@
+use crate::stable_diffusion::SDError;
+use serde::Serialize;
+use std::fmt;
+
+/// 任务失败分类，供后端统计与重试策略使用
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// GPU ran out of memory during generation
+    Oom,
+    /// Task parameters are missing or malformed
+    InvalidParams,
+    /// Task sets a param outside the node's allow-list
+    ForbiddenParam,
+    /// Request to the SD server timed out
+    Timeout,
+    /// SD server could not be reached
+    SdUnavailable,
+    /// SD server returned an error response
+    SdError,
+    /// Task message or SD response could not be parsed
+    ParseError,
+    /// Result images could not be uploaded
+    UploadFailed,
+    /// Result payload exceeds the NATS max payload
+    ResultTooLarge,
+    /// Task was attempted more times than the node's delivery limit
+    RetriesExhausted,
+    /// Node shut down before the task finished
+    Interrupted,
+    /// Task needs a script or extension the SD server does not have
+    UnsupportedFeature,
+    /// Anything not covered above
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Oom => "oom",
+            Self::InvalidParams => "invalid_params",
+            Self::ForbiddenParam => "forbidden_param",
+            Self::Timeout => "timeout",
+            Self::SdUnavailable => "sd_unavailable",
+            Self::SdError => "sd_error",
+            Self::ParseError => "parse_error",
+            Self::UploadFailed => "upload_failed",
+            Self::ResultTooLarge => "result_too_large",
+            Self::RetriesExhausted => "retries_exhausted",
+            Self::Interrupted => "interrupted",
+            Self::UnsupportedFeature => "unsupported_feature",
+            Self::Internal => "internal",
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// 带分类的任务错误，可直接返回或作为 `anyhow` 上下文附加到底层错误上
+#[derive(Debug, thiserror::Error)]
+#[error("{message}")]
+pub struct TaskError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl TaskError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn invalid_params(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::InvalidParams, message)
+    }
+}
+
+/// 根据错误链判断失败类型
+pub fn classify(error: &anyhow::Error) -> ErrorCode {
+    // 以 `context` 附加的 TaskError 在 `chain()` 中表现为包装类型，需通过 anyhow 的 downcast 查找
+    if let Some(e) = error.downcast_ref::<TaskError>() {
+        return e.code;
+    }
+    for cause in error.chain() {
+        if let Some(e) = cause.downcast_ref::<TaskError>() {
+            return e.code;
+        }
+        if let Some(SDError::Api { body, .. }) = cause.downcast_ref::<SDError>() {
+            if is_oom(body) {
+                return ErrorCode::Oom;
+            }
+            return ErrorCode::SdError;
+        }
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            if e.is_timeout() {
+                return ErrorCode::Timeout;
+            }
+            if e.is_connect() || e.is_request() {
+                return ErrorCode::SdUnavailable;
+            }
+            if e.is_decode() {
+                return ErrorCode::ParseError;
+            }
+        }
+        if cause.is::<serde_json::Error>() || cause.is::<base64::DecodeError>() {
+            return ErrorCode::ParseError;
+        }
+    }
+
+    if is_oom(&format!("{:#}", error)) {
+        return ErrorCode::Oom;
+    }
+    ErrorCode::Internal
+}
+
+fn is_oom(text: &str) -> bool {
+    text.contains("CUDA out of memory") || text.contains("OutOfMemoryError")
+}
+
+/// 截断过长的错误信息，保留开头并追加截断标记，结果（含标记）不超过 `max_len` 字节；
+/// `max_len` 为 0 时不截断，放不下标记时只截断
+pub fn truncate_stack(mut stack: String, max_len: usize) -> String {
+    if max_len == 0 || stack.len() <= max_len {
+        return stack;
+    }
+    let marker = format!("\n...truncated ({} bytes total)", stack.len());
+    let budget = max_len.checked_sub(marker.len());
+    let mut end = budget.unwrap_or(max_len);
+    while !stack.is_char_boundary(end) {
+        end -= 1;
+    }
+    stack.truncate(end);
+    if budget.is_some() {
+        stack.push_str(&marker);
+    }
+    stack
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::Engine as _;
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    fn api_error(status: u16, body: &str) -> anyhow::Error {
+        SDError::Api { status, body: body.to_string() }.into()
+    }
+
+    /// 本地HTTP服务：`reply` 为空时接受连接后不作响应
+    async fn serve(reply: Option<&'static str>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((mut stream, _)) = listener.accept().await {
+                match reply {
+                    Some(reply) => {
+                        let _ = stream.write_all(reply.as_bytes()).await;
+                    }
+                    None => held.push(stream),
+                }
+            }
+        });
+        format!("http://{}", address)
+    }
+
+    async fn reqwest_error(url: &str, timeout: Duration) -> anyhow::Error {
+        let client = reqwest::Client::builder().timeout(timeout).build().unwrap();
+        match client.get(url).send().await {
+            Ok(response) => response.json::<serde_json::Value>().await.unwrap_err().into(),
+            Err(e) => e.into(),
+        }
+    }
+
+    #[test]
+    fn classifies_errors_by_cause() {
+        let cases: Vec<(&str, anyhow::Error, ErrorCode)> = vec![
+            ("task error", TaskError::invalid_params("Missing required parameter: prompt").into(), ErrorCode::InvalidParams),
+            (
+                "task error as context",
+                anyhow::Error::from(std::io::Error::other("disk full"))
+                    .context(TaskError::new(ErrorCode::UploadFailed, "Failed to upload result images")),
+                ErrorCode::UploadFailed,
+            ),
+            ("forbidden param", TaskError::new(ErrorCode::ForbiddenParam, "not allowed").into(), ErrorCode::ForbiddenParam),
+            ("result too large", TaskError::new(ErrorCode::ResultTooLarge, "too large").into(), ErrorCode::ResultTooLarge),
+            ("retries exhausted", TaskError::new(ErrorCode::RetriesExhausted, "attempts").into(), ErrorCode::RetriesExhausted),
+            ("unsupported", TaskError::new(ErrorCode::UnsupportedFeature, "no script").into(), ErrorCode::UnsupportedFeature),
+            ("sd oom", api_error(500, "torch.cuda.OutOfMemoryError: CUDA out of memory."), ErrorCode::Oom),
+            ("sd error", api_error(422, "{\"detail\":\"Sampler not found\"}"), ErrorCode::SdError),
+            (
+                "sd error under context",
+                api_error(500, "RuntimeError").context("Failed to generate frame 2/4"),
+                ErrorCode::SdError,
+            ),
+            (
+                "json",
+                serde_json::from_str::<serde_json::Value>("{").unwrap_err().into(),
+                ErrorCode::ParseError,
+            ),
+            (
+                "base64",
+                base64::engine::general_purpose::STANDARD.decode("!!").unwrap_err().into(),
+                ErrorCode::ParseError,
+            ),
+            ("oom in message", anyhow::anyhow!("worker died: CUDA out of memory"), ErrorCode::Oom),
+            ("other", anyhow::anyhow!("something else"), ErrorCode::Internal),
+        ];
+
+        for (name, error, expected) in cases {
+            assert_eq!(classify(&error), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn task_error_code_wins_over_underlying_cause() {
+        let error = api_error(400, "bad refiner").context(TaskError::invalid_params("refiner rejected"));
+        assert_eq!(classify(&error), ErrorCode::InvalidParams);
+    }
+
+    #[tokio::test]
+    async fn classifies_http_failures() {
+        let refused = reqwest_error("http://127.0.0.1:1", Duration::from_secs(5)).await;
+        assert_eq!(classify(&refused), ErrorCode::SdUnavailable);
+
+        let silent = serve(None).await;
+        let timeout = reqwest_error(&silent, Duration::from_millis(100)).await;
+        assert_eq!(classify(&timeout), ErrorCode::Timeout);
+
+        let garbage = serve(Some("HTTP/1.1 200 OK\r\ncontent-length: 3\r\nconnection: close\r\n\r\nabc")).await;
+        let decode = reqwest_error(&garbage, Duration::from_secs(5)).await;
+        assert_eq!(classify(&decode.context("Failed to read SD response")), ErrorCode::ParseError);
+    }
+
+    #[test]
+    fn truncates_long_stacks_on_char_boundary() {
+        assert_eq!(truncate_stack("short".to_string(), 0), "short");
+        assert_eq!(truncate_stack("short".to_string(), 10), "short");
+        // 放不下截断标记时只截断
+        assert_eq!(truncate_stack("错误信息".to_string(), 4), "错");
+
+        let stack = "错".repeat(20);
+        for max_len in [31, 40, 59] {
+            let result = truncate_stack(stack.clone(), max_len);
+            assert!(result.len() <= max_len, "{} > {}", result.len(), max_len);
+            assert!(result.ends_with("\n...truncated (60 bytes total)"), "{}", result);
+        }
+        assert_eq!(truncate_stack(stack, 40), "错错错\n...truncated (60 bytes total)");
+    }
+}
@@ -0,0 +1,75 @@
+use crate::consts::*;
+use crate::upload::UploadProgress;
+use chrono::Utc;
+use serde::Serialize;
+
+/// 节点停止的原因
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StopReason {
+    /// Shutdown signal received, in-flight tasks drained
+    Signal,
+    /// Task source ended without a shutdown signal
+    Drain,
+    /// Task processor stopped on an error
+    Error,
+    /// No tasks arrived within the idle exit timeout
+    Idle,
+}
+
+/// 启动时上报的硬件与模型概要
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct NodeSummary {
+    pub gpu_model: Option<String>,
+    pub gpu_memory_mb: Option<u64>,
+    pub sd_url: String,
+    /// Checkpoint loaded on the SD server, when it could be queried
+    pub sd_model: Option<String>,
+    pub max_concurrent_tasks: usize,
+}
+
+/// 生命周期事件
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum LifecycleEvent {
+    Started(NodeSummary),
+    Stopped { reason: StopReason },
+    /// Part of a large result finished uploading
+    UploadProgress { task_id: String, progress: UploadProgress },
+}
+
+impl LifecycleEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Started(_) => "started",
+            Self::Stopped { .. } => "stopped",
+            Self::UploadProgress { .. } => "upload_progress",
+        }
+    }
+}
+
+/// 发布到生命周期主题的消息
+#[derive(Debug, Clone, Serialize)]
+pub struct LifecycleMessage {
+    pub node_id: String,
+    #[serde(flatten)]
+    pub event: LifecycleEvent,
+    pub client_version: &'static str,
+    pub timestamp: String,
+}
+
+impl LifecycleMessage {
+    pub fn new(node_id: &str, event: LifecycleEvent) -> Self {
+        Self {
+            node_id: node_id.to_string(),
+            event,
+            client_version: CLIENT_VERSION,
+            timestamp: Utc::now().to_rfc3339(),
+        }
+    }
+}
+
+/// 生命周期主题模板，`{node_id}` 替换为节点ID
+pub fn subject(template: &str, node_id: &str) -> String {
+    template.replace("{node_id}", node_id)
+}
@@ -0,0 +1,127 @@
+use chrono::Utc;
+use log::{Log, Metadata, Record};
+use regex::Regex;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::{Arc, LazyLock, Mutex};
+
+tokio::task_local! {
+    /// 当前任务的日志缓冲区，只在 `capture` 包裹的 future 内可见
+    static CAPTURE: Arc<Mutex<TaskLog>>;
+}
+
+/// 凭据类内容的匹配规则，捕获的日志写入结果前替换为 `<redacted>`
+static SECRET_PATTERNS: LazyLock<Vec<(Regex, &'static str)>> = LazyLock::new(|| {
+    [
+        // JWT
+        (r"eyJ[A-Za-z0-9_-]+\.[A-Za-z0-9_-]+\.[A-Za-z0-9_-]*", "<redacted>"),
+        // Authorization 头
+        (r"(?i)\b(bearer|basic)\s+[A-Za-z0-9._~+/=-]+", "$1 <redacted>"),
+        // key=value、key: value 与 JSON 形式的敏感字段
+        (
+            r#"(?i)("?\b(?:[a-z_]*token|password|secret|api_key|authorization)"?\s*[:=]\s*"?)[^"\s,}]+"#,
+            "${1}<redacted>",
+        ),
+    ]
+    .into_iter()
+    .map(|(pattern, replacement)| (Regex::new(pattern).expect("valid secret pattern"), replacement))
+    .collect()
+});
+
+/// 单个任务期间输出的日志，超过字节上限时丢弃最早的行
+#[derive(Debug)]
+struct TaskLog {
+    lines: VecDeque<String>,
+    bytes: usize,
+    limit: usize,
+    dropped: usize,
+}
+
+impl TaskLog {
+    fn new(limit: usize) -> Self {
+        Self {
+            lines: VecDeque::new(),
+            bytes: 0,
+            limit,
+            dropped: 0,
+        }
+    }
+
+    fn push(&mut self, line: String) {
+        self.bytes += line.len();
+        self.lines.push_back(line);
+        while self.bytes > self.limit {
+            let Some(oldest) = self.lines.pop_front() else { break };
+            self.bytes -= oldest.len();
+            self.dropped += 1;
+        }
+    }
+
+    fn into_lines(self) -> Vec<String> {
+        let mut lines = Vec::with_capacity(self.lines.len() + 1);
+        if self.dropped > 0 {
+            lines.push(format!("... {} earlier line(s) omitted", self.dropped));
+        }
+        lines.extend(self.lines);
+        lines
+    }
+}
+
+/// 在 `future` 执行期间捕获其输出的日志（最多 `limit` 字节，已脱敏）
+///
+/// `future` 中再 spawn 的任务不在捕获范围内。
+pub async fn capture<F: Future>(limit: usize, future: F) -> (F::Output, Vec<String>) {
+    let log = Arc::new(Mutex::new(TaskLog::new(limit)));
+    let output = CAPTURE.scope(Arc::clone(&log), future).await;
+
+    let captured = std::mem::replace(&mut *log.lock().unwrap_or_else(|e| e.into_inner()), TaskLog::new(0));
+    (output, captured.into_lines())
+}
+
+/// 去除日志行中的令牌、密码等凭据
+pub fn redact(line: &str) -> String {
+    SECRET_PATTERNS
+        .iter()
+        .fold(line.to_string(), |line, (pattern, replacement)| {
+            pattern.replace_all(&line, *replacement).into_owned()
+        })
+}
+
+/// 包装日志实现，在输出的同时把当前任务的日志写入其缓冲区
+pub struct CapturingLogger<L> {
+    inner: L,
+}
+
+impl<L: Log> CapturingLogger<L> {
+    pub fn new(inner: L) -> Self {
+        Self { inner }
+    }
+}
+
+impl<L: Log> Log for CapturingLogger<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.inner.enabled(record.metadata()) {
+            return;
+        }
+        self.inner.log(record);
+
+        let _ = CAPTURE.try_with(|log| {
+            let line = format!(
+                "{} {:<5} {}: {}",
+                Utc::now().format("%H:%M:%S%.3f"),
+                record.level(),
+                record.target(),
+                record.args()
+            );
+            log.lock().unwrap_or_else(|e| e.into_inner()).push(redact(&line));
+        });
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
@@ -0,0 +1,49 @@
+use anyhow::Result;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+/// Signature (8) + IHDR chunk (4 length + 4 type + 13 data + 4 CRC)
+const IHDR_END: usize = 33;
+
+/// A1111 用于存放生成参数的文本块关键字
+pub const PARAMETERS_KEYWORD: &str = "parameters";
+
+/// 从SD响应的 `info` JSON 中提取每张图像的参数文本（infotexts）
+pub fn infotexts(info: &str) -> Vec<String> {
+    serde_json::from_str::<serde_json::Value>(info)
+        .ok()
+        .and_then(|info| info.get("infotexts").cloned())
+        .and_then(|texts| serde_json::from_value(texts).ok())
+        .unwrap_or_default()
+}
+
+/// 从SD响应的 `info` JSON 中提取实际使用的种子，如 `seed`、`subseed`
+pub fn info_seed(info: &str, key: &str) -> Option<i64> {
+    serde_json::from_str::<serde_json::Value>(info)
+        .ok()
+        .and_then(|info| info.get(key).and_then(|v| v.as_i64()))
+}
+
+/// 在PNG的IHDR之后插入一个 iTXt 文本块（UTF-8，不压缩）
+pub fn embed_text(png: &[u8], keyword: &str, text: &str) -> Result<Vec<u8>> {
+    if png.len() < IHDR_END || &png[..8] != PNG_SIGNATURE || &png[12..16] != b"IHDR" {
+        return Err(anyhow::anyhow!("Not a PNG image"));
+    }
+
+    // keyword \0 compression_flag compression_method language_tag \0 translated_keyword \0 text
+    let mut data = Vec::with_capacity(keyword.len() + text.len() + 5);
+    data.extend_from_slice(keyword.as_bytes());
+    data.extend_from_slice(&[0, 0, 0, 0, 0]);
+    data.extend_from_slice(text.as_bytes());
+
+    let mut chunk = Vec::with_capacity(data.len() + 12);
+    chunk.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    chunk.extend_from_slice(b"iTXt");
+    chunk.extend_from_slice(&data);
+    chunk.extend_from_slice(&crc32fast::hash(&chunk[4..]).to_be_bytes());
+
+    let mut out = Vec::with_capacity(png.len() + chunk.len());
+    out.extend_from_slice(&png[..IHDR_END]);
+    out.extend_from_slice(&chunk);
+    out.extend_from_slice(&png[IHDR_END..]);
+    Ok(out)
+}
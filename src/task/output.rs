@@ -0,0 +1,168 @@
+use anyhow::Result;
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::{Delay, DynamicImage, Frame, ImageFormat};
+use sha2::{Digest, Sha256};
+
+/// 默认有损压缩质量
+pub const DEFAULT_QUALITY: u8 = 90;
+
+/// 缩略图的 JPEG 压缩质量
+const THUMBNAIL_QUALITY: u8 = 80;
+
+/// 结果图像输出格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    #[default]
+    Png,
+    Jpeg,
+    /// Lossless WebP; the `quality` param does not apply
+    Webp,
+    /// The only format supported for animated (`frames`) output
+    Gif,
+}
+
+impl OutputFormat {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "png" => Ok(Self::Png),
+            "jpeg" | "jpg" => Ok(Self::Jpeg),
+            "webp" => Ok(Self::Webp),
+            "gif" => Ok(Self::Gif),
+            other => Err(anyhow::anyhow!("Unsupported output_format: {}", other)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Png => "png",
+            Self::Jpeg => "jpeg",
+            Self::Webp => "webp",
+            Self::Gif => "gif",
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            Self::Png => "image/png",
+            Self::Jpeg => "image/jpeg",
+            Self::Webp => "image/webp",
+            Self::Gif => "image/gif",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Png => "png",
+            Self::Jpeg => "jpg",
+            Self::Webp => "webp",
+            Self::Gif => "gif",
+        }
+    }
+}
+
+/// 将SD返回的base64 PNG转换为目标格式的图像字节
+pub fn convert(base64_png: &str, format: OutputFormat, quality: u8) -> Result<Vec<u8>> {
+    let png = BASE64.decode(base64_png)?;
+    if format == OutputFormat::Png {
+        return Ok(png);
+    }
+
+    let image = image::load_from_memory_with_format(&png, ImageFormat::Png)?;
+    encode(&image, format, quality)
+}
+
+/// 按目标格式编码图像
+pub fn encode(image: &DynamicImage, format: OutputFormat, quality: u8) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    match format {
+        OutputFormat::Png => {
+            image.write_to(&mut std::io::Cursor::new(&mut bytes), ImageFormat::Png)?;
+        }
+        OutputFormat::Jpeg => {
+            // JPEG 不支持透明通道
+            let rgb = DynamicImage::ImageRgb8(image.to_rgb8());
+            rgb.write_with_encoder(JpegEncoder::new_with_quality(&mut bytes, quality.clamp(1, 100)))?;
+        }
+        OutputFormat::Webp => {
+            let rgba = DynamicImage::ImageRgba8(image.to_rgba8());
+            rgba.write_with_encoder(WebPEncoder::new_lossless(&mut bytes))?;
+        }
+        OutputFormat::Gif => {
+            image.write_to(&mut std::io::Cursor::new(&mut bytes), ImageFormat::Gif)?;
+        }
+    }
+    Ok(bytes)
+}
+
+/// 生成保持宽高比、长边不超过 `max_dimension` 的 JPEG 缩略图；动画取第一帧，小图不放大
+pub fn thumbnail(image_bytes: &[u8], max_dimension: u32) -> Result<Vec<u8>> {
+    let image = image::load_from_memory(image_bytes)?;
+    let max_dimension = max_dimension.max(1);
+    let thumbnail = if image.width() > max_dimension || image.height() > max_dimension {
+        image.thumbnail(max_dimension, max_dimension)
+    } else {
+        image
+    };
+    encode(&thumbnail, OutputFormat::Jpeg, THUMBNAIL_QUALITY)
+}
+
+/// 将多张base64 PNG帧合成为循环播放的GIF动画
+pub fn assemble_gif(frames: &[String], delay_ms: u32) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    {
+        let mut encoder = GifEncoder::new(&mut bytes);
+        encoder.set_repeat(Repeat::Infinite)?;
+        for frame in frames {
+            let png = BASE64.decode(frame)?;
+            let image = image::load_from_memory_with_format(&png, ImageFormat::Png)?;
+            encoder.encode_frame(Frame::from_parts(
+                image.to_rgba8(),
+                0,
+                0,
+                Delay::from_numer_denom_ms(delay_ms, 1),
+            ))?;
+        }
+    }
+    Ok(bytes)
+}
+
+/// 单帧SD输出（解码后的PNG字节）的SHA-256摘要
+pub fn frame_hash(base64_png: &str) -> Result<String> {
+    let png = BASE64.decode(base64_png)?;
+    Ok(format!("{:x}", Sha256::digest(&png)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GenericImageView, RgbImage};
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let image = DynamicImage::ImageRgb8(RgbImage::new(width, height));
+        encode(&image, OutputFormat::Png, DEFAULT_QUALITY).unwrap()
+    }
+
+    fn dimensions(jpeg: &[u8]) -> (u32, u32) {
+        image::load_from_memory_with_format(jpeg, ImageFormat::Jpeg).unwrap().dimensions()
+    }
+
+    #[test]
+    fn thumbnail_keeps_aspect_ratio_within_max_dimension() {
+        assert_eq!(dimensions(&thumbnail(&png(1024, 512), 256).unwrap()), (256, 128));
+        assert_eq!(dimensions(&thumbnail(&png(512, 768), 256).unwrap()), (171, 256));
+        assert_eq!(dimensions(&thumbnail(&png(512, 512), 256).unwrap()), (256, 256));
+    }
+
+    #[test]
+    fn thumbnail_does_not_upscale_small_images() {
+        assert_eq!(dimensions(&thumbnail(&png(200, 100), 256).unwrap()), (200, 100));
+    }
+
+    #[test]
+    fn thumbnail_fails_on_undecodable_input() {
+        assert!(thumbnail(b"not an image", 256).is_err());
+    }
+}
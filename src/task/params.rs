@@ -0,0 +1,139 @@
+use super::prompt::LoraWeight;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+/// 由任务源而非生成流程读取的参数，严格模式下不视为未知参数
+const SOURCE_PARAMS: &[&str] = &["priority"];
+
+/// 任务参数
+///
+/// `prompt` 声明为可选，缺失时由调用方给出明确的错误；未声明的键收集在 `extra` 中，
+/// 严格模式下据此拒绝拼写错误的参数（如 `cfgscale`）。
+#[derive(Debug, Default, Deserialize)]
+pub struct TaskParams {
+    pub prompt: Option<String>,
+    pub negative_prompt: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub steps: Option<u32>,
+    pub cfg_scale: Option<f32>,
+    pub seed: Option<i64>,
+    pub subseed: Option<i64>,
+    pub subseed_strength: Option<f32>,
+    pub batch_size: Option<u32>,
+    /// Named prompt style from the node config
+    pub style: Option<String>,
+    #[serde(default)]
+    pub loras: Vec<LoraWeight>,
+    /// Negative embeddings appended to the negative prompt
+    #[serde(default)]
+    pub embeddings: Vec<String>,
+    pub frames: Option<u32>,
+    pub frame_delay_ms: Option<u32>,
+    pub output_format: Option<String>,
+    pub timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub embed_metadata: bool,
+    pub quality: Option<u64>,
+    pub refiner_checkpoint: Option<String>,
+    pub refiner_switch_at: Option<f32>,
+    #[serde(default)]
+    pub no_retry: bool,
+    /// Return result URLs into the node's local image cache instead of inline data or object storage
+    #[serde(default)]
+    pub local_cache: bool,
+    /// Selectable SD script to run, e.g. `x/y/z plot`
+    pub script_name: Option<String>,
+    #[serde(default)]
+    pub script_args: Vec<Value>,
+    /// Always-on scripts (ControlNet, ADetailer, ...) keyed by script name
+    #[serde(default)]
+    pub alwayson_scripts: Map<String, Value>,
+    /// Keys not declared above
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl TaskParams {
+    /// 解析任务参数，错误信息指明出错的参数名
+    ///
+    /// `strict` 为真时拒绝未知参数。
+    pub fn parse(params: &Value, strict: bool) -> Result<Self, String> {
+        let object = params
+            .as_object()
+            .ok_or_else(|| "Task params must be a JSON object".to_string())?;
+
+        let parsed: Self = match serde_json::from_value(params.clone()) {
+            Ok(parsed) => parsed,
+            Err(e) => return Err(Self::describe_error(object, e)),
+        };
+
+        if strict {
+            let mut unknown: Vec<&str> = parsed
+                .extra
+                .keys()
+                .map(String::as_str)
+                .filter(|key| !SOURCE_PARAMS.contains(key))
+                .collect();
+            if !unknown.is_empty() {
+                unknown.sort_unstable();
+                return Err(format!("Unknown task param(s): {}", unknown.join(", ")));
+            }
+        }
+        Ok(parsed)
+    }
+
+    /// 逐个参数单独解析以定位出错的键；serde 的错误信息本身不包含字段名
+    fn describe_error(object: &Map<String, Value>, error: serde_json::Error) -> String {
+        let mut keys: Vec<&String> = object.keys().collect();
+        keys.sort_unstable();
+        for key in keys {
+            let single = Value::Object(Map::from_iter([(key.clone(), object[key].clone())]));
+            if let Err(e) = serde_json::from_value::<Self>(single) {
+                return format!("Invalid {}: {}", key, e);
+            }
+        }
+        format!("Invalid task params: {}", error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn strict_mode_rejects_unknown_params() {
+        let params = json!({"prompt": "a cat", "cfgscale": 7, "sampler": "Euler", "priority": "high"});
+        let error = TaskParams::parse(&params, true).unwrap_err();
+        // 任务源读取的 priority 不算未知参数
+        assert_eq!(error, "Unknown task param(s): cfgscale, sampler");
+    }
+
+    #[test]
+    fn lenient_mode_keeps_unknown_params_in_extra() {
+        let params = json!({"prompt": "a cat", "cfgscale": 7, "steps": 20});
+        let parsed = TaskParams::parse(&params, false).unwrap();
+        assert_eq!(parsed.prompt.as_deref(), Some("a cat"));
+        assert_eq!(parsed.steps, Some(20));
+        assert_eq!(parsed.cfg_scale, None);
+        assert_eq!(parsed.extra.get("cfgscale"), Some(&json!(7)));
+    }
+
+    #[test]
+    fn names_the_param_with_a_bad_type() {
+        for strict in [true, false] {
+            let params = json!({"prompt": "a cat", "steps": "twenty", "width": 512});
+            let error = TaskParams::parse(&params, strict).unwrap_err();
+            assert!(error.starts_with("Invalid steps: "), "{}", error);
+        }
+    }
+
+    #[test]
+    fn rejects_non_object_params() {
+        assert_eq!(
+            TaskParams::parse(&json!(["a cat"]), false).unwrap_err(),
+            "Task params must be a JSON object"
+        );
+    }
+}
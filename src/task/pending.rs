@@ -0,0 +1,54 @@
+use crate::consts::*;
+use anyhow::Result;
+use dirs::config_dir;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// 发布失败、等待重放的任务结果
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingResult {
+    pub task_id: String,
+    /// Node that produced the result; only that node replays it
+    #[serde(default)]
+    pub node_id: String,
+    pub subject: String,
+    pub payload: String,
+}
+
+/// 待重放结果的存放目录
+pub fn pending_dir() -> Result<PathBuf> {
+    let config_dir = config_dir().ok_or_else(|| anyhow::anyhow!("无法获取配置目录"))?;
+    Ok(config_dir.join(CONFIG_DIR).join(PENDING_RESULTS_DIR))
+}
+
+/// 将结果写入 `dir`，等待下次重放
+pub fn save(dir: &Path, pending: &PendingResult) -> Result<PathBuf> {
+    std::fs::create_dir_all(dir)?;
+
+    let path = dir.join(format!("{}.json", pending.task_id));
+    std::fs::write(&path, serde_json::to_string(pending)?)?;
+    Ok(path)
+}
+
+/// 读取 `dir` 中所有待重放的结果，无法解析的文件会被跳过
+pub fn load_all(dir: &Path) -> Result<Vec<(PathBuf, PendingResult)>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut results = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+        match std::fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|content| Ok(serde_json::from_str::<PendingResult>(&content)?))
+        {
+            Ok(pending) => results.push((path, pending)),
+            Err(e) => log::warn!("Skipping unreadable pending result {:?}: {}", path, e),
+        }
+    }
+    Ok(results)
+}
@@ -0,0 +1,175 @@
+use crate::config::PromptStyle;
+use crate::consts::*;
+use serde::Deserialize;
+
+/// 任务中指定的 LoRA 及其权重
+#[derive(Debug, Clone, Deserialize)]
+pub struct LoraWeight {
+    pub name: String,
+    #[serde(default = "default_lora_weight")]
+    pub weight: f64,
+}
+
+fn default_lora_weight() -> f64 {
+    1.0
+}
+
+/// 用逗号连接非空的提示词片段
+pub fn join_parts<'a>(parts: impl IntoIterator<Item = Option<&'a str>>) -> String {
+    parts
+        .into_iter()
+        .flatten()
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// 应用命名风格
+///
+/// 任务中的提示词原样保留，风格的前缀/后缀包裹在其两侧；风格的反向提示词追加在
+/// 任务反向提示词之后。
+pub fn apply_style(
+    style: &PromptStyle,
+    prompt: &str,
+    negative_prompt: Option<&str>,
+) -> (String, Option<String>) {
+    let prompt = join_parts([
+        style.prompt_prefix.as_deref(),
+        Some(prompt),
+        style.prompt_suffix.as_deref(),
+    ]);
+    let negative_prompt = join_parts([negative_prompt, style.negative_prompt.as_deref()]);
+
+    (prompt, (!negative_prompt.is_empty()).then_some(negative_prompt))
+}
+
+/// 追加节点级全局提示词后缀与反向提示词，任务自身的内容保留在前
+///
+/// 两者都未配置时原样返回任务的提示词。
+pub fn apply_global(
+    prompt: &str,
+    negative_prompt: Option<&str>,
+    prompt_suffix: Option<&str>,
+    global_negative_prompt: Option<&str>,
+) -> (String, Option<String>) {
+    if prompt_suffix.is_none() && global_negative_prompt.is_none() {
+        return (prompt.to_string(), negative_prompt.map(String::from));
+    }
+    let prompt = join_parts([Some(prompt), prompt_suffix]);
+    let negative_prompt = join_parts([negative_prompt, global_negative_prompt]);
+
+    (prompt, (!negative_prompt.is_empty()).then_some(negative_prompt))
+}
+
+/// 校验 LoRA / 嵌入名称，禁止可能破坏提示词标签语法的字符
+pub fn validate_name(kind: &str, name: &str) -> Result<(), String> {
+    if name.trim().is_empty() {
+        return Err(format!("{} name must not be empty", kind));
+    }
+    if name.contains(['<', '>', ':', ',']) {
+        return Err(format!("{} name contains invalid characters: {}", kind, name));
+    }
+    Ok(())
+}
+
+/// 校验 LoRA 名称与权重范围
+pub fn validate_loras(loras: &[LoraWeight]) -> Result<(), String> {
+    for lora in loras {
+        validate_name("LoRA", &lora.name)?;
+        if !lora.weight.is_finite() || lora.weight.abs() > MAX_LORA_WEIGHT {
+            return Err(format!(
+                "LoRA {} weight {} is outside -{}..={}",
+                lora.name, lora.weight, MAX_LORA_WEIGHT, MAX_LORA_WEIGHT
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// 将 LoRA 标签追加到提示词末尾，将嵌入追加到反向提示词末尾
+pub fn apply_extras(
+    prompt: &str,
+    negative_prompt: Option<&str>,
+    loras: &[LoraWeight],
+    embeddings: &[String],
+) -> (String, Option<String>) {
+    let tags: Vec<String> = loras
+        .iter()
+        .map(|lora| format!("<lora:{}:{}>", lora.name.trim(), lora.weight))
+        .collect();
+    let tags = tags.join(" ");
+    let prompt = join_parts([Some(prompt), Some(tags.as_str())]);
+
+    let embeddings = embeddings.iter().map(|name| Some(name.as_str()));
+    let negative_prompt = join_parts(std::iter::once(negative_prompt).chain(embeddings));
+
+    (prompt, (!negative_prompt.is_empty()).then_some(negative_prompt))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn style(prefix: Option<&str>, suffix: Option<&str>, negative: Option<&str>) -> PromptStyle {
+        PromptStyle {
+            prompt_prefix: prefix.map(String::from),
+            prompt_suffix: suffix.map(String::from),
+            negative_prompt: negative.map(String::from),
+        }
+    }
+
+    #[test]
+    fn style_wraps_task_prompt() {
+        let anime = style(Some("masterpiece"), Some("anime style"), Some("photo"));
+        let (prompt, negative) = apply_style(&anime, "a cat", Some("blurry"));
+        assert_eq!(prompt, "masterpiece, a cat, anime style");
+        assert_eq!(negative.as_deref(), Some("blurry, photo"));
+
+        // 任务没有反向提示词时只用风格的
+        let (_, negative) = apply_style(&anime, "a cat", None);
+        assert_eq!(negative.as_deref(), Some("photo"));
+    }
+
+    #[test]
+    fn empty_style_parts_are_skipped() {
+        let suffix_only = style(None, Some("  "), None);
+        let (prompt, negative) = apply_style(&suffix_only, "a cat", None);
+        assert_eq!(prompt, "a cat");
+        assert_eq!(negative, None);
+    }
+
+    #[test]
+    fn global_modifiers_append_after_task_prompt() {
+        let (prompt, negative) = apply_global("a cat", Some("blurry"), Some("safe for work"), Some("nsfw"));
+        assert_eq!(prompt, "a cat, safe for work");
+        assert_eq!(negative.as_deref(), Some("blurry, nsfw"));
+
+        // 任务没有反向提示词时仍注入全局反向提示词
+        let (prompt, negative) = apply_global("a cat", None, None, Some("nsfw"));
+        assert_eq!(prompt, "a cat");
+        assert_eq!(negative.as_deref(), Some("nsfw"));
+    }
+
+    #[test]
+    fn empty_global_config_leaves_prompts_untouched() {
+        let (prompt, negative) = apply_global(" a cat,  ", Some(" blurry "), None, None);
+        assert_eq!(prompt, " a cat,  ");
+        assert_eq!(negative.as_deref(), Some(" blurry "));
+
+        let (_, negative) = apply_global("a cat", None, None, None);
+        assert_eq!(negative, None);
+    }
+
+    #[test]
+    fn composes_style_then_extras_then_global() {
+        let anime = style(Some("masterpiece"), Some("anime style"), Some("photo"));
+        let (prompt, negative) = apply_style(&anime, "a cat", Some("blurry"));
+        let loras = [LoraWeight { name: "detail".to_string(), weight: 0.5 }];
+        let (prompt, negative) = apply_extras(&prompt, negative.as_deref(), &loras, &["easynegative".to_string()]);
+        let (prompt, negative) = apply_global(&prompt, negative.as_deref(), Some("safe for work"), Some("nsfw"));
+
+        assert_eq!(prompt, "masterpiece, a cat, anime style, <lora:detail:0.5>, safe for work");
+        assert_eq!(negative.as_deref(), Some("blurry, photo, easynegative, nsfw"));
+    }
+}
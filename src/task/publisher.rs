@@ -0,0 +1,31 @@
+use anyhow::Result;
+use async_nats::Client;
+use async_nats::jetstream::Context;
+use async_trait::async_trait;
+
+/// 任务结果的发布目标
+#[async_trait]
+pub trait ResultPublisher: Send + Sync {
+    /// 发布一条消息并等待服务端确认已存储
+    async fn publish(&self, subject: &str, payload: &str) -> Result<()>;
+}
+
+/// 发布到 JetStream，等待流的存储确认
+pub struct JetStreamPublisher(Context);
+
+impl JetStreamPublisher {
+    pub fn new(nats_client: Client) -> Self {
+        Self(async_nats::jetstream::new(nats_client))
+    }
+}
+
+#[async_trait]
+impl ResultPublisher for JetStreamPublisher {
+    async fn publish(&self, subject: &str, payload: &str) -> Result<()> {
+        self.0
+            .publish(subject.to_string(), payload.to_string().into())
+            .await?
+            .await?;
+        Ok(())
+    }
+}
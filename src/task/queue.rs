@@ -0,0 +1,147 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+/// 任务优先级
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl Priority {
+    /// 从任务参数解析优先级，支持 "low"/"normal"/"high" 或数值（<0 低，>0 高）
+    pub fn from_value(value: &serde_json::Value) -> Self {
+        if let Some(name) = value.as_str() {
+            return match name.to_ascii_lowercase().as_str() {
+                "low" => Self::Low,
+                "high" => Self::High,
+                _ => Self::Normal,
+            };
+        }
+        match value.as_i64() {
+            Some(n) if n < 0 => Self::Low,
+            Some(n) if n > 0 => Self::High,
+            _ => Self::Normal,
+        }
+    }
+
+    /// 从原始任务消息中读取 `params.priority`，解析失败时为普通优先级
+    pub fn from_payload(payload: &[u8]) -> Self {
+        serde_json::from_slice::<serde_json::Value>(payload)
+            .ok()
+            .and_then(|message| message.get("params")?.get("priority").map(Self::from_value))
+            .unwrap_or_default()
+    }
+}
+
+struct Entry<T> {
+    priority: Priority,
+    seq: u64,
+    item: T,
+}
+
+impl<T> PartialEq for Entry<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for Entry<T> {}
+
+impl<T> PartialOrd for Entry<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Entry<T> {
+    // 优先级高者先出；同优先级按到达顺序
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+/// 有界优先级队列
+pub struct PriorityQueue<T> {
+    heap: BinaryHeap<Entry<T>>,
+    capacity: usize,
+    next_seq: u64,
+}
+
+impl<T> PriorityQueue<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            heap: BinaryHeap::new(),
+            capacity: capacity.max(1),
+            next_seq: 0,
+        }
+    }
+
+    /// 入队；队列已满时原样返回该元素
+    pub fn push(&mut self, item: T, priority: Priority) -> Result<(), T> {
+        if self.heap.len() >= self.capacity {
+            return Err(item);
+        }
+        self.heap.push(Entry {
+            priority,
+            seq: self.next_seq,
+            item,
+        });
+        self.next_seq += 1;
+        Ok(())
+    }
+
+    /// 取出优先级最高的元素
+    pub fn pop(&mut self) -> Option<(T, Priority)> {
+        self.heap.pop().map(|entry| (entry.item, entry.priority))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pops_by_priority_then_arrival() {
+        let mut queue = PriorityQueue::new(8);
+        for (item, priority) in [
+            ("low-1", Priority::Low),
+            ("normal-1", Priority::Normal),
+            ("high-1", Priority::High),
+            ("normal-2", Priority::Normal),
+            ("low-2", Priority::Low),
+            ("high-2", Priority::High),
+        ] {
+            assert!(queue.push(item, priority).is_ok());
+        }
+
+        let order: Vec<&str> = std::iter::from_fn(|| queue.pop().map(|(item, _)| item)).collect();
+        assert_eq!(order, ["high-1", "high-2", "normal-1", "normal-2", "low-1", "low-2"]);
+    }
+
+    #[test]
+    fn rejects_items_beyond_capacity() {
+        let mut queue = PriorityQueue::new(2);
+        assert!(queue.push(1, Priority::Low).is_ok());
+        assert!(queue.push(2, Priority::Normal).is_ok());
+        assert_eq!(queue.push(3, Priority::High), Err(3));
+
+        assert_eq!(queue.pop(), Some((2, Priority::Normal)));
+        assert!(queue.push(4, Priority::High).is_ok());
+        assert_eq!(queue.pop(), Some((4, Priority::High)));
+        assert_eq!(queue.pop(), Some((1, Priority::Low)));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn parses_priority_from_payload() {
+        assert_eq!(Priority::from_payload(br#"{"params":{"priority":"HIGH"}}"#), Priority::High);
+        assert_eq!(Priority::from_payload(br#"{"params":{"priority":-3}}"#), Priority::Low);
+        assert_eq!(Priority::from_payload(br#"{"params":{}}"#), Priority::Normal);
+        assert_eq!(Priority::from_payload(b"not json"), Priority::Normal);
+    }
+}
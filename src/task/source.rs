@@ -0,0 +1,241 @@
+use crate::consts::*;
+use anyhow::Result;
+use async_nats::Client;
+use async_nats::jetstream::consumer::pull::{self, Config as PullConfig};
+use async_nats::jetstream::{AckKind, consumer::PullConsumer, Message as JetStreamMessage};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::StreamExt;
+use std::time::Duration;
+use super::queue::{Priority, PriorityQueue};
+
+/// 从任务源收到的一条任务消息
+pub struct IncomingTask {
+    /// Subject or address the task was received on, for logging
+    pub subject: String,
+    /// Raw task message (JSON `TaskMessage`)
+    pub payload: Bytes,
+    /// Acknowledges the task back to its source once handled
+    pub handle: Box<dyn TaskHandle>,
+}
+
+/// 任务确认句柄
+///
+/// 确认操作挂在每条任务上而不是任务源上，使工作协程可以在任务源继续拉取消息的同时完成确认。
+#[async_trait]
+pub trait TaskHandle: Send {
+    /// 任务已处理（无论成功与否），不再投递
+    async fn ack(self: Box<Self>) -> Result<()>;
+
+    /// 任务未处理，在 `delay` 后（或立即）重新投递
+    async fn nak(self: Box<Self>, delay: Option<Duration>) -> Result<()>;
+}
+
+/// 任务来源，例如 JetStream 消费者
+#[async_trait]
+pub trait TaskSource: Send {
+    /// 等待下一条任务；任务源永久结束（如重连失败）时返回 `None`
+    ///
+    /// `capacity` 为调用方此刻能立即开始处理的任务数（至少为 1），批量拉取的任务源据此限制每次拉取的数量。
+    async fn next(&mut self, capacity: usize) -> Option<IncomingTask>;
+}
+
+/// JetStream 消息的确认句柄
+struct JetStreamHandle(JetStreamMessage);
+
+#[async_trait]
+impl TaskHandle for JetStreamHandle {
+    async fn ack(self: Box<Self>) -> Result<()> {
+        self.0.ack().await.map_err(|e| anyhow::anyhow!(e))
+    }
+
+    async fn nak(self: Box<Self>, delay: Option<Duration>) -> Result<()> {
+        self.0.ack_with(AckKind::Nak(delay)).await.map_err(|e| anyhow::anyhow!(e))
+    }
+}
+
+/// JetStream 拉取消费者任务源
+///
+/// `fetch_batch_size` 为 1 时使用流式迭代器逐条接收；否则批量拉取并按任务优先级重排，
+/// 每批不超过调用方空闲的处理名额，未能处理的消息留在流中而不是在本地队列里等到 ack_wait。
+/// 连接中断时自动重连，重连失败后结束。
+pub struct JetStreamSource {
+    nats_client: Client,
+    consumer_name: String,
+    consumer_config: PullConfig,
+    fetch_batch_size: usize,
+    queue_capacity: usize,
+    consumer: PullConsumer,
+    messages: Option<pull::Stream>,
+    queue: PriorityQueue<JetStreamMessage>,
+}
+
+impl JetStreamSource {
+    /// 获取或创建 `TASKS` 流上的拉取消费者
+    pub async fn connect(
+        nats_client: Client,
+        consumer_name: String,
+        consumer_config: PullConfig,
+        fetch_batch_size: usize,
+        queue_capacity: usize,
+    ) -> Result<Self> {
+        // 获取JetStream上下文
+        log::debug!("Getting JetStream context");
+        let jetstream = async_nats::jetstream::new(nats_client.clone());
+
+        // 订阅JetStream流
+        log::debug!("Subscribing to 'TASKS' stream using JetStream");
+        let stream = jetstream.get_stream("TASKS").await?;
+        let consumer = stream.get_or_create_consumer(&consumer_name, consumer_config.clone()).await?;
+
+        Ok(Self {
+            nats_client,
+            consumer_name,
+            consumer_config,
+            fetch_batch_size,
+            queue_capacity,
+            consumer,
+            messages: None,
+            queue: PriorityQueue::new(queue_capacity),
+        })
+    }
+
+    /// 逐条接收消息，消息流结束时返回 `None`
+    ///
+    /// 每次只向服务端请求一条消息（默认迭代器会预取 200 条），未处理的消息留在流中，
+    /// 可由其他有空闲的节点接手。
+    async fn next_streamed(&mut self) -> Result<Option<JetStreamMessage>> {
+        if self.messages.is_none() {
+            self.messages = Some(self.consumer.stream().max_messages_per_batch(1).messages().await?);
+        }
+        let Some(messages) = self.messages.as_mut() else {
+            return Ok(None);
+        };
+        match messages.next().await {
+            Some(msg) => Ok(Some(msg.map_err(|e| anyhow::anyhow!(e))?)),
+            None => Ok(None),
+        }
+    }
+
+    /// 批量拉取至多 `capacity` 条消息放入优先级队列，超出队列容量的消息退回重新投递
+    async fn fetch_batch(&mut self, capacity: usize) -> Result<()> {
+        let mut batch = self
+            .consumer
+            .fetch()
+            .max_messages(self.fetch_batch_size.min(capacity).max(1))
+            .messages()
+            .await?;
+
+        let mut received = 0;
+        while let Some(msg) = batch.next().await {
+            let msg = msg.map_err(|e| anyhow::anyhow!(e))?;
+            received += 1;
+            let priority = Priority::from_payload(&msg.payload);
+            if let Err(msg) = self.queue.push(msg, priority) {
+                log::warn!("Task queue full ({}), naking message for redelivery", self.queue_capacity);
+                if let Err(e) = msg.ack_with(AckKind::Nak(None)).await {
+                    log::error!("Failed to nak message: {:?}", e);
+                }
+            }
+        }
+
+        if received == 0 {
+            tokio::time::sleep(Duration::from_millis(FETCH_IDLE_DELAY_MS)).await;
+        } else {
+            log::debug!("Dispatching batch of {} JetStream messages by priority", received);
+        }
+        Ok(())
+    }
+
+    /// 拉取恰好一条消息，没有消息时持续等待
+    ///
+    /// 不使用流式迭代器或批量拉取，避免预取的消息在单任务模式退出后等到 ack_wait 才重新投递。
+    pub async fn fetch_one(&mut self) -> Result<IncomingTask> {
+        loop {
+            let mut batch = self.consumer.fetch().max_messages(1).messages().await?;
+            if let Some(msg) = batch.next().await {
+                return Ok(incoming(msg.map_err(|e| anyhow::anyhow!(e))?));
+            }
+            tokio::time::sleep(Duration::from_millis(FETCH_IDLE_DELAY_MS)).await;
+        }
+    }
+
+    /// 重新获取消费者，失败时按指数退避重试
+    async fn reconnect(&mut self) -> bool {
+        // 如果内部循环结束，表示连接可能已断开，尝试重新连接
+        log::warn!("JetStream subscription interrupted, attempting to reconnect in 5 seconds");
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        self.messages = None;
+
+        let retry_count = 3;
+        for attempt in 1..=retry_count {
+            log::info!("Reconnection attempt {}/{}", attempt, retry_count);
+
+            match async_nats::jetstream::new(self.nats_client.clone()).get_stream("TASKS").await {
+                Ok(stream) => {
+                    match stream.get_or_create_consumer(&self.consumer_name, self.consumer_config.clone()).await {
+                        Ok(consumer) => {
+                            self.consumer = consumer;
+                            log::info!("Successfully reconnected to JetStream");
+                            log::info!("Resuming task processing loop");
+                            return true;
+                        }
+                        Err(e) => log::error!("Failed to get consumer after reconnection: {:?}", e),
+                    }
+                }
+                Err(e) => log::error!("Failed to get stream after reconnection: {:?}", e),
+            }
+
+            // 指数退避重试
+            let backoff = Duration::from_secs(2u64.pow(attempt));
+            log::info!("Waiting {}s before next reconnection attempt", backoff.as_secs());
+            tokio::time::sleep(backoff).await;
+        }
+
+        log::error!("Failed to reconnect after {} attempts, exiting task processing", retry_count);
+        false
+    }
+}
+
+#[async_trait]
+impl TaskSource for JetStreamSource {
+    async fn next(&mut self, capacity: usize) -> Option<IncomingTask> {
+        loop {
+            if let Some((msg, priority)) = self.queue.pop() {
+                log::debug!("Dispatching message with {:?} priority", priority);
+                return Some(incoming(msg));
+            }
+
+            let result = if self.fetch_batch_size > 1 {
+                self.fetch_batch(capacity).await.map(|_| true)
+            } else {
+                match self.next_streamed().await {
+                    Ok(Some(msg)) => return Some(incoming(msg)),
+                    Ok(None) => Ok(false),
+                    Err(e) => Err(e),
+                }
+            };
+
+            let connected = match result {
+                Ok(connected) => connected,
+                Err(e) => {
+                    log::error!("Error receiving JetStream message: {:?}", e);
+                    false
+                }
+            };
+            if !connected && !self.reconnect().await {
+                log::warn!("JetStream subscription ended");
+                return None;
+            }
+        }
+    }
+}
+
+fn incoming(msg: JetStreamMessage) -> IncomingTask {
+    log::debug!("JetStream message headers: {:?}", msg.headers);
+    IncomingTask {
+        subject: msg.subject.to_string(),
+        payload: msg.payload.clone(),
+        handle: Box::new(JetStreamHandle(msg)),
+    }
+}
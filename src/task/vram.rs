@@ -0,0 +1,40 @@
+use crate::device::hardware;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+/// 任务执行期间的显存峰值采样
+///
+/// 在后台定期查询 nvidia-smi 的 memory.used，记录最大值。读数是整块GPU的占用，
+/// 在与其他进程共享的GPU上只是近似值，且可能错过两次采样之间的短暂峰值。
+pub struct VramSampler {
+    stop: oneshot::Sender<()>,
+    handle: JoinHandle<Option<u64>>,
+}
+
+impl VramSampler {
+    /// 立即采样一次，之后每隔 `interval` 采样直到 `finish`
+    pub fn start(interval: Duration) -> Self {
+        let (stop, mut stopped) = oneshot::channel();
+        let handle = tokio::spawn(async move {
+            let mut peak = None;
+            loop {
+                if let Ok(Some(used)) = tokio::task::spawn_blocking(hardware::gpu_memory_used).await {
+                    peak = peak.max(Some(used));
+                }
+                tokio::select! {
+                    _ = tokio::time::sleep(interval) => {}
+                    _ = &mut stopped => break,
+                }
+            }
+            peak
+        });
+        Self { stop, handle }
+    }
+
+    /// 停止采样并返回峰值（MB），没有成功的读数时为空
+    pub async fn finish(self) -> Option<u64> {
+        let _ = self.stop.send(());
+        self.handle.await.ok().flatten()
+    }
+}
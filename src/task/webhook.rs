@@ -0,0 +1,117 @@
+use super::TaskResult;
+use crate::consts::*;
+use anyhow::Result;
+use reqwest::Client;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// 任务结果的投递方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResultDelivery {
+    /// Publish to the JetStream results subject only
+    Nats,
+    /// POST to the result webhook only
+    Webhook,
+    /// Publish to JetStream and POST to the webhook
+    Both,
+}
+
+impl ResultDelivery {
+    pub fn uses_nats(self) -> bool {
+        self != Self::Webhook
+    }
+
+    pub fn uses_webhook(self) -> bool {
+        self != Self::Nats
+    }
+}
+
+impl FromStr for ResultDelivery {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "nats" => Ok(Self::Nats),
+            "webhook" => Ok(Self::Webhook),
+            "both" => Ok(Self::Both),
+            other => Err(anyhow::anyhow!("Unknown result delivery: {} (expected nats, webhook or both)", other)),
+        }
+    }
+}
+
+impl Display for ResultDelivery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Nats => f.write_str("nats"),
+            Self::Webhook => f.write_str("webhook"),
+            Self::Both => f.write_str("both"),
+        }
+    }
+}
+
+/// 结果回调配置
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Value of the `Authorization` header sent with each POST
+    pub auth_header: Option<String>,
+    /// POST attempts per result before giving up
+    pub attempts: u32,
+}
+
+/// 以 HTTP POST 投递任务结果
+pub struct ResultWebhook {
+    client: Client,
+    config: WebhookConfig,
+}
+
+impl ResultWebhook {
+    pub fn new(config: WebhookConfig) -> Self {
+        Self {
+            client: Client::builder()
+                .timeout(Duration::from_secs(RESULT_WEBHOOK_TIMEOUT_SECONDS))
+                .build()
+                .unwrap_or_default(),
+            config,
+        }
+    }
+
+    /// 投递结果，失败时指数退避重试；最终失败只记录日志，不影响任务
+    pub async fn post(&self, result: &TaskResult) {
+        let attempts = self.config.attempts.max(1);
+        for attempt in 1..=attempts {
+            if attempt > 1 {
+                let delay = PUBLISH_RETRY_DELAY_MS * 2u64.pow(attempt - 2);
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+            }
+
+            match self.send(result).await {
+                Ok(()) => {
+                    log::debug!("Result for task {} posted to webhook", result.task_id);
+                    return;
+                }
+                Err(e) => log::warn!(
+                    "Failed to post result for task {} to webhook (attempt {}/{}): {:?}",
+                    result.task_id, attempt, attempts, e
+                ),
+            }
+        }
+        log::error!("Giving up posting result for task {} to webhook {}", result.task_id, self.config.url);
+    }
+
+    async fn send(&self, result: &TaskResult) -> Result<()> {
+        let mut request = self.client.post(&self.config.url).json(result);
+        if let Some(auth) = &self.config.auth_header {
+            request = request.header("Authorization", auth);
+        }
+
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            anyhow::bail!("Webhook returned HTTP {}: {}", status, body);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,228 @@
+use super::{ProgressSink, ResultUploader, UploadItem};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// 本地图片缓存配置
+#[derive(Debug, Clone)]
+pub struct ImageCacheConfig {
+    /// Directory holding the cached images, one `<sha256>.<ext>` file each
+    pub dir: PathBuf,
+    /// Total size cap (bytes); least recently used images are evicted above it
+    pub max_bytes: u64,
+    /// Base URL the backend fetches images from, i.e. the node's metrics server
+    pub public_url: String,
+}
+
+/// 缓存中的单个文件
+#[derive(Debug)]
+struct CacheEntry {
+    size: u64,
+    /// Position in the use order; smaller values were used longer ago
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct CacheIndex {
+    entries: HashMap<String, CacheEntry>,
+    total_bytes: u64,
+    clock: u64,
+}
+
+impl CacheIndex {
+    fn touch(&mut self, name: &str) -> bool {
+        self.clock += 1;
+        match self.entries.get_mut(name) {
+            Some(entry) => {
+                entry.last_used = self.clock;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn insert(&mut self, name: String, size: u64) {
+        self.clock += 1;
+        let entry = CacheEntry { size, last_used: self.clock };
+        if let Some(previous) = self.entries.insert(name, entry) {
+            self.total_bytes -= previous.size;
+        }
+        self.total_bytes += size;
+    }
+
+    /// 移除最久未使用的文件直到总大小不超过 `max_bytes`，返回被移除的文件名；`keep` 不会被移除
+    fn evict(&mut self, max_bytes: u64, keep: Option<&str>) -> Vec<String> {
+        let mut candidates: Vec<(u64, String)> = self
+            .entries
+            .iter()
+            .filter(|(name, _)| Some(name.as_str()) != keep)
+            .map(|(name, entry)| (entry.last_used, name.clone()))
+            .collect();
+        candidates.sort_unstable();
+
+        let mut evicted = Vec::new();
+        for (_, name) in candidates {
+            if self.total_bytes <= max_bytes {
+                break;
+            }
+            if let Some(entry) = self.entries.remove(&name) {
+                self.total_bytes -= entry.size;
+                evicted.push(name);
+            }
+        }
+        evicted
+    }
+}
+
+/// 按内容哈希寻址的本地图片缓存
+///
+/// 结果图片写入本地目录，任务结果只携带指向节点 HTTP 服务（`/images/<sha256>.<ext>`）的URL，
+/// 由后端按需拉取，省去 base64 内联的约 33% 膨胀和外部对象存储。总大小超过上限时按最近最少使用淘汰；
+/// 后端须在图片被淘汰前取走。
+#[derive(Debug)]
+pub struct ImageCache {
+    config: ImageCacheConfig,
+    index: Mutex<CacheIndex>,
+}
+
+impl ImageCache {
+    /// 打开缓存目录，载入已有文件（按修改时间排定使用顺序）并淘汰超出上限的部分
+    pub fn open(config: ImageCacheConfig) -> Result<Self> {
+        std::fs::create_dir_all(&config.dir)
+            .with_context(|| format!("Failed to create image cache dir {}", config.dir.display()))?;
+
+        let mut existing: Vec<(SystemTime, String, u64)> = Vec::new();
+        for entry in std::fs::read_dir(&config.dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let metadata = entry.metadata()?;
+            if !metadata.is_file() || !is_cache_name(&name) {
+                continue;
+            }
+            existing.push((metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH), name, metadata.len()));
+        }
+        existing.sort_unstable();
+
+        let mut index = CacheIndex::default();
+        for (_, name, size) in existing {
+            index.insert(name, size);
+        }
+        let cache = Self { config, index: Mutex::new(index) };
+        let evicted = cache.lock().evict(cache.config.max_bytes, None);
+        cache.remove_files(&evicted);
+
+        let index = cache.lock();
+        log::info!(
+            "Image cache at {}: {} image(s), {} of {} bytes",
+            cache.config.dir.display(),
+            index.entries.len(),
+            index.total_bytes,
+            cache.config.max_bytes
+        );
+        drop(index);
+        Ok(cache)
+    }
+
+    /// 保存图片并返回其文件名 `<sha256>.<ext>`；相同内容只保存一次
+    pub async fn insert(&self, data: &[u8], extension: &str) -> Result<String> {
+        let name = format!("{:x}.{}", Sha256::digest(data), extension);
+        if self.lock().touch(&name) {
+            return Ok(name);
+        }
+
+        // 先写临时文件再改名，读取方不会看到写了一半的图片
+        let path = self.config.dir.join(&name);
+        let partial = self.config.dir.join(format!("{}.partial", name));
+        tokio::fs::write(&partial, data)
+            .await
+            .with_context(|| format!("Failed to write {}", partial.display()))?;
+        tokio::fs::rename(&partial, &path)
+            .await
+            .with_context(|| format!("Failed to move {} into place", partial.display()))?;
+
+        let evicted = {
+            let mut index = self.lock();
+            index.insert(name.clone(), data.len() as u64);
+            index.evict(self.config.max_bytes, Some(&name))
+        };
+        if !evicted.is_empty() {
+            log::debug!("Image cache full, evicted {} image(s)", evicted.len());
+            self.remove_files(&evicted);
+        }
+        Ok(name)
+    }
+
+    /// 读取缓存的图片及其 MIME 类型；不存在或名称不合法时为空
+    pub async fn get(&self, name: &str) -> Option<(Vec<u8>, &'static str)> {
+        if !is_cache_name(name) || !self.lock().touch(name) {
+            return None;
+        }
+        match tokio::fs::read(self.config.dir.join(name)).await {
+            Ok(data) => Some((data, content_type(name))),
+            Err(e) => {
+                log::warn!("Cached image {} is unreadable, dropping it: {}", name, e);
+                let mut index = self.lock();
+                if let Some(entry) = index.entries.remove(name) {
+                    index.total_bytes -= entry.size;
+                }
+                None
+            }
+        }
+    }
+
+    /// 图片的拉取URL
+    pub fn url(&self, name: &str) -> String {
+        format!("{}/images/{}", self.config.public_url.trim_end_matches('/'), name)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, CacheIndex> {
+        self.index.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn remove_files(&self, names: &[String]) {
+        for name in names {
+            if let Err(e) = std::fs::remove_file(self.config.dir.join(name)) {
+                log::warn!("Failed to remove evicted image {}: {}", name, e);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl ResultUploader for ImageCache {
+    async fn upload(&self, item: &UploadItem, _progress: Option<&ProgressSink>) -> Result<String> {
+        let extension = item
+            .key
+            .rsplit_once('.')
+            .map(|(_, extension)| extension)
+            .filter(|extension| !extension.is_empty() && extension.chars().all(|c| c.is_ascii_alphanumeric()))
+            .unwrap_or("bin");
+        let name = self.insert(&item.data, extension).await?;
+        Ok(self.url(&name))
+    }
+}
+
+/// 缓存文件名：64位小写十六进制哈希加字母数字扩展名，拒绝路径分隔符等其他字符
+fn is_cache_name(name: &str) -> bool {
+    let Some((hash, extension)) = name.split_once('.') else {
+        return false;
+    };
+    hash.len() == 64
+        && hash.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+        && !extension.is_empty()
+        && extension.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+fn content_type(name: &str) -> &'static str {
+    match name.rsplit_once('.').map(|(_, extension)| extension) {
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("webp") => "image/webp",
+        Some("gif") => "image/gif",
+        _ => "application/octet-stream",
+    }
+}
@@ -0,0 +1,372 @@
+use crate::consts::*;
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::future;
+use reqwest::{Client, RequestBuilder};
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+pub use cache::{ImageCache, ImageCacheConfig};
+pub mod cache;
+
+/// 结果上传配置
+#[derive(Debug, Clone)]
+pub struct UploadConfig {
+    /// Base URL images are PUT to, e.g. a bucket endpoint
+    pub url: String,
+    /// Base URL returned to the backend (defaults to `url`)
+    pub public_url: Option<String>,
+    /// Optional bearer token for the upload endpoint
+    pub auth_token: Option<String>,
+    /// Max number of images uploaded concurrently per task
+    pub concurrency: usize,
+    /// Return the images that uploaded successfully instead of failing the whole task
+    pub allow_partial: bool,
+    /// Objects at least this large (bytes) use an S3-style multipart upload; `None` always uses a single PUT
+    pub multipart_threshold: Option<usize>,
+    /// Part size for multipart uploads (bytes)
+    pub chunk_size: usize,
+}
+
+/// 分片上传进度
+#[derive(Debug, Clone, Serialize)]
+pub struct UploadProgress {
+    pub key: String,
+    pub bytes_sent: usize,
+    pub total_bytes: usize,
+    pub parts_done: usize,
+    pub parts_total: usize,
+}
+
+/// 接收上传进度的回调
+pub type ProgressSink = Arc<dyn Fn(&UploadProgress) + Send + Sync>;
+
+/// 待上传的单张图片
+#[derive(Debug, Clone)]
+pub struct UploadItem {
+    pub key: String,
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+/// 结果上传器
+#[async_trait]
+pub trait ResultUploader: Send + Sync {
+    /// Upload one object and return the URL it can be fetched from; large objects
+    /// may report progress to `progress`
+    async fn upload(&self, item: &UploadItem, progress: Option<&ProgressSink>) -> Result<String>;
+}
+
+/// 通过 HTTP PUT 上传到对象存储（S3 兼容或预签名网关）
+pub struct HttpUploader {
+    client: Client,
+    config: UploadConfig,
+}
+
+impl HttpUploader {
+    pub fn new(config: UploadConfig) -> Self {
+        Self {
+            client: Client::new(),
+            config,
+        }
+    }
+}
+
+impl HttpUploader {
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.config.auth_token {
+            Some(token) => request.header("Authorization", format!("Bearer {}", token)),
+            None => request,
+        }
+    }
+
+    /// 单次 PUT 上传整个对象
+    async fn put_single(&self, url: &str, item: &UploadItem) -> Result<()> {
+        let request = self
+            .client
+            .put(url)
+            .header("Content-Type", &item.content_type)
+            .body(item.data.clone());
+        let response = self.authorize(request).send().await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let error_text = response.text().await.unwrap_or_default();
+            anyhow::bail!("Upload of {} failed: HTTP {}: {}", item.key, status, error_text);
+        }
+        Ok(())
+    }
+
+    /// S3 分片上传：创建上传、逐个上传分片并报告进度、合并；任一步失败时中止上传，
+    /// 避免存储端残留未完成的分片
+    async fn put_multipart(&self, url: &str, item: &UploadItem, progress: Option<&ProgressSink>) -> Result<()> {
+        let upload_id = self.create_multipart(url, item).await?;
+        let result = async {
+            let etags = self.upload_parts(url, &upload_id, item, progress).await?;
+            self.complete_multipart(url, &upload_id, &etags).await
+        }
+        .await;
+
+        if let Err(e) = &result {
+            log::warn!("Multipart upload of {} failed, aborting it: {:?}", item.key, e);
+            if let Err(abort_error) = self.abort_multipart(url, &upload_id).await {
+                log::error!("Failed to abort multipart upload {} of {}: {:?}", upload_id, item.key, abort_error);
+            }
+        }
+        result
+    }
+
+    async fn create_multipart(&self, url: &str, item: &UploadItem) -> Result<String> {
+        let request = self
+            .client
+            .post(format!("{}?uploads", url))
+            .header("Content-Type", &item.content_type);
+        let response = self.authorize(request).send().await?;
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        if !status.is_success() {
+            anyhow::bail!("Creating multipart upload of {} failed: HTTP {}: {}", item.key, status, body);
+        }
+        xml_value(&body, "UploadId")
+            .map(String::from)
+            .ok_or_else(|| anyhow::anyhow!("Multipart upload response for {} has no UploadId: {}", item.key, body))
+    }
+
+    async fn upload_parts(
+        &self,
+        url: &str,
+        upload_id: &str,
+        item: &UploadItem,
+        progress: Option<&ProgressSink>,
+    ) -> Result<Vec<String>> {
+        let chunk_size = self.config.chunk_size.max(UPLOAD_MIN_CHUNK_SIZE);
+        let parts_total = item.data.len().div_ceil(chunk_size);
+        let mut etags = Vec::with_capacity(parts_total);
+        let mut bytes_sent = 0;
+
+        for (i, chunk) in item.data.chunks(chunk_size).enumerate() {
+            let part_number = i + 1;
+            let request = self
+                .client
+                .put(url)
+                .query(&[("partNumber", part_number.to_string()), ("uploadId", upload_id.to_string())])
+                .body(Bytes::copy_from_slice(chunk));
+            let response = self.authorize(request).send().await?;
+            if !response.status().is_success() {
+                let status = response.status();
+                let error_text = response.text().await.unwrap_or_default();
+                anyhow::bail!("Upload of part {}/{} of {} failed: HTTP {}: {}",
+                    part_number, parts_total, item.key, status, error_text);
+            }
+            let etag = response
+                .headers()
+                .get("ETag")
+                .and_then(|value| value.to_str().ok())
+                .ok_or_else(|| anyhow::anyhow!("Part {} of {} was stored without an ETag", part_number, item.key))?;
+            etags.push(etag.to_string());
+
+            bytes_sent += chunk.len();
+            log::debug!("Uploaded part {}/{} of {} ({}/{} bytes)",
+                part_number, parts_total, item.key, bytes_sent, item.data.len());
+            if let Some(progress) = progress {
+                progress(&UploadProgress {
+                    key: item.key.clone(),
+                    bytes_sent,
+                    total_bytes: item.data.len(),
+                    parts_done: part_number,
+                    parts_total,
+                });
+            }
+        }
+        Ok(etags)
+    }
+
+    async fn complete_multipart(&self, url: &str, upload_id: &str, etags: &[String]) -> Result<()> {
+        let parts: String = etags
+            .iter()
+            .enumerate()
+            .map(|(i, etag)| format!("<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>", i + 1, etag))
+            .collect();
+        let request = self
+            .client
+            .post(url)
+            .query(&[("uploadId", upload_id)])
+            .header("Content-Type", "application/xml")
+            .body(format!("<CompleteMultipartUpload>{}</CompleteMultipartUpload>", parts));
+        let response = self.authorize(request).send().await?;
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        // S3 可能以 200 状态返回合并失败的错误信息
+        if !status.is_success() || body.contains("<Error>") {
+            anyhow::bail!("Completing multipart upload {} failed: HTTP {}: {}", upload_id, status, body);
+        }
+        Ok(())
+    }
+
+    async fn abort_multipart(&self, url: &str, upload_id: &str) -> Result<()> {
+        let request = self.client.delete(url).query(&[("uploadId", upload_id)]);
+        let response = self.authorize(request).send().await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let error_text = response.text().await.unwrap_or_default();
+            anyhow::bail!("HTTP {}: {}", status, error_text);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ResultUploader for HttpUploader {
+    async fn upload(&self, item: &UploadItem, progress: Option<&ProgressSink>) -> Result<String> {
+        let url = format!("{}/{}", self.config.url.trim_end_matches('/'), item.key);
+
+        // 小对象仍使用单次 PUT
+        match self.config.multipart_threshold {
+            Some(threshold) if item.data.len() >= threshold => {
+                log::debug!("Uploading {} ({} bytes) to {} in parts", item.key, item.data.len(), url);
+                self.put_multipart(&url, item, progress).await?;
+            }
+            _ => {
+                log::debug!("Uploading {} ({} bytes) to {}", item.key, item.data.len(), url);
+                self.put_single(&url, item).await?;
+            }
+        }
+
+        let public_url = self.config.public_url.as_deref().unwrap_or(&self.config.url);
+        Ok(format!("{}/{}", public_url.trim_end_matches('/'), item.key))
+    }
+}
+
+/// 取 XML 响应中第一个 `<tag>` 元素的文本
+fn xml_value<'a>(body: &'a str, tag: &str) -> Option<&'a str> {
+    let open = format!("<{}>", tag);
+    let start = body.find(&open)? + open.len();
+    let end = body[start..].find(&format!("</{}>", tag))? + start;
+    Some(body[start..end].trim())
+}
+
+/// 并发上传多张图片，最多同时进行 `concurrency` 个上传
+///
+/// 任一上传失败时，`allow_partial` 为 false 则整个任务失败；为 true 则返回上传成功的子集
+/// （全部失败时仍返回错误）。
+pub async fn upload_all(
+    uploader: Arc<dyn ResultUploader>,
+    items: Vec<UploadItem>,
+    concurrency: usize,
+    allow_partial: bool,
+    progress: Option<ProgressSink>,
+) -> Result<Vec<String>> {
+    let semaphore = Arc::new(Semaphore::new(concurrency.max(1)));
+    let total = items.len();
+
+    let uploads = items.into_iter().map(|item| {
+        let uploader = Arc::clone(&uploader);
+        let semaphore = Arc::clone(&semaphore);
+        let progress = progress.clone();
+        async move {
+            let _permit = semaphore.acquire_owned().await?;
+            uploader.upload(&item, progress.as_ref()).await
+        }
+    });
+
+    if !allow_partial {
+        return future::try_join_all(uploads).await;
+    }
+
+    let mut urls = Vec::with_capacity(total);
+    let mut last_error = None;
+    for result in future::join_all(uploads).await {
+        match result {
+            Ok(url) => urls.push(url),
+            Err(e) => {
+                log::warn!("Image upload failed, returning partial results: {:?}", e);
+                last_error = Some(e);
+            }
+        }
+    }
+
+    match last_error {
+        Some(e) if urls.is_empty() => Err(e),
+        _ => {
+            if urls.len() < total {
+                log::warn!("Uploaded {}/{} images", urls.len(), total);
+            }
+            Ok(urls)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    /// 按键名返回URL的上传器：序号越小完成越晚，`failing` 中的键上传失败；记录同时进行的最大上传数
+    #[derive(Default)]
+    struct FakeUploader {
+        failing: Vec<String>,
+        active: AtomicUsize,
+        max_active: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl ResultUploader for FakeUploader {
+        async fn upload(&self, item: &UploadItem, _progress: Option<&ProgressSink>) -> Result<String> {
+            let active = self.active.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_active.fetch_max(active, Ordering::SeqCst);
+            let index: u64 = item.key.parse().unwrap();
+            tokio::time::sleep(Duration::from_millis(50 - index * 10)).await;
+            self.active.fetch_sub(1, Ordering::SeqCst);
+
+            if self.failing.contains(&item.key) {
+                anyhow::bail!("upload of {} rejected", item.key);
+            }
+            Ok(format!("https://bucket/{}", item.key))
+        }
+    }
+
+    fn items(count: usize) -> Vec<UploadItem> {
+        (0..count)
+            .map(|i| UploadItem {
+                key: i.to_string(),
+                content_type: "image/png".to_string(),
+                data: vec![i as u8],
+            })
+            .collect()
+    }
+
+    fn failing(keys: &[&str]) -> Arc<FakeUploader> {
+        Arc::new(FakeUploader {
+            failing: keys.iter().map(|key| key.to_string()).collect(),
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn keeps_input_order_under_concurrency() {
+        let uploader = failing(&[]);
+        let urls = upload_all(uploader.clone(), items(5), 3, false, None).await.unwrap();
+        assert_eq!(
+            urls,
+            (0..5).map(|i| format!("https://bucket/{}", i)).collect::<Vec<_>>()
+        );
+        assert_eq!(uploader.max_active.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn one_failed_image_fails_the_task() {
+        let error = upload_all(failing(&["2"]), items(4), 4, false, None).await.unwrap_err();
+        assert!(error.to_string().contains("upload of 2 rejected"));
+    }
+
+    #[tokio::test]
+    async fn partial_mode_returns_successful_uploads_in_order() {
+        let urls = upload_all(failing(&["1", "3"]), items(4), 2, true, None).await.unwrap();
+        assert_eq!(urls, ["https://bucket/0", "https://bucket/2"]);
+
+        let error = upload_all(failing(&["0", "1"]), items(2), 2, true, None).await;
+        assert!(error.is_err());
+    }
+}